@@ -0,0 +1,160 @@
+//! Personal API tokens, for CI and scripts
+//!
+//! An API token acts on behalf of the user who created it, restricted to a single deployment and a set of actions. Only SHA-256 hashes of the tokens are stored, in a JSON file.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::permissions::Action;
+use crate::utils::{self, Res};
+
+/// Prefix of the API tokens, to tell them apart from JWTs
+pub const API_TOKEN_PREFIX: &str = "maintos_";
+
+#[derive(Serialize, Deserialize, Clone)]
+/// A personal API token (without the token itself)
+pub struct ApiToken {
+    pub id: String,
+    /// A name describing the token's use (eg: `github-actions`)
+    pub name: String,
+    /// Username of the user who created the token, the token acts on their behalf
+    pub username: String,
+    /// The deployment the token can be used for
+    pub deployment: String,
+    /// The actions the token allows
+    pub actions: Vec<Action>,
+    /// Creation time (UNIX timestamp in seconds)
+    pub created_at: i64,
+    /// Expiration time (UNIX timestamp in seconds)
+    pub expiration: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+/// An API token as stored in the file
+struct StoredApiToken {
+    /// Hash of the token
+    hash: String,
+    #[serde(flatten)]
+    token: ApiToken,
+}
+
+/// The API tokens store
+pub struct ApiTokenStore {
+    path: PathBuf,
+    /// API tokens by id
+    tokens: Mutex<HashMap<String, StoredApiToken>>,
+}
+
+impl ApiTokenStore {
+    /// Loads the API tokens stored in the given (JSON) file. The file is created when the first token is created.
+    pub fn load(path: PathBuf) -> Res<Self> {
+        let tokens = utils::read_json_store(&path)?;
+
+        Ok(Self {
+            path,
+            tokens: Mutex::new(tokens),
+        })
+    }
+
+    /// Creates an API token. Returns the token (only available now) and its information.
+    pub async fn create(
+        &self,
+        name: &str,
+        username: &str,
+        deployment: &str,
+        actions: Vec<Action>,
+        expiration: i64,
+    ) -> Res<(String, ApiToken)> {
+        let secret = format!(
+            "{API_TOKEN_PREFIX}{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let token = ApiToken {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            username: username.to_string(),
+            deployment: deployment.to_string(),
+            actions,
+            created_at: chrono::Utc::now().timestamp(),
+            expiration,
+        };
+
+        let mut tokens = self.tokens.lock().await;
+        tokens.insert(
+            token.id.clone(),
+            StoredApiToken {
+                hash: utils::hash_secret(&secret),
+                token: token.clone(),
+            },
+        );
+        self.save(&mut tokens).await?;
+
+        Ok((secret, token))
+    }
+
+    /// Returns the information of an API token if it exists and has not expired
+    pub async fn verify(&self, secret: &str) -> Option<ApiToken> {
+        let hash = utils::hash_secret(secret);
+        let now = chrono::Utc::now().timestamp();
+
+        self.tokens
+            .lock()
+            .await
+            .values()
+            .find(|stored| stored.hash == hash && stored.token.expiration > now)
+            .map(|stored| stored.token.clone())
+    }
+
+    /// Returns the (unexpired) API tokens of a deployment, oldest first
+    pub async fn list(&self, deployment: &str) -> Vec<ApiToken> {
+        let now = chrono::Utc::now().timestamp();
+
+        let mut tokens: Vec<ApiToken> = self
+            .tokens
+            .lock()
+            .await
+            .values()
+            .filter(|stored| stored.token.deployment == deployment && stored.token.expiration > now)
+            .map(|stored| stored.token.clone())
+            .collect();
+        tokens.sort_by_key(|token| token.created_at);
+
+        tokens
+    }
+
+    /// Revokes an API token of a deployment. Returns whether the token existed.
+    pub async fn revoke(&self, deployment: &str, id: &str) -> Res<bool> {
+        let mut tokens = self.tokens.lock().await;
+
+        if tokens
+            .get(id)
+            .is_some_and(|stored| stored.token.deployment == deployment)
+        {
+            tokens.remove(id);
+            self.save(&mut tokens).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Revokes all the API tokens created by a user
+    pub async fn revoke_user(&self, username: &str) -> Res<()> {
+        let mut tokens = self.tokens.lock().await;
+        tokens.retain(|_, stored| stored.token.username != username);
+
+        self.save(&mut tokens).await
+    }
+
+    /// Prunes the expired tokens and writes the tokens to the file
+    async fn save(&self, tokens: &mut HashMap<String, StoredApiToken>) -> Res<()> {
+        let now = chrono::Utc::now().timestamp();
+        tokens.retain(|_, stored| stored.token.expiration > now);
+
+        utils::write_json_atomic(&self.path, tokens).await
+    }
+}
@@ -0,0 +1,171 @@
+//! Append-only audit log of maintainer actions
+//!
+//! Each action is recorded as a JSON line in the audit log file. Secrets in the parameters are redacted before being recorded.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+use crate::utils::Res;
+
+/// Parameter names (or parts of names) whose values are redacted
+const REDACTED_PARAMS: [&str; 4] = ["value", "secret", "token", "password"];
+
+#[derive(Serialize, Deserialize, Clone)]
+/// The outcome of an audited action
+pub struct AuditOutcome {
+    /// Whether the action succeeded
+    pub success: bool,
+    /// HTTP status code of the response
+    pub status_code: u16,
+    /// The response message, if any
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+/// An entry of the audit log
+pub struct AuditEntry {
+    /// Time of the action (UNIX timestamp in seconds)
+    pub timestamp: i64,
+    /// Username of the user who performed the action
+    pub username: String,
+    /// The deployment the action was performed on, if any
+    pub deployment: Option<String>,
+    /// The action performed (eg: `POST /deployments/{name}/redeploy`)
+    pub action: String,
+    /// The parameters of the action (with secrets redacted)
+    pub params: serde_json::Value,
+    pub outcome: AuditOutcome,
+}
+
+/// Filters for querying the audit log
+pub struct AuditFilter<'a> {
+    pub deployment: Option<&'a str>,
+    pub username: Option<&'a str>,
+    /// Only return entries since this UNIX timestamp
+    pub since: Option<i64>,
+    /// Maximum number of entries returned (the newest ones)
+    pub limit: usize,
+    /// Number of the newest matching entries skipped, to page through older entries
+    pub offset: usize,
+}
+
+/// Replaces the values of secret parameters with `[REDACTED]`
+pub fn redact_params(params: &mut serde_json::Value) {
+    match params {
+        serde_json::Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                let name = name.to_lowercase();
+
+                if REDACTED_PARAMS.iter().any(|secret| name.contains(secret)) {
+                    *value = serde_json::Value::String("[REDACTED]".into());
+                } else {
+                    redact_params(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_params),
+        _ => {}
+    }
+}
+
+/// The audit log file
+pub struct AuditLog {
+    path: PathBuf,
+    /// Serializes writes so that lines are never interleaved
+    write_lock: Mutex<()>,
+}
+
+impl AuditLog {
+    /// Creates an audit log stored in the given (JSON lines) file
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Appends an entry to the audit log, creating it (readable only by the owner) if needed
+    pub async fn record(&self, entry: &AuditEntry) -> Res<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let _guard = self.write_lock.lock().await;
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+
+        Ok(())
+    }
+
+    /// Returns a page of the entries matching the filter and the `visible` predicate (the newest ones, after skipping `offset` entries), oldest first. The file is read line by line. Corrupt lines (eg: partially written) are skipped with a warning.
+    pub async fn query(
+        &self,
+        filter: &AuditFilter<'_>,
+        visible: impl Fn(&AuditEntry) -> bool,
+    ) -> Res<Vec<AuditEntry>> {
+        let file = match fs::File::open(&self.path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        // The newest matching entries, including the skipped ones
+        let capacity = filter.limit + filter.offset;
+        let mut entries = VecDeque::with_capacity(capacity.min(1024));
+
+        let mut lines = BufReader::new(file).lines();
+        let mut index = 0;
+        while let Some(line) = lines.next_line().await? {
+            index += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry = match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    tracing::warn!("Skipping corrupt audit log entry on line {index}: {err}");
+                    continue;
+                }
+            };
+
+            if filter
+                .deployment
+                .is_none_or(|deployment| entry.deployment.as_deref() == Some(deployment))
+                && filter
+                    .username
+                    .is_none_or(|username| entry.username == username)
+                && filter.since.is_none_or(|since| entry.timestamp >= since)
+                && visible(&entry)
+            {
+                if entries.len() == capacity {
+                    entries.pop_front();
+                }
+                if capacity > 0 {
+                    entries.push_back(entry);
+                }
+            }
+        }
+
+        entries.truncate(entries.len().saturating_sub(filter.offset));
+
+        Ok(entries.into())
+    }
+}
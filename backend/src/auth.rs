@@ -3,67 +3,194 @@
 //! Currently this is only used in the admin dashboard and uses Github OAuth for authentication
 
 use anyhow::anyhow;
+use hmac::{Hmac, Mac};
 use jwt::{Claims, RegisteredClaims, SignWithKey, VerifyWithKey};
+use sha2::Sha256;
 use std::collections::BTreeMap;
 
-use crate::{env::EnvVars, github, utils::Res};
+use crate::{
+    api_tokens::ApiToken, denylist::TokenDenylist, env::EnvVars, github::GithubApi,
+    permissions::Action, sessions::SessionStore, utils, utils::Res,
+};
+
+#[derive(Clone)]
+/// How a user is authenticated
+pub enum AuthKind {
+    /// With a JWT issued for a login session
+    Session { session_id: String },
+    /// With a personal API token, restricted to a deployment and a set of actions
+    ApiToken {
+        deployment: String,
+        actions: Vec<Action>,
+    },
+}
 
 #[derive(Clone)]
 /// Struct containing the auth information of a user
 pub struct Auth {
+    /// The bearer token (JWT or API token)
     pub jwt: String,
     pub username: String,
+    /// The token id (`jti` claim of a JWT, or the API token id)
+    pub token_id: String,
+    /// Time the token expires at (UNIX timestamp in seconds)
+    pub expiration: i64,
+    pub kind: AuthKind,
+}
+
+impl Auth {
+    /// Authentication with a personal API token
+    pub fn from_api_token(token: &str, api_token: ApiToken) -> Self {
+        Self {
+            jwt: token.to_owned(),
+            username: api_token.username,
+            token_id: api_token.id,
+            expiration: api_token.expiration,
+            kind: AuthKind::ApiToken {
+                deployment: api_token.deployment,
+                actions: api_token.actions,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+/// The reason a JWT is rejected
+pub enum TokenError {
+    /// The token could not be decoded or a required claim is missing or invalid
+    Malformed(&'static str),
+    /// The token is not signed with the secret key
+    BadSignature,
+    Expired,
+    /// The token's `nbf` or `iat` claim is in the future
+    NotYetValid,
+    InvalidIssuer,
+    InvalidAudience,
+    Revoked,
 }
 
-/// Verifies whether a JWT is valid and signed with the secret key
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(reason) => write!(f, "Authorization token malformed: {reason}"),
+            Self::BadSignature => write!(f, "Authorization token signature invalid."),
+            Self::Expired => write!(f, "Authorization token expired."),
+            Self::NotYetValid => write!(f, "Authorization token not valid yet."),
+            Self::InvalidIssuer => write!(f, "Authorization token issuer invalid."),
+            Self::InvalidAudience => write!(f, "Authorization token audience invalid."),
+            Self::Revoked => write!(f, "Authorization token revoked."),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// Verifies whether a JWT is valid, signed with the secret key and not revoked. All the registered claims are validated, with a tolerance of `JWT_CLOCK_SKEW` for the time claims.
 ///
-/// Returns the username and jwt in a struct
-pub async fn verify_token(token: &str, env_vars: &EnvVars) -> Res<Auth> {
+/// Returns the username and jwt in a struct. If the token is rejected, the error is a [`TokenError`].
+pub async fn verify_token(token: &str, env_vars: &EnvVars, denylist: &TokenDenylist) -> Res<Auth> {
     let jwt_key = env_vars.get_jwt_key()?;
-    let claims: Result<Claims, _> = token.verify_with_key(&jwt_key);
+    let claims: Claims = token.verify_with_key(&jwt_key).map_err(|err| match err {
+        jwt::Error::InvalidSignature | jwt::Error::RustCryptoMac(_) => TokenError::BadSignature,
+        _ => TokenError::Malformed("Invalid JWT."),
+    })?;
+    let registered = claims.registered;
+
+    let now = chrono::Utc::now().timestamp();
+    let skew = env_vars.jwt_clock_skew as i64;
+
+    let expiration = registered
+        .expiration
+        .ok_or(TokenError::Malformed("Expiration time not in the claims."))?
+        as i64;
+    if now > expiration + skew {
+        return Err(TokenError::Expired.into());
+    }
 
-    let claims = claims.map_err(|_| anyhow!("Claims not found on the JWT."))?;
+    let issued_at = registered
+        .issued_at
+        .ok_or(TokenError::Malformed("Issue time not in the claims."))? as i64;
+    if registered
+        .not_before
+        .map_or(issued_at, |not_before| not_before as i64)
+        .max(issued_at)
+        > now + skew
+    {
+        return Err(TokenError::NotYetValid.into());
+    }
+
+    if registered.issuer.as_deref() != Some(env_vars.jwt_issuer.as_str()) {
+        return Err(TokenError::InvalidIssuer.into());
+    }
+    if registered.audience.as_deref() != Some(env_vars.jwt_audience.as_str()) {
+        return Err(TokenError::InvalidAudience.into());
+    }
+
+    let token_id = registered
+        .json_web_token_id
+        .ok_or(TokenError::Malformed("Token id not in the claims."))?;
     let username = claims
         .private
         .get("username")
-        .ok_or("Username not in the claims.")
-        .map_err(|_| anyhow!("Username not in the claims."))?;
-    let username = username
+        .ok_or(TokenError::Malformed("Username not in the claims."))?
         .as_str()
-        .ok_or("Username is not a string.")
-        .map_err(|_| anyhow!("Username is not a string."))?;
+        .ok_or(TokenError::Malformed("Username is not a string."))?;
+    let session_id = claims
+        .private
+        .get("session")
+        .and_then(|session_id| session_id.as_str())
+        .ok_or(TokenError::Malformed("Session id not in the claims."))?;
+
+    if denylist
+        .is_revoked(&token_id, session_id, username, issued_at)
+        .await
+    {
+        return Err(TokenError::Revoked.into());
+    }
 
     Ok(Auth {
         jwt: token.to_owned(),
         username: username.to_owned(),
+        token_id,
+        expiration,
+        kind: AuthKind::Session {
+            session_id: session_id.to_owned(),
+        },
     })
 }
 
-/// Generates a JWT with the username (for claims) and secret key
-async fn generate_token(username: &str, env_vars: &EnvVars) -> Res<String> {
+/// Generates a short-lived JWT (access token) with the username and session id (for claims) and secret key
+pub async fn generate_token(username: &str, session_id: &str, env_vars: &EnvVars) -> Res<String> {
     let jwt_key = env_vars.get_jwt_key()?;
 
-    let expiration = chrono::Utc::now()
-        .checked_add_days(chrono::naive::Days::new(7))
+    let now = chrono::Utc::now();
+    let expiration = now
+        .checked_add_signed(chrono::TimeDelta::seconds(
+            env_vars.access_token_lifetime as i64,
+        ))
         .ok_or("Error checking JWT expiration date")
         .map_err(|_| anyhow!("Error setting JWT expiry date."))?
         .timestamp()
-        .unsigned_abs(); // 7 Days expiration
+        .unsigned_abs();
 
     let mut private_claims = BTreeMap::new();
     private_claims.insert(
         "username".into(),
         serde_json::Value::String(username.into()),
     );
+    private_claims.insert(
+        "session".into(),
+        serde_json::Value::String(session_id.into()),
+    );
 
     let claims = Claims {
         registered: RegisteredClaims {
-            audience: None,
-            issued_at: None,
-            issuer: None,
+            audience: Some(env_vars.jwt_audience.clone()),
+            issued_at: Some(now.timestamp().unsigned_abs()),
+            issuer: Some(env_vars.jwt_issuer.clone()),
             subject: None,
-            not_before: None,
-            json_web_token_id: None,
+            not_before: Some(now.timestamp().unsigned_abs()),
+            json_web_token_id: Some(uuid::Uuid::new_v4().to_string()),
             expiration: Some(expiration),
         },
         private: private_claims,
@@ -72,41 +199,220 @@ async fn generate_token(username: &str, env_vars: &EnvVars) -> Res<String> {
     Ok(claims.sign_with_key(&jwt_key)?)
 }
 
-/// Takes a Github OAuth code and creates a JWT authentication token for the user
+/// Lifetime of an OAuth state (in seconds)
+pub const OAUTH_STATE_LIFETIME: u64 = 10 * 60;
+/// Audience of the OAuth states, so that they cannot be used as auth tokens
+const OAUTH_STATE_AUDIENCE: &str = "maintos-oauth-state";
+
+/// Name of the private claim of an OAuth state binding it to the browser that started the login
+const OAUTH_STATE_BINDING_CLAIM: &str = "binding";
+
+/// Generates the random key signing the OAuth states of this server process. States issued before a restart (whose use was only recorded in memory) are thus rejected.
+pub fn generate_oauth_state_key() -> Res<Hmac<Sha256>> {
+    let secret = format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+
+    Ok(Hmac::new_from_slice(secret.as_bytes())?)
+}
+
+/// Generates a signed, short-lived OAuth `state` parameter (a JWT with a random nonce) to protect the login flow against CSRF
+///
+/// The state is bound to the browser that started the login by `binding`, the SHA-256 hash (hex encoded) of a random verifier kept by the browser. The verifier must be sent back with the state, see [`verify_oauth_state`].
+pub fn generate_oauth_state(key: &Hmac<Sha256>, env_vars: &EnvVars, binding: &str) -> Res<String> {
+    let now = chrono::Utc::now().timestamp().unsigned_abs();
+
+    let claims = Claims {
+        registered: RegisteredClaims {
+            audience: Some(OAUTH_STATE_AUDIENCE.into()),
+            issued_at: Some(now),
+            issuer: Some(env_vars.jwt_issuer.clone()),
+            subject: None,
+            not_before: None,
+            json_web_token_id: Some(uuid::Uuid::new_v4().to_string()),
+            expiration: Some(now + OAUTH_STATE_LIFETIME),
+        },
+        private: BTreeMap::from([(
+            OAUTH_STATE_BINDING_CLAIM.to_string(),
+            serde_json::Value::String(binding.to_string()),
+        )]),
+    };
+
+    Ok(claims.sign_with_key(key)?)
+}
+
+/// Verifies an OAuth `state` parameter generated by [`generate_oauth_state`] and the verifier of its binding
+///
+/// Returns the state's nonce if it is valid, not expired and the verifier matches, `None` otherwise.
+pub fn verify_oauth_state(
+    state: &str,
+    verifier: &str,
+    key: &Hmac<Sha256>,
+    env_vars: &EnvVars,
+) -> Option<String> {
+    let claims: Claims = VerifyWithKey::verify_with_key(state, key).ok()?;
+    let registered = claims.registered;
+
+    let now = chrono::Utc::now().timestamp();
+    let is_valid = registered.audience.as_deref() == Some(OAUTH_STATE_AUDIENCE)
+        && registered.issuer.as_deref() == Some(env_vars.jwt_issuer.as_str())
+        && registered
+            .expiration
+            .is_some_and(|expiration| expiration as i64 + env_vars.jwt_clock_skew as i64 >= now)
+        && claims
+            .private
+            .get(OAUTH_STATE_BINDING_CLAIM)
+            .and_then(|binding| binding.as_str())
+            .is_some_and(|binding| binding == utils::hash_secret(verifier));
+
+    registered.json_web_token_id.filter(|_| is_valid)
+}
+
+/// A JWT (access token) and the refresh token of its login session
+pub struct Tokens {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+/// Takes a Github OAuth code, starts a login session and creates a JWT authentication token for the user
 /// 1. Uses the OAuth code to get an access token.
 /// 2. Uses the access token to get the user's username.
 /// 3. Uses the username and an admin's access token to verify whether the user is a member of the admins github team, or the admin themselves.
 ///
-/// Returns the JWT if the user is authenticated, `None` otherwise.
-pub async fn authenticate_user(code: &str, env_vars: &EnvVars) -> Res<Option<String>> {
-    let client = reqwest::Client::new();
-
+/// Returns the JWT and the session's refresh token if the user is authenticated, `None` otherwise.
+pub async fn authenticate_user(
+    code: &str,
+    env_vars: &EnvVars,
+    github: &dyn GithubApi,
+    sessions: &SessionStore,
+) -> Res<Option<Tokens>> {
     // Get the access token for authenticating other endpoints
-    let access_token = github::get_access_token(
-        &client,
-        &env_vars.gh_client_id,
-        &env_vars.gh_client_secret,
-        code,
-    )
-    .await?;
+    let access_token = github.get_access_token(code).await?;
 
     // Get the username of the user who made the request
-    let username = github::get_username(&client, &access_token).await?;
+    let username = github.get_username(&access_token).await?;
 
     // Check the user's membership in the github org
-    let client = reqwest::Client::new();
-
-    let is_member = github::check_membership(
-        &client,
-        &env_vars.gh_org_admin_token,
-        &env_vars.gh_org_name,
-        &username,
-    )
-    .await?;
+    let is_member = github
+        .check_membership(&env_vars.gh_org_name, &username)
+        .await?;
 
     if is_member {
-        Ok(Some(generate_token(&username, env_vars).await?))
+        let (session_id, refresh_token) = sessions.create(&username).await?;
+
+        Ok(Some(Tokens {
+            token: generate_token(&username, &session_id, env_vars).await?,
+            refresh_token,
+        }))
     } else {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::github::fake::FakeGithub;
+
+    fn env_vars() -> EnvVars {
+        EnvVars::parse_from([
+            "maintos",
+            "client-id",
+            "admin-token",
+            "jwt-secret",
+            "client-secret",
+            "metakgp",
+        ])
+    }
+
+    #[tokio::test]
+    async fn only_org_members_are_authenticated() {
+        let env_vars = env_vars();
+        let data_dir = tempfile::tempdir().unwrap();
+        let denylist = TokenDenylist::load(data_dir.path().join("denylist.json"), 60, 30).unwrap();
+        let sessions = SessionStore::load(data_dir.path().join("sessions.json"), 60, 3600).unwrap();
+        let github = FakeGithub::default()
+            .with_user("alice-code", "alice")
+            .with_user("bob-code", "bob")
+            .with_org_member("metakgp", "alice");
+
+        let tokens = authenticate_user("alice-code", &env_vars, &github, &sessions)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            verify_token(&tokens.token, &env_vars, &denylist)
+                .await
+                .unwrap()
+                .username,
+            "alice"
+        );
+
+        assert!(
+            authenticate_user("bob-code", &env_vars, &github, &sessions)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            authenticate_user("invalid-code", &env_vars, &github, &sessions)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_rejected() {
+        let env_vars = env_vars();
+        let data_dir = tempfile::tempdir().unwrap();
+        let denylist_path = data_dir.path().join("denylist.json");
+        let denylist = TokenDenylist::load(denylist_path.clone(), 60, 30).unwrap();
+
+        let first = generate_token("alice", "first-session", &env_vars)
+            .await
+            .unwrap();
+        let second = generate_token("alice", "second-session", &env_vars)
+            .await
+            .unwrap();
+        let third = generate_token("alice", "third-session", &env_vars)
+            .await
+            .unwrap();
+        let auth = verify_token(&first, &env_vars, &denylist).await.unwrap();
+
+        denylist
+            .revoke_token(&auth.token_id, auth.expiration)
+            .await
+            .unwrap();
+        assert!(verify_token(&first, &env_vars, &denylist).await.is_err());
+        assert!(verify_token(&second, &env_vars, &denylist).await.is_ok());
+
+        // The denylist is persisted
+        let denylist = TokenDenylist::load(denylist_path, 60, 30).unwrap();
+        assert!(verify_token(&first, &env_vars, &denylist).await.is_err());
+
+        denylist.revoke_session("second-session").await.unwrap();
+        assert!(verify_token(&second, &env_vars, &denylist).await.is_err());
+        assert!(verify_token(&third, &env_vars, &denylist).await.is_ok());
+
+        denylist.revoke_user("alice").await.unwrap();
+        assert!(verify_token(&third, &env_vars, &denylist).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_kept_until_the_clock_skew_has_passed() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let denylist = TokenDenylist::load(data_dir.path().join("denylist.json"), 60, 30).unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        // Expired, but still accepted within the clock skew
+        denylist.revoke_token("recent", now - 10).await.unwrap();
+        denylist.revoke_token("old", now - 40).await.unwrap();
+
+        assert!(denylist.is_revoked("recent", "session", "alice", now).await);
+        assert!(!denylist.is_revoked("old", "session", "alice", now).await);
+    }
+}
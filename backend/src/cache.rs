@@ -0,0 +1,55 @@
+//! A simple in-memory cache with a time-to-live for its entries
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// An in-memory key-value cache whose entries expire after a fixed time-to-live
+pub struct TtlCache<K, V> {
+    ttl: Duration,
+    entries: Mutex<HashMap<K, (Instant, V)>>,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    /// Creates an empty cache with the given time-to-live
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached value of a key, if it exists and has not expired
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+
+        entries
+            .get(key)
+            .filter(|(inserted_at, _)| inserted_at.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Caches the value of a key, and removes the expired entries
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+
+        entries.retain(|_, (inserted_at, _)| inserted_at.elapsed() < self.ttl);
+        entries.insert(key, (Instant::now(), value));
+    }
+
+    /// Caches the value of a key if it is not cached (or has expired), and removes the expired entries. Returns whether the value was inserted.
+    ///
+    /// The check and the insertion are done under a single lock, so only one of several concurrent callers can insert a key.
+    pub fn insert_if_absent(&self, key: K, value: V) -> bool {
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+
+        entries.retain(|_, (inserted_at, _)| inserted_at.elapsed() < self.ttl);
+        if entries.contains_key(&key) {
+            return false;
+        }
+
+        entries.insert(key, (Instant::now(), value));
+        true
+    }
+}
@@ -0,0 +1,1185 @@
+//! Parsing and validation of the Docker Compose files of deployments
+//!
+//! The compose file at the root of a deployment's directory is parsed into a typed model of its services, networks and volumes. `${VAR}` references are interpolated from the `.env` file next to the compose file, like `docker compose` does. The short and long syntaxes of the compose specification are both accepted, unsupported keys are ignored.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use bollard::{Docker, query_parameters::InspectNetworkOptions};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use tokio::fs;
+
+use crate::{env_file, utils::Res};
+
+pub mod ops;
+
+/// Names of the compose file, in order of precedence
+const COMPOSE_FILE_NAMES: [&str; 4] = [
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yaml",
+    "docker-compose.yml",
+];
+
+/// Name of the network services are attached to if they do not specify any
+pub const DEFAULT_NETWORK: &str = "default";
+
+#[derive(Serialize, Clone)]
+/// The build configuration of a service's image
+pub struct Build {
+    /// The build context, relative to the project directory
+    pub context: String,
+    /// The Dockerfile, relative to the build context (`Dockerfile` by default)
+    pub dockerfile: String,
+    /// Build arguments
+    pub args: BTreeMap<String, String>,
+    /// The build stage to build
+    pub target: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+/// An environment file of a service
+pub struct EnvFileRef {
+    /// Path of the file, relative to the project directory
+    pub path: String,
+    /// Whether the file must exist
+    pub required: bool,
+}
+
+#[derive(Serialize, Clone)]
+/// A published port of a service
+pub struct Port {
+    /// The host IP the port is published on (all interfaces by default)
+    pub host_ip: Option<String>,
+    /// The host port (or port range), a random port if `None`
+    pub published: Option<String>,
+    /// The container port (or port range)
+    pub target: String,
+    /// `tcp` or `udp`
+    pub protocol: String,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+/// The type of a mount
+pub enum MountKind {
+    /// A host path
+    Bind,
+    /// A (named or anonymous) volume
+    Volume,
+    /// A temporary in-memory filesystem
+    Tmpfs,
+}
+
+#[derive(Serialize, Clone)]
+/// A mount of a service's containers
+pub struct Mount {
+    pub kind: MountKind,
+    /// The host path (for binds, relative paths are resolved from the project directory) or volume name (`None` for anonymous volumes and tmpfs)
+    pub source: Option<String>,
+    /// The path in the container
+    pub target: String,
+    pub read_only: bool,
+}
+
+#[derive(Serialize, Clone)]
+/// A network a service is attached to
+pub struct ServiceNetwork {
+    /// Name of the network (a key of the top-level `networks`)
+    pub name: String,
+    /// Aliases of the service on the network
+    pub aliases: Vec<String>,
+}
+
+#[derive(Serialize, Clone)]
+/// A service of a compose project
+pub struct Service {
+    pub name: String,
+    /// The image to run (or to tag the built image with)
+    pub image: Option<String>,
+    pub build: Option<Build>,
+    pub container_name: Option<String>,
+    /// Overrides the image's command
+    pub command: Option<Vec<String>>,
+    /// Overrides the image's entrypoint
+    pub entrypoint: Option<Vec<String>>,
+    pub env_files: Vec<EnvFileRef>,
+    /// Environment variables, `None` values are taken from the project's `.env` file
+    pub environment: BTreeMap<String, Option<String>>,
+    pub ports: Vec<Port>,
+    pub mounts: Vec<Mount>,
+    pub networks: Vec<ServiceNetwork>,
+    /// The network mode (eg: `host`), replaces the networks
+    pub network_mode: Option<String>,
+    /// The restart policy (eg: `always`, `unless-stopped`)
+    pub restart: Option<String>,
+    /// Services that must be started before this one
+    pub depends_on: Vec<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Clone)]
+/// A network or volume of a compose project
+pub struct Resource {
+    /// The key of the resource in the compose file
+    pub key: String,
+    /// The name of the resource in docker (`<project>_<key>` by default)
+    pub name: String,
+    /// Whether the resource is created outside of the project (and must exist before deploying)
+    pub external: bool,
+    pub driver: Option<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Clone)]
+/// A parsed compose project
+pub struct ComposeProject {
+    /// Path of the compose file, relative to the deployment directory
+    pub file: String,
+    /// Name of the project (the `name` key, or the name of the deployment directory)
+    pub name: String,
+    pub services: Vec<Service>,
+    pub networks: Vec<Resource>,
+    pub volumes: Vec<Resource>,
+    /// Problems that prevent deploying the project
+    pub errors: Vec<String>,
+    /// Problems that do not prevent deploying the project (eg: unset variables)
+    pub warnings: Vec<String>,
+}
+
+/// A value given as a single item or a list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            Self::One(item) => vec![item],
+            Self::Many(items) => items,
+        }
+    }
+}
+
+/// A mapping given as a map or as a list of `KEY=value` items (eg: `environment`, `labels`)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawMapping {
+    List(Vec<String>),
+    Map(BTreeMap<String, Option<Value>>),
+}
+
+impl RawMapping {
+    fn into_map(self) -> BTreeMap<String, Option<String>> {
+        match self {
+            Self::List(items) => items
+                .into_iter()
+                .map(|item| match item.split_once('=') {
+                    Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                    None => (item, None),
+                })
+                .collect(),
+            Self::Map(map) => map
+                .into_iter()
+                .map(|(key, value)| (key, value.and_then(scalar_to_string)))
+                .collect(),
+        }
+    }
+
+    /// Returns the mapping, with missing values replaced by empty strings
+    fn into_string_map(self) -> BTreeMap<String, String> {
+        self.into_map()
+            .into_iter()
+            .map(|(key, value)| (key, value.unwrap_or_default()))
+            .collect()
+    }
+}
+
+/// A command given as a string (split on whitespace) or a list of arguments
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawCommand {
+    String(String),
+    List(Vec<String>),
+}
+
+impl RawCommand {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::String(command) => command.split_whitespace().map(String::from).collect(),
+            Self::List(args) => args,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawBuild {
+    Context(String),
+    Config {
+        context: Option<String>,
+        dockerfile: Option<String>,
+        args: Option<RawMapping>,
+        target: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawEnvFile {
+    Path(String),
+    Config {
+        path: String,
+        #[serde(default = "default_true")]
+        required: bool,
+    },
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPort {
+    Number(u32),
+    Short(String),
+    Long {
+        target: Value,
+        published: Option<Value>,
+        host_ip: Option<String>,
+        protocol: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawMount {
+    Short(String),
+    Long {
+        #[serde(rename = "type")]
+        kind: String,
+        source: Option<String>,
+        target: String,
+        #[serde(default)]
+        read_only: bool,
+    },
+}
+
+#[derive(Deserialize, Default)]
+struct RawServiceNetworkConfig {
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawServiceNetworks {
+    List(Vec<String>),
+    Map(BTreeMap<String, Option<RawServiceNetworkConfig>>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDependsOn {
+    List(Vec<String>),
+    Map(BTreeMap<String, Value>),
+}
+
+#[derive(Deserialize)]
+struct RawService {
+    image: Option<String>,
+    build: Option<RawBuild>,
+    container_name: Option<String>,
+    command: Option<RawCommand>,
+    entrypoint: Option<RawCommand>,
+    env_file: Option<OneOrMany<RawEnvFile>>,
+    environment: Option<RawMapping>,
+    #[serde(default)]
+    ports: Vec<RawPort>,
+    #[serde(default)]
+    volumes: Vec<RawMount>,
+    networks: Option<RawServiceNetworks>,
+    network_mode: Option<String>,
+    restart: Option<String>,
+    depends_on: Option<RawDependsOn>,
+    labels: Option<RawMapping>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawExternal {
+    Flag(bool),
+    /// Legacy syntax: `external: { name: ... }`
+    Named {
+        name: String,
+    },
+}
+
+#[derive(Deserialize, Default)]
+struct RawResource {
+    name: Option<String>,
+    external: Option<RawExternal>,
+    driver: Option<String>,
+    labels: Option<RawMapping>,
+}
+
+#[derive(Deserialize)]
+struct RawCompose {
+    name: Option<String>,
+    #[serde(default)]
+    services: BTreeMap<String, RawService>,
+    #[serde(default)]
+    networks: BTreeMap<String, Option<RawResource>>,
+    #[serde(default)]
+    volumes: BTreeMap<String, Option<RawResource>>,
+}
+
+/// Converts a scalar YAML value (string, number or boolean) to a string
+fn scalar_to_string(value: Value) -> Option<String> {
+    match value {
+        Value::String(string) => Some(string),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(bool) => Some(bool.to_string()),
+        _ => None,
+    }
+}
+
+/// Returns the index of the `}` closing a `${` reference in the string following it, skipping the nested references (eg: in `${A:-${B}}`)
+fn find_closing_brace(string: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = string.char_indices().peekable();
+
+    while let Some((index, char)) = chars.next() {
+        match char {
+            '$' if chars.next_if(|(_, next)| *next == '$').is_some() => {}
+            '$' if chars.next_if(|(_, next)| *next == '{').is_some() => depth += 1,
+            '}' if depth == 0 => return Some(index),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Interpolates the `$VAR`, `${VAR}`, `${VAR:-default}`, `${VAR-default}`, `${VAR:?error}`, `${VAR?error}`, `${VAR:+replacement}` and `${VAR+replacement}` references in a string. `$$` is an escaped `$`. Defaults and replacements can contain references themselves (eg: `${A:-${B}}`), they are interpolated when used. Unset variables are replaced by an empty string and added to `unset`.
+fn interpolate(
+    string: &str,
+    vars: &HashMap<String, String>,
+    unset: &mut BTreeSet<String>,
+) -> Res<String> {
+    let mut result = String::new();
+    let mut rest = string;
+
+    while let Some(index) = rest.find('$') {
+        result.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = find_closing_brace(after)
+                .ok_or(anyhow!("Unterminated variable reference in `{string}`."))?;
+            let expression = &after[..end];
+            rest = &after[end + 1..];
+
+            let name_len = expression
+                .find(|char: char| !(char.is_ascii_alphanumeric() || char == '_'))
+                .unwrap_or(expression.len());
+            let (name, modifier) = expression.split_at(name_len);
+            if name.is_empty() {
+                return Err(anyhow!("Invalid variable reference `${{{expression}}}`."));
+            }
+
+            let value = vars.get(name);
+            let is_set_non_empty = value.is_some_and(|value| !value.is_empty());
+
+            let substituted = match modifier {
+                "" => value.cloned(),
+                _ if modifier.starts_with(":-") => Some(if is_set_non_empty {
+                    value.cloned().unwrap_or_default()
+                } else {
+                    interpolate(&modifier[2..], vars, unset)?
+                }),
+                _ if modifier.starts_with('-') => Some(match value {
+                    Some(value) => value.clone(),
+                    None => interpolate(&modifier[1..], vars, unset)?,
+                }),
+                _ if modifier.starts_with(":?") || modifier.starts_with('?') => {
+                    let is_valid = if modifier.starts_with(':') {
+                        is_set_non_empty
+                    } else {
+                        value.is_some()
+                    };
+                    if !is_valid {
+                        return Err(anyhow!(
+                            "Required variable {name} is not set: {}",
+                            &modifier.strip_prefix(':').unwrap_or(modifier)[1..]
+                        ));
+                    }
+                    value.cloned()
+                }
+                _ if modifier.starts_with(":+") => Some(if is_set_non_empty {
+                    interpolate(&modifier[2..], vars, unset)?
+                } else {
+                    String::new()
+                }),
+                _ if modifier.starts_with('+') => Some(if value.is_some() {
+                    interpolate(&modifier[1..], vars, unset)?
+                } else {
+                    String::new()
+                }),
+                _ => return Err(anyhow!("Invalid variable reference `${{{expression}}}`.")),
+            };
+
+            match substituted {
+                Some(substituted) => result.push_str(&substituted),
+                None => {
+                    unset.insert(name.to_string());
+                }
+            }
+        } else {
+            let name_len = rest
+                .find(|char: char| !(char.is_ascii_alphanumeric() || char == '_'))
+                .unwrap_or(rest.len());
+            let (name, after) = rest.split_at(name_len);
+            rest = after;
+
+            if name.is_empty() {
+                result.push('$');
+            } else if let Some(value) = vars.get(name) {
+                result.push_str(value);
+            } else {
+                unset.insert(name.to_string());
+            }
+        }
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Interpolates all the strings of a YAML value (see [`interpolate`])
+fn interpolate_value(
+    value: &mut Value,
+    vars: &HashMap<String, String>,
+    unset: &mut BTreeSet<String>,
+) -> Res<()> {
+    match value {
+        Value::String(string) => *string = interpolate(string, vars, unset)?,
+        Value::Sequence(values) => {
+            for value in values {
+                interpolate_value(value, vars, unset)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                interpolate_value(value, vars, unset)?;
+            }
+        }
+        Value::Tagged(tagged) => interpolate_value(&mut tagged.value, vars, unset)?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Returns the project name derived from a directory name, as `docker compose` does (lowercase alphanumeric characters, `_` and `-`)
+fn normalize_project_name(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .filter(|char| char.is_ascii_alphanumeric() || *char == '_' || *char == '-')
+        .collect()
+}
+
+/// Parses a port in the short syntax (`[[host_ip:]published:]target[/protocol]`)
+fn parse_port(port: &str) -> Port {
+    let (port, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+    let mut parts = port.rsplitn(3, ':');
+
+    let target = parts.next().unwrap_or_default().to_string();
+    let published = parts
+        .next()
+        .filter(|published| !published.is_empty())
+        .map(String::from);
+    let host_ip = parts.next().map(String::from);
+
+    Port {
+        host_ip,
+        published,
+        target,
+        protocol: protocol.to_string(),
+    }
+}
+
+/// Parses a mount in the short syntax (`[source:]target[:mode]`)
+fn parse_mount(mount: &str) -> Mount {
+    let parts: Vec<&str> = mount.split(':').collect();
+
+    let (source, target, mode) = match parts.as_slice() {
+        [target] => (None, *target, ""),
+        [source, target] => (Some(*source), *target, ""),
+        [source, target, mode, ..] => (Some(*source), *target, *mode),
+        [] => (None, "", ""),
+    };
+    let kind = match source {
+        Some(source) if source.starts_with(['/', '.', '~']) => MountKind::Bind,
+        _ => MountKind::Volume,
+    };
+
+    Mount {
+        kind,
+        source: source.map(String::from),
+        target: target.to_string(),
+        read_only: mode.split(',').any(|option| option == "ro"),
+    }
+}
+
+impl Service {
+    /// Converts a parsed service
+    fn from_raw(name: String, raw: RawService) -> Res<Self> {
+        let build = raw.build.map(|build| match build {
+            RawBuild::Context(context) => Build {
+                context,
+                dockerfile: "Dockerfile".into(),
+                args: BTreeMap::new(),
+                target: None,
+            },
+            RawBuild::Config {
+                context,
+                dockerfile,
+                args,
+                target,
+            } => Build {
+                context: context.unwrap_or_else(|| ".".into()),
+                dockerfile: dockerfile.unwrap_or_else(|| "Dockerfile".into()),
+                args: args.map(RawMapping::into_string_map).unwrap_or_default(),
+                target,
+            },
+        });
+
+        let env_files = raw
+            .env_file
+            .map(OneOrMany::into_vec)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|env_file| match env_file {
+                RawEnvFile::Path(path) => EnvFileRef {
+                    path,
+                    required: true,
+                },
+                RawEnvFile::Config { path, required } => EnvFileRef { path, required },
+            })
+            .collect();
+
+        let ports = raw
+            .ports
+            .into_iter()
+            .map(|port| match port {
+                RawPort::Number(target) => Ok(Port {
+                    host_ip: None,
+                    published: None,
+                    target: target.to_string(),
+                    protocol: "tcp".into(),
+                }),
+                RawPort::Short(port) => Ok(parse_port(&port)),
+                RawPort::Long {
+                    target,
+                    published,
+                    host_ip,
+                    protocol,
+                } => Ok(Port {
+                    host_ip,
+                    published: published.and_then(scalar_to_string),
+                    target: scalar_to_string(target)
+                        .ok_or(anyhow!("Invalid port target in service {name}."))?,
+                    protocol: protocol.unwrap_or_else(|| "tcp".into()),
+                }),
+            })
+            .collect::<Res<_>>()?;
+
+        let mounts = raw
+            .volumes
+            .into_iter()
+            .map(|mount| match mount {
+                RawMount::Short(mount) => Ok(parse_mount(&mount)),
+                RawMount::Long {
+                    kind,
+                    source,
+                    target,
+                    read_only,
+                } => Ok(Mount {
+                    kind: match kind.as_str() {
+                        "bind" => MountKind::Bind,
+                        "volume" => MountKind::Volume,
+                        "tmpfs" => MountKind::Tmpfs,
+                        _ => {
+                            return Err(anyhow!(
+                                "Unsupported mount type {kind} in service {name}."
+                            ));
+                        }
+                    },
+                    source,
+                    target,
+                    read_only,
+                }),
+            })
+            .collect::<Res<_>>()?;
+
+        let networks = match raw.networks {
+            None if raw.network_mode.is_none() => vec![ServiceNetwork {
+                name: DEFAULT_NETWORK.into(),
+                aliases: Vec::new(),
+            }],
+            None => Vec::new(),
+            Some(RawServiceNetworks::List(names)) => names
+                .into_iter()
+                .map(|name| ServiceNetwork {
+                    name,
+                    aliases: Vec::new(),
+                })
+                .collect(),
+            Some(RawServiceNetworks::Map(map)) => map
+                .into_iter()
+                .map(|(name, config)| ServiceNetwork {
+                    name,
+                    aliases: config.unwrap_or_default().aliases,
+                })
+                .collect(),
+        };
+
+        let depends_on = match raw.depends_on {
+            None => Vec::new(),
+            Some(RawDependsOn::List(services)) => services,
+            Some(RawDependsOn::Map(map)) => map.into_keys().collect(),
+        };
+
+        Ok(Self {
+            name,
+            image: raw.image,
+            build,
+            container_name: raw.container_name,
+            command: raw.command.map(RawCommand::into_vec),
+            entrypoint: raw.entrypoint.map(RawCommand::into_vec),
+            env_files,
+            environment: raw
+                .environment
+                .map(RawMapping::into_map)
+                .unwrap_or_default(),
+            ports,
+            mounts,
+            networks,
+            network_mode: raw.network_mode,
+            restart: raw.restart,
+            depends_on,
+            labels: raw
+                .labels
+                .map(RawMapping::into_string_map)
+                .unwrap_or_default(),
+        })
+    }
+}
+
+impl Resource {
+    /// Converts a parsed network or volume
+    fn from_raw(project: &str, key: String, raw: Option<RawResource>) -> Self {
+        let raw = raw.unwrap_or_default();
+
+        let (external, external_name) = match raw.external {
+            Some(RawExternal::Flag(external)) => (external, None),
+            Some(RawExternal::Named { name }) => (true, Some(name)),
+            None => (false, None),
+        };
+        let name = external_name.or(raw.name).unwrap_or_else(|| {
+            if external {
+                key.clone()
+            } else {
+                format!("{project}_{key}")
+            }
+        });
+
+        Self {
+            key,
+            name,
+            external,
+            driver: raw.driver,
+            labels: raw
+                .labels
+                .map(RawMapping::into_string_map)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl ComposeProject {
+    /// Parses a compose file. `vars` are the variables used for interpolation, `default_name` is the project name used if the file does not set one.
+    fn parse(
+        file: String,
+        content: &str,
+        vars: &HashMap<String, String>,
+        default_name: &str,
+    ) -> Res<Self> {
+        let mut value: Value = serde_yaml::from_str(content)?;
+
+        let mut unset = BTreeSet::new();
+        interpolate_value(&mut value, vars, &mut unset)?;
+
+        let raw: RawCompose = serde_yaml::from_value(value)?;
+        let name = normalize_project_name(raw.name.as_deref().unwrap_or(default_name));
+
+        let services = raw
+            .services
+            .into_iter()
+            .map(|(service_name, service)| Service::from_raw(service_name, service))
+            .collect::<Res<_>>()?;
+
+        let mut networks: Vec<Resource> = raw
+            .networks
+            .into_iter()
+            .map(|(key, network)| Resource::from_raw(&name, key, network))
+            .collect();
+        if !networks
+            .iter()
+            .any(|network| network.key == DEFAULT_NETWORK)
+        {
+            networks.push(Resource::from_raw(&name, DEFAULT_NETWORK.into(), None));
+        }
+
+        let volumes = raw
+            .volumes
+            .into_iter()
+            .map(|(key, volume)| Resource::from_raw(&name, key, volume))
+            .collect();
+
+        Ok(Self {
+            file,
+            name,
+            services,
+            networks,
+            volumes,
+            errors: Vec::new(),
+            warnings: unset
+                .into_iter()
+                .map(|var| format!("Variable {var} is not set, defaulting to a blank string."))
+                .collect(),
+        })
+    }
+
+    /// Replaces the non-empty values of the services' environment variables, build arguments, commands, entrypoints and labels (and the labels of the networks and volumes) with [`env_file::MASKED_VALUE`], as they may contain secrets (eg: interpolated from the `.env` file)
+    pub fn mask_secrets(&mut self) {
+        let mask = |value: &mut String| {
+            if !value.is_empty() {
+                *value = env_file::MASKED_VALUE.into();
+            }
+        };
+
+        for service in &mut self.services {
+            service.environment.values_mut().flatten().for_each(mask);
+            if let Some(build) = &mut service.build {
+                build.args.values_mut().for_each(mask);
+            }
+            service.command.iter_mut().flatten().for_each(mask);
+            service.entrypoint.iter_mut().flatten().for_each(mask);
+            service.labels.values_mut().for_each(mask);
+        }
+
+        for resource in self.networks.iter_mut().chain(&mut self.volumes) {
+            resource.labels.values_mut().for_each(mask);
+        }
+    }
+
+    /// Returns the networks used by at least one service (the default network is only created if it is used)
+    pub fn used_networks(&self) -> impl Iterator<Item = &Resource> {
+        self.networks.iter().filter(|network| {
+            self.services.iter().any(|service| {
+                service
+                    .networks
+                    .iter()
+                    .any(|service_network| service_network.name == network.key)
+            })
+        })
+    }
+
+    /// Checks the services' references to networks, volumes, other services and files. Adds the problems to [`Self::errors`].
+    async fn check(&mut self, project_dir: &Path) {
+        let mut errors = Vec::new();
+        let service_names: BTreeSet<&str> = self
+            .services
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect();
+
+        for service in &self.services {
+            let name = &service.name;
+
+            if service.image.is_none() && service.build.is_none() {
+                errors.push(format!("Service {name} has neither an image nor a build."));
+            }
+
+            if let Some(build) = &service.build
+                && !build.context.contains("://")
+            {
+                let context = project_dir.join(&build.context);
+                if !fs::metadata(&context).await.is_ok_and(|meta| meta.is_dir()) {
+                    errors.push(format!(
+                        "Build context {} of service {name} does not exist.",
+                        build.context
+                    ));
+                } else if !fs::try_exists(context.join(&build.dockerfile))
+                    .await
+                    .unwrap_or(false)
+                {
+                    errors.push(format!(
+                        "Dockerfile {} of service {name} does not exist.",
+                        build.dockerfile
+                    ));
+                }
+            }
+
+            for env_file in &service.env_files {
+                if env_file.required
+                    && !fs::try_exists(project_dir.join(&env_file.path))
+                        .await
+                        .unwrap_or(false)
+                {
+                    errors.push(format!(
+                        "Environment file {} of service {name} does not exist.",
+                        env_file.path
+                    ));
+                }
+            }
+
+            for network in &service.networks {
+                if !self
+                    .networks
+                    .iter()
+                    .any(|declared| declared.key == network.name)
+                {
+                    errors.push(format!(
+                        "Service {name} uses the undeclared network {}.",
+                        network.name
+                    ));
+                }
+            }
+
+            for mount in &service.mounts {
+                if mount.kind == MountKind::Volume
+                    && let Some(source) = &mount.source
+                    && !self.volumes.iter().any(|declared| &declared.key == source)
+                {
+                    errors.push(format!(
+                        "Service {name} uses the undeclared volume {source}."
+                    ));
+                }
+            }
+
+            for dependency in &service.depends_on {
+                if !service_names.contains(dependency.as_str()) {
+                    errors.push(format!(
+                        "Service {name} depends on the undefined service {dependency}."
+                    ));
+                }
+            }
+        }
+
+        self.errors.extend(errors);
+    }
+
+    /// Checks that the external networks and volumes used by the project exist. Adds the problems to [`Self::errors`].
+    pub async fn check_external_resources(&mut self, docker: &Docker) {
+        let mut errors = Vec::new();
+
+        for network in self.used_networks().filter(|network| network.external) {
+            if docker
+                .inspect_network(&network.name, None::<InspectNetworkOptions>)
+                .await
+                .is_err()
+            {
+                errors.push(format!("External network {} does not exist.", network.name));
+            }
+        }
+        for volume in self.volumes.iter().filter(|volume| volume.external) {
+            if docker.inspect_volume(&volume.name).await.is_err() {
+                errors.push(format!("External volume {} does not exist.", volume.name));
+            }
+        }
+
+        self.errors.extend(errors);
+    }
+}
+
+/// Finds the compose file at the root of a deployment's directory
+async fn find_compose_file(deployment_dir: &Path) -> Option<PathBuf> {
+    for name in COMPOSE_FILE_NAMES {
+        let path = deployment_dir.join(name);
+
+        if fs::try_exists(&path).await.unwrap_or(false) {
+            return Some(path);
+        }
+    }
+
+    None
+}
+
+/// Parses and checks the compose file at the root of a deployment's directory (see [`ComposeProject::check_external_resources`] for the checks requiring docker). Returns `None` if the deployment has no compose file.
+pub async fn load_project(deployment_dir: &Path) -> Res<Option<ComposeProject>> {
+    let Some(path) = find_compose_file(deployment_dir).await else {
+        return Ok(None);
+    };
+
+    let content = fs::read_to_string(&path).await?;
+    let vars = env_file::read_env_vars(&deployment_dir.join(".env"))
+        .await?
+        .unwrap_or_default();
+    let default_name = deployment_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let file = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut project = ComposeProject::parse(file.clone(), &content, &vars, &default_name)
+        .map_err(|err| anyhow!("Error parsing {file}: {err}"))?;
+    project.check(deployment_dir).await;
+
+    Ok(Some(project))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables_are_interpolated() {
+        let vars = HashMap::from([
+            ("SET".to_string(), "value".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]);
+        let mut unset = BTreeSet::new();
+        let mut interpolate = |string| interpolate(string, &vars, &mut unset);
+
+        assert_eq!(interpolate("$SET ${SET}/x").unwrap(), "value value/x");
+        assert_eq!(interpolate("$$SET costs $$5").unwrap(), "$SET costs $5");
+        assert_eq!(interpolate("${EMPTY:-default}").unwrap(), "default");
+        assert_eq!(interpolate("${EMPTY-default}").unwrap(), "");
+        assert_eq!(interpolate("${UNSET-default}").unwrap(), "default");
+        assert_eq!(interpolate("${SET:+replaced}").unwrap(), "replaced");
+        assert_eq!(interpolate("${UNSET:+replaced}").unwrap(), "");
+        assert_eq!(interpolate("a${UNSET}b").unwrap(), "ab");
+        assert!(interpolate("${UNSET:?must be set}").is_err());
+        assert!(interpolate("${SET").is_err());
+        // Nested references in defaults and replacements
+        assert_eq!(interpolate("${UNSET:-${SET}}/x").unwrap(), "value/x");
+        assert_eq!(interpolate("${EMPTY:-${UNSET:-a}b}").unwrap(), "ab");
+        assert_eq!(interpolate("${SET:+$${SET}}").unwrap(), "${SET}");
+        assert_eq!(interpolate("${SET:-${OTHER}}").unwrap(), "value");
+        assert!(interpolate("${UNSET:-${SET}").is_err());
+        assert_eq!(unset, BTreeSet::from(["UNSET".to_string()]));
+    }
+
+    #[test]
+    fn the_backend_compose_file_is_parsed() {
+        let vars = HashMap::from([
+            ("DEPLOYMENTS_DIR".to_string(), "/deployments".to_string()),
+            ("DATA_DIR".to_string(), "/data".to_string()),
+        ]);
+        let project = ComposeProject::parse(
+            "docker-compose.yml".into(),
+            include_str!("../docker-compose.yml"),
+            &vars,
+            "Maintos",
+        )
+        .unwrap();
+
+        assert_eq!(project.name, "maintos");
+        assert!(project.warnings.is_empty());
+
+        let [service] = project.services.as_slice() else {
+            panic!("Expected a single service.");
+        };
+        assert_eq!(service.name, "maintos-backend");
+        assert_eq!(service.image.as_deref(), Some("metakgporg/maintos-backend"));
+        assert_eq!(service.build.as_ref().unwrap().context, ".");
+        assert_eq!(service.restart.as_deref(), Some("always"));
+        assert_eq!(service.env_files[0].path, ".env");
+        assert_eq!(service.networks[0].name, "metaploy-network");
+        assert_eq!(service.networks[0].aliases, ["maintos-backend"]);
+
+        let mounts: Vec<(MountKind, Option<&str>, &str)> = service
+            .mounts
+            .iter()
+            .map(|mount| (mount.kind, mount.source.as_deref(), mount.target.as_str()))
+            .collect();
+        assert_eq!(
+            mounts,
+            [
+                (
+                    MountKind::Volume,
+                    Some("nginx-config-volume"),
+                    "/etc/nginx/sites-enabled"
+                ),
+                (
+                    MountKind::Bind,
+                    Some("/var/run/docker.sock"),
+                    "/var/run/docker.sock"
+                ),
+                (MountKind::Bind, Some("/deployments"), "/deployments"),
+                (MountKind::Volume, Some("maintos-data"), "/data"),
+            ]
+        );
+
+        let network = project
+            .networks
+            .iter()
+            .find(|network| network.key == "metaploy-network")
+            .unwrap();
+        assert!(network.external);
+        assert_eq!(network.name, "metaploy-network");
+        assert_eq!(project.used_networks().count(), 1);
+
+        let volumes: Vec<(&str, &str, bool)> = project
+            .volumes
+            .iter()
+            .map(|volume| (volume.key.as_str(), volume.name.as_str(), volume.external))
+            .collect();
+        assert_eq!(
+            volumes,
+            [
+                ("maintos-data", "maintos_maintos-data", false),
+                ("nginx-config-volume", "metaploy-nginx-config-volume", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn short_and_long_syntaxes_are_parsed() {
+        let content = r#"
+name: app
+services:
+  web:
+    build:
+      context: ./web
+      args:
+        VERSION: 2
+    command: npm start
+    env_file:
+      - path: .env.web
+        required: false
+    environment:
+      - MODE=production
+      - INHERITED
+    ports:
+      - "127.0.0.1:8080:80/udp"
+      - 3000
+      - target: 443
+        published: "8443"
+    volumes:
+      - type: tmpfs
+        target: /tmp
+      - ./static:/static:ro
+    depends_on:
+      db:
+        condition: service_healthy
+    labels:
+      traefik.enable: true
+  db:
+    image: postgres:16
+    network_mode: host
+"#;
+        let project =
+            ComposeProject::parse("compose.yaml".into(), content, &HashMap::new(), "x").unwrap();
+
+        let web = &project.services[1];
+        let build = web.build.as_ref().unwrap();
+        assert_eq!(build.context, "./web");
+        assert_eq!(build.args["VERSION"], "2");
+        assert_eq!(
+            web.command.as_deref(),
+            Some(&["npm".into(), "start".into()][..])
+        );
+        assert!(!web.env_files[0].required);
+        assert_eq!(web.environment["MODE"].as_deref(), Some("production"));
+        assert_eq!(web.environment["INHERITED"], None);
+
+        let ports: Vec<(Option<&str>, Option<&str>, &str, &str)> = web
+            .ports
+            .iter()
+            .map(|port| {
+                (
+                    port.host_ip.as_deref(),
+                    port.published.as_deref(),
+                    port.target.as_str(),
+                    port.protocol.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            ports,
+            [
+                (Some("127.0.0.1"), Some("8080"), "80", "udp"),
+                (None, None, "3000", "tcp"),
+                (None, Some("8443"), "443", "tcp"),
+            ]
+        );
+
+        assert_eq!(web.mounts[0].kind, MountKind::Tmpfs);
+        assert_eq!(web.mounts[1].kind, MountKind::Bind);
+        assert!(web.mounts[1].read_only);
+        assert_eq!(web.depends_on, ["db"]);
+        assert_eq!(web.labels["traefik.enable"], "true");
+        assert_eq!(web.networks[0].name, DEFAULT_NETWORK);
+
+        let db = &project.services[0];
+        assert!(db.networks.is_empty());
+        assert_eq!(project.networks[0].name, "app_default");
+    }
+
+    #[tokio::test]
+    async fn references_are_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("compose.yaml"),
+            r#"
+services:
+  web:
+    build: ./missing
+    env_file: .env.web
+    networks: [backend]
+    volumes:
+      - data:/data
+    depends_on: [db]
+    environment:
+      TOKEN: ${TOKEN}
+  worker:
+    command: run
+"#,
+        )
+        .unwrap();
+
+        let project = load_project(dir.path()).await.unwrap().unwrap();
+        assert_eq!(
+            project.errors,
+            [
+                "Build context ./missing of service web does not exist.",
+                "Environment file .env.web of service web does not exist.",
+                "Service web uses the undeclared network backend.",
+                "Service web uses the undeclared volume data.",
+                "Service web depends on the undefined service db.",
+                "Service worker has neither an image nor a build.",
+            ]
+        );
+        assert_eq!(
+            project.warnings,
+            ["Variable TOKEN is not set, defaulting to a blank string."]
+        );
+    }
+}
@@ -0,0 +1,822 @@
+//! Compose-like operations on compose projects (`build`, `pull`, `up -d` and `down`), over the docker API
+//!
+//! The containers, networks and volumes are created with the same names and labels as with `docker compose`, so that the projects can still be managed with it (and their containers are found by [`docker::get_project_containers`]). A hash of the configuration and image of each container is stored in a label, `up` only recreates the containers whose configuration or image changed.
+//!
+//! Relative bind mounts are resolved from the project directory, so the deployments directory must be mounted at the same path in the maintos container as on the host.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::anyhow;
+use bollard::{
+    Docker,
+    models::{
+        ContainerCreateBody, ContainerSummary, ContainerSummaryStateEnum, EndpointSettings,
+        HostConfig, Mount as DockerMount, MountTypeEnum, NetworkConnectRequest,
+        NetworkCreateRequest, NetworkingConfig, PortBinding, RestartPolicy, RestartPolicyNameEnum,
+        VolumeCreateOptions,
+    },
+    query_parameters::{
+        CreateContainerOptionsBuilder, InspectNetworkOptions, ListContainersOptionsBuilder,
+        RemoveContainerOptions, StartContainerOptions, StopContainerOptions,
+    },
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{ComposeProject, MountKind, Resource, Service};
+use crate::{
+    docker::{
+        self, BuildOptions, COMPOSE_CONFIG_FILES_LABEL, COMPOSE_PROJECT_LABEL,
+        COMPOSE_SERVICE_LABEL, COMPOSE_WORKING_DIR_LABEL,
+    },
+    env_file,
+    utils::Res,
+};
+
+/// Label containing the hash of the configuration and image of a container
+const CONFIG_HASH_LABEL: &str = "com.docker.compose.config-hash";
+/// Label containing the number of a container in its service (always `1`, services are not scaled)
+const CONTAINER_NUMBER_LABEL: &str = "com.docker.compose.container-number";
+/// Label marking the containers of one-off commands (`docker compose run`)
+const ONEOFF_LABEL: &str = "com.docker.compose.oneoff";
+/// Label containing the key of a network in the compose file
+const NETWORK_LABEL: &str = "com.docker.compose.network";
+/// Label containing the key of a volume in the compose file
+const VOLUME_LABEL: &str = "com.docker.compose.volume";
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+/// A compose operation
+pub enum ComposeOperation {
+    /// Build the images of the services with a build configuration
+    Build,
+    /// Pull the images of the services without a build configuration
+    Pull,
+    /// Create the networks, volumes and containers, and start the containers
+    Up,
+    /// Stop and remove the containers and networks (the volumes are kept)
+    Down,
+}
+
+impl ComposeOperation {
+    /// Returns the name of the operation
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Build => "build",
+            Self::Pull => "pull",
+            Self::Up => "up",
+            Self::Down => "down",
+        }
+    }
+}
+
+/// Returns the image of a service (the image built for services without an `image` is named `<project>-<service>`)
+fn image_name(project: &ComposeProject, service: &Service) -> String {
+    service
+        .image
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}", project.name, service.name))
+}
+
+/// Returns the name of the container of a service (`<project>-<service>-1` by default)
+fn container_name(project: &ComposeProject, service: &Service) -> String {
+    service
+        .container_name
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}-1", project.name, service.name))
+}
+
+/// Returns the services ordered so that every service comes after its dependencies
+fn start_order(project: &ComposeProject) -> Res<Vec<&Service>> {
+    let mut ordered: Vec<&Service> = Vec::new();
+
+    while ordered.len() < project.services.len() {
+        let ready: Vec<&Service> = project
+            .services
+            .iter()
+            .filter(|service| !ordered.iter().any(|done| done.name == service.name))
+            .filter(|service| {
+                service
+                    .depends_on
+                    .iter()
+                    .all(|dependency| ordered.iter().any(|done| &done.name == dependency))
+            })
+            .collect();
+
+        if ready.is_empty() {
+            return Err(anyhow!("The service dependencies form a cycle."));
+        }
+        ordered.extend(ready);
+    }
+
+    Ok(ordered)
+}
+
+/// Returns the docker name of a network or volume of the project, by its key in the compose file
+fn resource_name<'a>(resources: &'a [Resource], key: &str) -> Res<&'a str> {
+    resources
+        .iter()
+        .find(|resource| resource.key == key)
+        .map(|resource| resource.name.as_str())
+        .ok_or(anyhow!("Undeclared network or volume {key}."))
+}
+
+/// Resolves a (relative) host path from the project directory, removing the `.` and `..` components
+fn resolve_host_path(project_dir: &Path, path: &str) -> String {
+    let path = match path.strip_prefix('~') {
+        Some(rest) => PathBuf::from(std::env::var("HOME").unwrap_or_default())
+            .join(rest.trim_start_matches('/')),
+        None => project_dir.join(path),
+    };
+
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+
+    resolved.display().to_string()
+}
+
+/// Parses a port or port range (eg: `8000-8010`)
+fn parse_port_range(range: &str) -> Res<Vec<u16>> {
+    let invalid = || anyhow!("Invalid port `{range}`.");
+
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let (start, end): (u16, u16) = (
+        start.trim().parse().map_err(|_| invalid())?,
+        end.trim().parse().map_err(|_| invalid())?,
+    );
+    if start > end {
+        return Err(invalid());
+    }
+
+    Ok((start..=end).collect())
+}
+
+/// Parses a restart policy (`no`, `always`, `unless-stopped` or `on-failure[:max-retries]`)
+fn parse_restart_policy(policy: &str) -> Res<RestartPolicy> {
+    let (name, max_retries) = policy.split_once(':').unwrap_or((policy, ""));
+
+    let name = match name {
+        "no" => RestartPolicyNameEnum::NO,
+        "always" => RestartPolicyNameEnum::ALWAYS,
+        "unless-stopped" => RestartPolicyNameEnum::UNLESS_STOPPED,
+        "on-failure" => RestartPolicyNameEnum::ON_FAILURE,
+        _ => return Err(anyhow!("Invalid restart policy `{policy}`.")),
+    };
+    let maximum_retry_count = match max_retries {
+        "" => None,
+        max_retries => Some(
+            max_retries
+                .parse()
+                .map_err(|_| anyhow!("Invalid restart policy `{policy}`."))?,
+        ),
+    };
+
+    Ok(RestartPolicy {
+        name: Some(name),
+        maximum_retry_count,
+    })
+}
+
+/// Returns the environment variables of a service's container: the variables of its env files (in order), overridden by its `environment`. Variables of the `environment` without a value are taken from the project's `.env` file, and are left unset otherwise (the environment of maintos, which contains its secrets, is never used).
+async fn service_environment(project_dir: &Path, service: &Service) -> Res<Vec<String>> {
+    let mut environment = BTreeMap::new();
+
+    for env_file in &service.env_files {
+        match env_file::read_env_vars(&project_dir.join(&env_file.path)).await? {
+            Some(vars) => environment.extend(vars),
+            None if env_file.required => {
+                return Err(anyhow!(
+                    "Environment file {} of service {} does not exist.",
+                    env_file.path,
+                    service.name
+                ));
+            }
+            None => {}
+        }
+    }
+
+    let project_vars = env_file::read_env_vars(&project_dir.join(".env"))
+        .await?
+        .unwrap_or_default();
+    for (key, value) in &service.environment {
+        let value = value.clone().or_else(|| project_vars.get(key).cloned());
+
+        match value {
+            Some(value) => environment.insert(key.clone(), value),
+            None => environment.remove(key),
+        };
+    }
+
+    Ok(environment
+        .into_iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect())
+}
+
+/// The configuration of the container of a service
+struct ContainerConfig {
+    body: ContainerCreateBody,
+    /// Networks the container is connected to after its creation (a container can only be created with a single network), by network name
+    extra_networks: Vec<(String, EndpointSettings)>,
+}
+
+/// Returns the configuration of the container of a service (without the config hash label)
+async fn container_config(
+    project: &ComposeProject,
+    project_dir: &Path,
+    service: &Service,
+) -> Res<ContainerConfig> {
+    let mut labels: HashMap<String, String> = service.labels.clone().into_iter().collect();
+    labels.extend([
+        (COMPOSE_PROJECT_LABEL.into(), project.name.clone()),
+        (COMPOSE_SERVICE_LABEL.into(), service.name.clone()),
+        (
+            COMPOSE_WORKING_DIR_LABEL.into(),
+            project_dir.display().to_string(),
+        ),
+        (
+            COMPOSE_CONFIG_FILES_LABEL.into(),
+            project_dir.join(&project.file).display().to_string(),
+        ),
+        (CONTAINER_NUMBER_LABEL.into(), "1".into()),
+        (ONEOFF_LABEL.into(), "False".into()),
+    ]);
+
+    let mut exposed_ports = HashMap::new();
+    let mut port_bindings = HashMap::new();
+    for port in &service.ports {
+        let targets = parse_port_range(&port.target)?;
+        let published = match &port.published {
+            // A range of published ports for a single target port lets docker pick a free one
+            Some(published) if targets.len() == 1 => vec![Some(published.clone())],
+            Some(published_range) => {
+                let published = parse_port_range(published_range)?;
+                if published.len() != targets.len() {
+                    return Err(anyhow!(
+                        "The port ranges {published_range} and {} of service {} have different lengths.",
+                        port.target,
+                        service.name
+                    ));
+                }
+                published
+                    .into_iter()
+                    .map(|port| Some(port.to_string()))
+                    .collect()
+            }
+            None => vec![None; targets.len()],
+        };
+
+        for (target, published) in targets.into_iter().zip(published) {
+            let key = format!("{target}/{}", port.protocol);
+            exposed_ports.insert(key.clone(), HashMap::new());
+            port_bindings
+                .entry(key)
+                .or_insert_with(|| Some(Vec::new()))
+                .get_or_insert_default()
+                .push(PortBinding {
+                    host_ip: port.host_ip.clone(),
+                    host_port: Some(published.unwrap_or_default()),
+                });
+        }
+    }
+
+    let mounts = service
+        .mounts
+        .iter()
+        .map(|mount| {
+            let (typ, source) = match mount.kind {
+                MountKind::Bind => (
+                    MountTypeEnum::BIND,
+                    mount
+                        .source
+                        .as_deref()
+                        .map(|source| resolve_host_path(project_dir, source)),
+                ),
+                MountKind::Volume => (
+                    MountTypeEnum::VOLUME,
+                    mount
+                        .source
+                        .as_deref()
+                        .map(|source| resource_name(&project.volumes, source).map(String::from))
+                        .transpose()?,
+                ),
+                MountKind::Tmpfs => (MountTypeEnum::TMPFS, None),
+            };
+
+            Ok(DockerMount {
+                target: Some(mount.target.clone()),
+                source,
+                typ: Some(typ),
+                read_only: Some(mount.read_only),
+                ..Default::default()
+            })
+        })
+        .collect::<Res<_>>()?;
+
+    let mut networks = service
+        .networks
+        .iter()
+        .map(|network| {
+            let mut aliases = vec![service.name.clone()];
+            aliases.extend(network.aliases.iter().cloned());
+
+            Ok((
+                resource_name(&project.networks, &network.name)?.to_string(),
+                EndpointSettings {
+                    aliases: Some(aliases),
+                    ..Default::default()
+                },
+            ))
+        })
+        .collect::<Res<Vec<_>>>()?
+        .into_iter();
+    let first_network = networks.next();
+
+    let network_mode = match (&service.network_mode, &first_network) {
+        (Some(mode), _) => Some(match mode.strip_prefix("service:") {
+            Some(other) => {
+                let other = project
+                    .services
+                    .iter()
+                    .find(|service| service.name == other)
+                    .ok_or(anyhow!("Undefined service {other} in network_mode."))?;
+                format!("container:{}", container_name(project, other))
+            }
+            None => mode.clone(),
+        }),
+        (None, Some((name, _))) => Some(name.clone()),
+        (None, None) => None,
+    };
+
+    let body = ContainerCreateBody {
+        image: Some(image_name(project, service)),
+        cmd: service.command.clone(),
+        entrypoint: service.entrypoint.clone(),
+        env: Some(service_environment(project_dir, service).await?),
+        labels: Some(labels),
+        exposed_ports: Some(exposed_ports),
+        host_config: Some(HostConfig {
+            port_bindings: Some(port_bindings),
+            mounts: Some(mounts),
+            network_mode,
+            restart_policy: service
+                .restart
+                .as_deref()
+                .map(parse_restart_policy)
+                .transpose()?,
+            ..Default::default()
+        }),
+        networking_config: Some(NetworkingConfig {
+            endpoints_config: first_network.map(|network| HashMap::from([network])),
+        }),
+        ..Default::default()
+    };
+
+    Ok(ContainerConfig {
+        body,
+        extra_networks: networks.collect(),
+    })
+}
+
+/// Returns the hash of the configuration and image of a container
+fn config_hash(body: &ContainerCreateBody, image_id: &str) -> Res<String> {
+    // JSON objects are serialized with sorted keys, so the hash is stable
+    let config = serde_json::to_value(body)?.to_string();
+
+    let mut hasher = Sha256::new();
+    hasher.update(config.as_bytes());
+    hasher.update(image_id.as_bytes());
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Returns the value of a label of a container
+fn get_label<'a>(container: &'a ContainerSummary, label: &str) -> Option<&'a str> {
+    container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(label))
+        .map(String::as_str)
+}
+
+/// Returns all the containers (running or not) of a compose project, excluding the one-off containers
+async fn get_containers(docker: &Docker, project: &ComposeProject) -> Res<Vec<ContainerSummary>> {
+    let project_filter = format!("{COMPOSE_PROJECT_LABEL}={}", project.name);
+    let options = ListContainersOptionsBuilder::new()
+        .all(true)
+        .filters(&HashMap::from([("label", vec![project_filter.as_str()])]))
+        .build();
+
+    Ok(docker
+        .list_containers(Some(options))
+        .await?
+        .into_iter()
+        .filter(|container| {
+            get_label(container, COMPOSE_PROJECT_LABEL) == Some(project.name.as_str())
+                && get_label(container, ONEOFF_LABEL) != Some("True")
+        })
+        .collect())
+}
+
+/// Stops and removes a container
+async fn remove_container(docker: &Docker, container: &ContainerSummary) -> Res<String> {
+    let id = container
+        .id
+        .as_deref()
+        .ok_or(anyhow!("Error: Container has no id."))?;
+    let name = docker::get_container_name(container).unwrap_or(id.to_string());
+
+    if container.state == Some(ContainerSummaryStateEnum::RUNNING) {
+        docker
+            .stop_container(id, None::<StopContainerOptions>)
+            .await?;
+    }
+    docker
+        .remove_container(id, None::<RemoveContainerOptions>)
+        .await?;
+
+    Ok(name)
+}
+
+/// Builds the images of the services with a build configuration (tagged with the service's image)
+///
+/// Returns the build output.
+pub async fn build(docker: &Docker, project: &ComposeProject, project_dir: &Path) -> Res<String> {
+    let mut output = String::new();
+
+    for service in &project.services {
+        let Some(build) = &service.build else {
+            continue;
+        };
+        let image = image_name(project, service);
+
+        output.push_str(&format!("Building {image} (service {})\n", service.name));
+        output.push_str(
+            &docker::build_image(
+                docker,
+                Path::new(&resolve_host_path(project_dir, &build.context)),
+                &image,
+                BuildOptions {
+                    dockerfile: &build.dockerfile,
+                    args: &build.args,
+                    target: build.target.as_deref(),
+                },
+            )
+            .await?,
+        );
+    }
+
+    if output.is_empty() {
+        output.push_str("No services to build.");
+    }
+
+    Ok(output)
+}
+
+/// Pulls the images of the services without a build configuration
+///
+/// Returns the pull output.
+pub async fn pull(docker: &Docker, project: &ComposeProject) -> Res<String> {
+    let mut output = String::new();
+
+    for service in &project.services {
+        if let (Some(image), None) = (&service.image, &service.build) {
+            output.push_str(&format!("Pulling {image} (service {})\n", service.name));
+            output.push_str(&docker::pull_image(docker, image).await?);
+        }
+    }
+
+    if output.is_empty() {
+        output.push_str("No images to pull.");
+    }
+
+    Ok(output)
+}
+
+/// Creates the missing networks and volumes, and (re)creates and starts the containers of the services (after their dependencies). The containers whose configuration and image did not change are only started if they are stopped.
+///
+/// Returns the output (one line per change).
+pub async fn up(docker: &Docker, project: &ComposeProject, project_dir: &Path) -> Res<String> {
+    let mut output = Vec::new();
+
+    for network in project.used_networks().filter(|network| !network.external) {
+        if docker
+            .inspect_network(&network.name, None::<InspectNetworkOptions>)
+            .await
+            .is_err()
+        {
+            let mut labels: HashMap<String, String> = network.labels.clone().into_iter().collect();
+            labels.insert(COMPOSE_PROJECT_LABEL.into(), project.name.clone());
+            labels.insert(NETWORK_LABEL.into(), network.key.clone());
+
+            docker
+                .create_network(NetworkCreateRequest {
+                    name: network.name.clone(),
+                    driver: network.driver.clone(),
+                    labels: Some(labels),
+                    ..Default::default()
+                })
+                .await?;
+            output.push(format!("Created network {}.", network.name));
+        }
+    }
+
+    for volume in project.volumes.iter().filter(|volume| !volume.external) {
+        if docker.inspect_volume(&volume.name).await.is_err() {
+            let mut labels: HashMap<String, String> = volume.labels.clone().into_iter().collect();
+            labels.insert(COMPOSE_PROJECT_LABEL.into(), project.name.clone());
+            labels.insert(VOLUME_LABEL.into(), volume.key.clone());
+
+            docker
+                .create_volume(VolumeCreateOptions {
+                    name: Some(volume.name.clone()),
+                    driver: volume.driver.clone(),
+                    labels: Some(labels),
+                    ..Default::default()
+                })
+                .await?;
+            output.push(format!("Created volume {}.", volume.name));
+        }
+    }
+
+    let containers = get_containers(docker, project).await?;
+
+    for service in start_order(project)? {
+        let image = image_name(project, service);
+        let image_id = match docker::get_image_id(docker, &image).await {
+            Ok(image_id) => image_id,
+            Err(_) if service.build.is_none() => {
+                docker::pull_image(docker, &image).await?;
+                output.push(format!("Pulled image {image}."));
+                docker::get_image_id(docker, &image).await?
+            }
+            Err(err) => return Err(err),
+        };
+
+        let ContainerConfig {
+            mut body,
+            extra_networks,
+        } = container_config(project, project_dir, service).await?;
+        let hash = config_hash(&body, &image_id)?;
+        body.labels
+            .get_or_insert_default()
+            .insert(CONFIG_HASH_LABEL.into(), hash.clone());
+
+        let name = container_name(project, service);
+        let current: Vec<&ContainerSummary> = containers
+            .iter()
+            .filter(|container| {
+                get_label(container, COMPOSE_SERVICE_LABEL) == Some(service.name.as_str())
+            })
+            .collect();
+
+        if let [container] = current.as_slice()
+            && get_label(container, CONFIG_HASH_LABEL) == Some(hash.as_str())
+            && docker::get_container_name(container).as_deref() == Some(name.as_str())
+            && let Some(id) = &container.id
+        {
+            if container.state == Some(ContainerSummaryStateEnum::RUNNING) {
+                output.push(format!("Container {name} is up to date."));
+            } else {
+                docker
+                    .start_container(id, None::<StartContainerOptions>)
+                    .await?;
+                output.push(format!("Started container {name}."));
+            }
+            continue;
+        }
+
+        for container in &current {
+            let removed = remove_container(docker, container).await?;
+            output.push(format!("Removed container {removed}."));
+        }
+
+        let container = docker
+            .create_container(
+                Some(CreateContainerOptionsBuilder::new().name(&name).build()),
+                body,
+            )
+            .await?;
+        for (network, endpoint) in extra_networks {
+            docker
+                .connect_network(
+                    &network,
+                    NetworkConnectRequest {
+                        container: Some(container.id.clone()),
+                        endpoint_config: Some(endpoint),
+                    },
+                )
+                .await?;
+        }
+        docker
+            .start_container(&container.id, None::<StartContainerOptions>)
+            .await?;
+        output.push(format!("Created and started container {name}."));
+    }
+
+    for container in &containers {
+        if let Some(service) = get_label(container, COMPOSE_SERVICE_LABEL)
+            && !project
+                .services
+                .iter()
+                .any(|defined| defined.name == service)
+        {
+            output.push(format!(
+                "Warning: Found the container {} of the undefined service {service}, remove it with `down`.",
+                docker::get_container_name(container).unwrap_or_default()
+            ));
+        }
+    }
+
+    Ok(output.join("\n"))
+}
+
+/// Stops and removes the containers (including those of services no longer in the compose file) and the networks of a project. The volumes are kept.
+///
+/// Returns the output (one line per change).
+pub async fn down(docker: &Docker, project: &ComposeProject) -> Res<String> {
+    let mut output = Vec::new();
+
+    let mut containers = get_containers(docker, project).await?;
+    // Dependents are removed before their dependencies
+    let order = start_order(project).unwrap_or_default();
+    containers.sort_by_key(|container| {
+        std::cmp::Reverse(
+            order
+                .iter()
+                .position(|service| {
+                    get_label(container, COMPOSE_SERVICE_LABEL) == Some(service.name.as_str())
+                })
+                .map_or(usize::MAX, |position| position + 1),
+        )
+    });
+
+    for container in &containers {
+        let name = remove_container(docker, container).await?;
+        output.push(format!("Removed container {name}."));
+    }
+
+    for network in project.networks.iter().filter(|network| !network.external) {
+        if docker
+            .inspect_network(&network.name, None::<InspectNetworkOptions>)
+            .await
+            .is_ok()
+        {
+            docker.remove_network(&network.name).await?;
+            output.push(format!("Removed network {}.", network.name));
+        }
+    }
+
+    if output.is_empty() {
+        output.push("Nothing to remove.".into());
+    }
+
+    Ok(output.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> ComposeProject {
+        ComposeProject::parse("compose.yaml".into(), content, &HashMap::new(), "app").unwrap()
+    }
+
+    #[test]
+    fn services_start_after_their_dependencies() {
+        let project = parse(
+            "services:\n  web:\n    image: web\n    depends_on: [api]\n  api:\n    image: api\n    depends_on: [db]\n  db:\n    image: db\n",
+        );
+        let order: Vec<&str> = start_order(&project)
+            .unwrap()
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect();
+        assert_eq!(order, ["db", "api", "web"]);
+
+        let project = parse(
+            "services:\n  a:\n    image: a\n    depends_on: [b]\n  b:\n    image: b\n    depends_on: [a]\n",
+        );
+        assert!(start_order(&project).is_err());
+    }
+
+    #[test]
+    fn restart_policies_and_ports_are_parsed() {
+        let policy = parse_restart_policy("on-failure:3").unwrap();
+        assert_eq!(policy.name, Some(RestartPolicyNameEnum::ON_FAILURE));
+        assert_eq!(policy.maximum_retry_count, Some(3));
+        assert_eq!(
+            parse_restart_policy("unless-stopped").unwrap().name,
+            Some(RestartPolicyNameEnum::UNLESS_STOPPED)
+        );
+        assert!(parse_restart_policy("sometimes").is_err());
+
+        assert_eq!(parse_port_range("80").unwrap(), [80]);
+        assert_eq!(parse_port_range("8000-8002").unwrap(), [8000, 8001, 8002]);
+        assert!(parse_port_range("9000-8000").is_err());
+        assert!(parse_port_range("http").is_err());
+
+        assert_eq!(
+            resolve_host_path(Path::new("/deployments/app"), "./data/../static"),
+            "/deployments/app/static"
+        );
+    }
+
+    #[tokio::test]
+    async fn container_configs_follow_the_compose_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".env"), "TOKEN=secret\n").unwrap();
+        std::fs::write(dir.path().join("web.env"), "MODE=dev\nPORT=80\n").unwrap();
+
+        let project = parse(
+            r#"
+services:
+  web:
+    build: .
+    restart: always
+    env_file: web.env
+    environment:
+      MODE: production
+      TOKEN:
+      UNSET_ANYWHERE_0C9F:
+      PATH:
+    ports:
+      - "127.0.0.1:8080:80"
+    volumes:
+      - data:/data
+      - ./static:/static:ro
+    networks:
+      front:
+        aliases: [site]
+      back:
+    labels:
+      team: web
+networks:
+  front:
+  back:
+    external: true
+    name: shared
+volumes:
+  data:
+"#,
+        );
+        let config = container_config(&project, dir.path(), &project.services[0])
+            .await
+            .unwrap();
+        let body = config.body;
+
+        assert_eq!(body.image.as_deref(), Some("app-web"));
+        // Variables without a value are never taken from the environment of maintos (eg: `PATH`)
+        assert_eq!(
+            body.env.unwrap(),
+            ["MODE=production", "PORT=80", "TOKEN=secret"]
+        );
+
+        let labels = body.labels.unwrap();
+        assert_eq!(labels["team"], "web");
+        assert_eq!(labels[COMPOSE_PROJECT_LABEL], "app");
+        assert_eq!(labels[COMPOSE_SERVICE_LABEL], "web");
+
+        let host_config = body.host_config.unwrap();
+        assert_eq!(
+            host_config.restart_policy.unwrap().name,
+            Some(RestartPolicyNameEnum::ALWAYS)
+        );
+        let binding = &host_config.port_bindings.unwrap()["80/tcp"]
+            .clone()
+            .unwrap()[0];
+        assert_eq!(binding.host_ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(binding.host_port.as_deref(), Some("8080"));
+
+        let mounts = host_config.mounts.unwrap();
+        assert_eq!(mounts[0].source.as_deref(), Some("app_data"));
+        assert_eq!(mounts[0].typ, Some(MountTypeEnum::VOLUME));
+        assert_eq!(
+            mounts[1].source,
+            Some(dir.path().join("static").display().to_string())
+        );
+        assert_eq!(mounts[1].read_only, Some(true));
+
+        // The container is created on the first network and connected to the others
+        assert_eq!(host_config.network_mode.as_deref(), Some("shared"));
+        let endpoints = body.networking_config.unwrap().endpoints_config.unwrap();
+        assert_eq!(endpoints["shared"].aliases, Some(vec!["web".to_string()]));
+        let [(network, endpoint)] = config.extra_networks.as_slice() else {
+            panic!("Expected a single extra network.");
+        };
+        assert_eq!(network, "app_front");
+        assert_eq!(
+            endpoint.aliases,
+            Some(vec!["web".to_string(), "site".to_string()])
+        );
+    }
+}
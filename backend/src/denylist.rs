@@ -0,0 +1,104 @@
+//! Persisted denylist of revoked JWTs
+//!
+//! Tokens are revoked either individually (by their `jti` claim), by session (eg: on logout) or all at once for a user (every token issued to the user before the revocation). The denylist is stored as a JSON file and entries are pruned once the tokens they revoke have expired.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::utils::{self, Res};
+
+#[derive(Serialize, Deserialize, Default)]
+/// The contents of the denylist file
+struct DenylistData {
+    /// Revoked token ids and the expiration time of the tokens (UNIX timestamps in seconds)
+    tokens: HashMap<String, i64>,
+    /// Usernames and the time before which all the tokens issued to them are revoked (UNIX timestamps in seconds)
+    users: HashMap<String, i64>,
+    /// Revoked session ids and the time they were revoked at (UNIX timestamps in seconds)
+    sessions: HashMap<String, i64>,
+}
+
+/// The denylist of revoked tokens
+pub struct TokenDenylist {
+    path: PathBuf,
+    /// Maximum lifetime of a token (in seconds), after which revoked users' entries are pruned
+    token_lifetime: i64,
+    /// Tolerance of the token expiration checks (in seconds), entries are kept for this long after the tokens expire
+    clock_skew: i64,
+    data: RwLock<DenylistData>,
+}
+
+impl TokenDenylist {
+    /// Loads the denylist stored in the given (JSON) file. The file is created on the first revocation. Fails if the file is corrupt, starting with an empty denylist would make the revoked tokens valid again.
+    pub fn load(path: PathBuf, token_lifetime: i64, clock_skew: i64) -> Res<Self> {
+        let data = utils::read_json_file(&path)?;
+
+        Ok(Self {
+            path,
+            token_lifetime,
+            clock_skew,
+            data: RwLock::new(data),
+        })
+    }
+
+    /// Checks whether a token was revoked, by its id, by revoking its session or by revoking all the tokens of its user
+    pub async fn is_revoked(
+        &self,
+        token_id: &str,
+        session_id: &str,
+        username: &str,
+        issued_at: i64,
+    ) -> bool {
+        let data = self.data.read().await;
+
+        data.tokens.contains_key(token_id)
+            || data.sessions.contains_key(session_id)
+            || data
+                .users
+                .get(username)
+                .is_some_and(|revoked_at| issued_at <= *revoked_at)
+    }
+
+    /// Revokes a single token by its id
+    pub async fn revoke_token(&self, token_id: &str, expiration: i64) -> Res<()> {
+        let mut data = self.data.write().await;
+        data.tokens.insert(token_id.to_string(), expiration);
+
+        self.save(&mut data).await
+    }
+
+    /// Revokes all the tokens of a session
+    pub async fn revoke_session(&self, session_id: &str) -> Res<()> {
+        let mut data = self.data.write().await;
+        data.sessions
+            .insert(session_id.to_string(), chrono::Utc::now().timestamp());
+
+        self.save(&mut data).await
+    }
+
+    /// Revokes all the tokens issued to a user until now
+    pub async fn revoke_user(&self, username: &str) -> Res<()> {
+        let mut data = self.data.write().await;
+        data.users
+            .insert(username.to_string(), chrono::Utc::now().timestamp());
+
+        self.save(&mut data).await
+    }
+
+    /// Prunes the entries of expired tokens (including the clock skew tolerated when verifying them) and writes the denylist to its file
+    async fn save(&self, data: &mut DenylistData) -> Res<()> {
+        let now = chrono::Utc::now().timestamp();
+        let retention = self.token_lifetime + self.clock_skew;
+        data.tokens
+            .retain(|_, expiration| *expiration + self.clock_skew > now);
+        data.users
+            .retain(|_, revoked_at| *revoked_at + retention > now);
+        data.sessions
+            .retain(|_, revoked_at| *revoked_at + retention > now);
+
+        utils::write_json_atomic(&self.path, data).await
+    }
+}
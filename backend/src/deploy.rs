@@ -0,0 +1,284 @@
+//! Pull-and-redeploy and rollback of deployments, and compose operations on their compose projects
+//!
+//! Deploys run as background jobs (see [`crate::jobs`]) and are recorded in the deploy history (see [`crate::history`]).
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use bollard::Docker;
+use git2::Repository;
+
+use crate::{
+    compose::{
+        self, ComposeProject,
+        ops::{self, ComposeOperation},
+    },
+    docker,
+    git::{self, FastForward},
+    history::{DeployHistory, DeployRecord},
+    jobs::JobHandle,
+    utils::Res,
+};
+
+/// Runs a step of a deployment operation, writing its output to the job's output. Returns the value of the step, or an error naming the failed step.
+async fn run_step<T>(
+    job: &JobHandle,
+    step: &str,
+    result: impl Future<Output = Res<(T, String)>>,
+) -> Res<T> {
+    job.output(&format!("==> {step}\n")).await;
+
+    let (value, output) = result
+        .await
+        .with_context(|| format!("Step `{step}` failed"))?;
+    job.output(&format!("{}\n", output.trim_end())).await;
+
+    Ok(value)
+}
+
+/// Fetches `origin` and fast-forwards the checked-out branch of a deployment's repository
+async fn pull(repo: Repository, fetch_token: String) -> Res<String> {
+    tokio::task::spawn_blocking(move || {
+        git::fetch_origin(&repo, &fetch_token)?;
+
+        Ok(match git::fast_forward(&repo)? {
+            FastForward::UpToDate => "Already up to date.".into(),
+            FastForward::Updated(old_sha, new_sha) => {
+                format!("Fast-forwarded from {old_sha} to {new_sha}.")
+            }
+        })
+    })
+    .await?
+}
+
+/// Resets the checked-out branch of a deployment's repository to a commit (see [`git::reset_to_commit`])
+async fn reset(repo: Repository, sha: String) -> Res<String> {
+    tokio::task::spawn_blocking(move || {
+        let sha = git::reset_to_commit(&repo, &sha)?;
+        Ok(format!("Checked out {sha}."))
+    })
+    .await?
+}
+
+/// Returns the ids of the images used by a deployment's containers, by image name
+pub async fn get_image_ids(docker: &Docker, deployment_dir: &Path) -> Res<HashMap<String, String>> {
+    Ok(docker::get_project_containers(docker, deployment_dir)
+        .await?
+        .into_iter()
+        .filter_map(|container| Some((container.image?, container.image_id?)))
+        .collect())
+}
+
+/// Rebuilds the locally built images of a deployment and recreates the containers using them
+///
+/// 1. Rebuilds every locally built image used by the deployment's containers from the `Dockerfile` in the container's compose project directory.
+/// 2. Recreates every container whose image was rebuilt.
+async fn rebuild(docker: &Docker, deployment_dir: &Path, job: &JobHandle) -> Res<()> {
+    let containers = docker::get_project_containers(docker, deployment_dir).await?;
+
+    // Images to be rebuilt, with their build contexts
+    let mut images: HashMap<String, PathBuf> = HashMap::new();
+    for container in &containers {
+        if let Some(image) = &container.image
+            && let Some(working_dir) = docker::get_container_working_dir(container)
+            && working_dir.join("Dockerfile").is_file()
+            && docker::is_locally_built(docker, image).await?
+        {
+            images.insert(image.clone(), working_dir);
+        }
+    }
+
+    // New image ids of the rebuilt images
+    let mut image_ids = HashMap::new();
+    for (image, context) in &images {
+        let image_id = run_step(job, &format!("build {image}"), async {
+            let output = docker::build_image(docker, context, image, Default::default()).await?;
+            Ok((docker::get_image_id(docker, image).await?, output))
+        })
+        .await?;
+        image_ids.insert(image.clone(), image_id);
+    }
+
+    for container in &containers {
+        if let (Some(id), Some(image)) = (&container.id, &container.image)
+            && let Some(new_image_id) = image_ids.get(image)
+            && container.image_id.as_ref() != Some(new_image_id)
+        {
+            let name = docker::get_container_name(container).unwrap_or(id.clone());
+
+            run_step(job, &format!("recreate {name}"), async {
+                docker::recreate_container(docker, id).await?;
+                Ok(((), format!("Recreated container {name}.")))
+            })
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses and checks the compose file of a deployment (see [`compose::load_project`]), failing if it has errors. Returns the project (`None` if the deployment has no compose file).
+async fn validate(docker: &Docker, deployment_dir: &Path) -> Res<(Option<ComposeProject>, String)> {
+    let Some(mut project) = compose::load_project(deployment_dir).await? else {
+        return Ok((None, "No compose file found, skipping validation.".into()));
+    };
+    project.check_external_resources(docker).await;
+
+    let mut output: String = project
+        .warnings
+        .iter()
+        .map(|warning| format!("Warning: {warning}\n"))
+        .collect();
+    if !project.errors.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{output}{} has {} error(s):\n{}",
+            project.file,
+            project.errors.len(),
+            project.errors.join("\n")
+        ));
+    }
+    output.push_str(&format!(
+        "{} is valid ({} service(s)).",
+        project.file,
+        project.services.len()
+    ));
+
+    Ok((Some(project), output))
+}
+
+/// Builds and pulls the images of a compose project, and recreates the containers whose configuration or image changed (see [`compose::ops`])
+async fn update_project(
+    docker: &Docker,
+    project: &ComposeProject,
+    deployment_dir: &Path,
+    job: &JobHandle,
+) -> Res<()> {
+    run_step(job, "build", async {
+        Ok(((), ops::build(docker, project, deployment_dir).await?))
+    })
+    .await?;
+    run_step(job, "pull images", async {
+        Ok(((), ops::pull(docker, project).await?))
+    })
+    .await?;
+    run_step(job, "up", async {
+        Ok(((), ops::up(docker, project, deployment_dir).await?))
+    })
+    .await
+}
+
+/// Runs a compose operation on the compose project of a deployment, as a job. The project is validated first, except for `down`.
+pub async fn run_compose_operation(
+    docker: &Docker,
+    deployment_dir: &Path,
+    operation: ComposeOperation,
+    job: &JobHandle,
+) -> Res<()> {
+    let project = if operation == ComposeOperation::Down {
+        compose::load_project(deployment_dir).await?
+    } else {
+        run_step(job, "validate", validate(docker, deployment_dir)).await?
+    }
+    .ok_or(anyhow::anyhow!("The deployment has no compose file."))?;
+
+    run_step(job, operation.name(), async {
+        let output = match operation {
+            ComposeOperation::Build => ops::build(docker, &project, deployment_dir).await?,
+            ComposeOperation::Pull => ops::pull(docker, &project).await?,
+            ComposeOperation::Up => ops::up(docker, &project, deployment_dir).await?,
+            ComposeOperation::Down => ops::down(docker, &project).await?,
+        };
+        Ok(((), output))
+    })
+    .await
+}
+
+/// A deployment operation
+pub enum DeployKind {
+    /// Pull the latest changes of the checked-out branch
+    Redeploy,
+    /// Roll back to a commit (SHA) in the history of the checked-out branch
+    Rollback(String),
+}
+
+impl DeployKind {
+    /// Returns the name of the operation (the job kind)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Redeploy => "redeploy",
+            Self::Rollback(_) => "rollback",
+        }
+    }
+}
+
+/// Redeploys or rolls back a deployment, as a job, recording it in the deploy history
+///
+/// - A redeploy fetches `origin` and fast-forwards the checked-out branch (refusing on divergence or a dirty tree).
+/// - A rollback resets the checked-out branch to a commit in its history (refusing on a dirty tree).
+///
+/// Then the compose file is validated (see [`validate`]), the images are built and pulled and the changed containers are recreated (see [`update_project`]). Deployments without a compose file at their root only get their locally built images rebuilt and the containers using them recreated (see [`rebuild`]). The output of each step is written to the job's output. Stops at the first failed step.
+///
+/// `fetch_token` is the Github token used to fetch `origin` (see [`git::fetch_origin`]).
+#[allow(clippy::too_many_arguments)]
+pub async fn deploy(
+    docker: &Docker,
+    history: &DeployHistory,
+    deployment: &str,
+    username: &str,
+    kind: DeployKind,
+    repo: Repository,
+    fetch_token: &str,
+    job: &JobHandle,
+) -> Res<()> {
+    let deployment_dir = git::get_workdir(&repo)?;
+
+    history
+        .record(
+            deployment,
+            DeployRecord {
+                job_id: job.id().to_string(),
+                kind: kind.name().to_string(),
+                username: username.to_string(),
+                timestamp: chrono::Utc::now().timestamp(),
+                previous_sha: git::get_head_sha(&repo)?,
+                previous_images: get_image_ids(docker, &deployment_dir).await?,
+                sha: None,
+                success: None,
+            },
+        )
+        .await?;
+
+    let result = async {
+        match kind {
+            DeployKind::Redeploy => {
+                run_step(job, "pull", async {
+                    Ok(((), pull(repo, fetch_token.to_string()).await?))
+                })
+                .await?
+            }
+            DeployKind::Rollback(sha) => {
+                run_step(job, &format!("checkout {sha}"), async {
+                    Ok(((), reset(repo, sha).await?))
+                })
+                .await?
+            }
+        }
+
+        match run_step(job, "validate", validate(docker, &deployment_dir)).await? {
+            Some(project) => update_project(docker, &project, &deployment_dir, job).await,
+            None => rebuild(docker, &deployment_dir, job).await,
+        }
+    }
+    .await;
+
+    let sha = Repository::open(&deployment_dir)
+        .map_err(anyhow::Error::from)
+        .and_then(|repo| git::get_head_sha(&repo))
+        .ok();
+    history
+        .finish(deployment, job.id(), sha, result.is_ok())
+        .await?;
+
+    result
+}
@@ -0,0 +1,903 @@
+//! Utils for managing the docker containers and images of deployments
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use bollard::{
+    Docker,
+    container::LogOutput,
+    models::{
+        ContainerCpuStats, ContainerCreateBody, ContainerStatsResponse, ContainerSummary,
+        ContainerSummaryStateEnum, EndpointSettings, NetworkingConfig,
+    },
+    query_parameters::{
+        BuildImageOptionsBuilder, CreateContainerOptionsBuilder, CreateImageOptionsBuilder,
+        InspectContainerOptions, ListContainersOptionsBuilder, LogsOptionsBuilder,
+        RemoveContainerOptions, RestartContainerOptions, StartContainerOptions,
+        StatsOptionsBuilder, StopContainerOptions, StopContainerOptionsBuilder,
+    },
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{dockerignore::DockerIgnore, utils::Res};
+
+/// Label set by docker compose on every container, network and volume, containing the name of the compose project
+pub const COMPOSE_PROJECT_LABEL: &str = "com.docker.compose.project";
+/// Label set by docker compose on every container, containing the directory of the compose project
+pub const COMPOSE_WORKING_DIR_LABEL: &str = "com.docker.compose.project.working_dir";
+/// Label set by docker compose on every container, containing the comma-separated paths of the compose files of the project
+pub const COMPOSE_CONFIG_FILES_LABEL: &str = "com.docker.compose.project.config_files";
+/// Label set by docker compose on every container, containing the name of the compose service
+pub const COMPOSE_SERVICE_LABEL: &str = "com.docker.compose.service";
+
+/// Checks whether a container belongs to a compose project inside a deployment's directory (either the project directory or one of the compose files is inside it)
+fn is_project_container(container: &ContainerSummary, deployment_dir: &Path) -> bool {
+    let Some(labels) = &container.labels else {
+        return false;
+    };
+
+    let in_working_dir = labels
+        .get(COMPOSE_WORKING_DIR_LABEL)
+        .is_some_and(|working_dir| Path::new(working_dir).starts_with(deployment_dir));
+    let in_config_files = labels
+        .get(COMPOSE_CONFIG_FILES_LABEL)
+        .is_some_and(|config_files| {
+            config_files
+                .split(',')
+                .any(|file| Path::new(file).starts_with(deployment_dir))
+        });
+
+    in_working_dir || in_config_files
+}
+
+/// Returns all the containers (running or not) of the compose projects inside a deployment's directory
+pub async fn get_project_containers(
+    docker: &Docker,
+    deployment_dir: &Path,
+) -> Res<Vec<ContainerSummary>> {
+    let containers = docker
+        .list_containers(Some(ListContainersOptionsBuilder::new().all(true).build()))
+        .await?;
+
+    Ok(containers
+        .into_iter()
+        .filter(|container| is_project_container(container, deployment_dir))
+        .collect())
+}
+
+/// Minimum length of a container id prefix used to find a container
+const MIN_CONTAINER_ID_PREFIX_LEN: usize = 4;
+
+/// The result of finding a container by its id, id prefix or name
+pub enum ContainerMatch {
+    /// The id of the container
+    Found(String),
+    NotFound,
+    /// The id prefix matches several containers
+    Ambiguous,
+}
+
+/// Finds a container by its full id or name, or else by a unique id prefix of at least [`MIN_CONTAINER_ID_PREFIX_LEN`] characters
+fn match_container(containers: Vec<ContainerSummary>, container: &str) -> ContainerMatch {
+    let containers: Vec<(String, Option<String>)> = containers
+        .into_iter()
+        .filter_map(|summary| {
+            let name = get_container_name(&summary);
+            summary.id.map(|id| (id, name))
+        })
+        .collect();
+
+    if let Some((id, _)) = containers
+        .iter()
+        .find(|(id, name)| id == container || name.as_deref() == Some(container))
+    {
+        return ContainerMatch::Found(id.clone());
+    }
+
+    if container.len() < MIN_CONTAINER_ID_PREFIX_LEN {
+        return ContainerMatch::NotFound;
+    }
+
+    let mut matches = containers
+        .into_iter()
+        .filter(|(id, _)| id.starts_with(container));
+    match (matches.next(), matches.next()) {
+        (Some((id, _)), None) => ContainerMatch::Found(id),
+        (None, _) => ContainerMatch::NotFound,
+        (Some(_), Some(_)) => ContainerMatch::Ambiguous,
+    }
+}
+
+/// Finds a container of the compose projects inside a deployment's directory by its id, name or unique id prefix (see [`match_container`])
+pub async fn find_project_container(
+    docker: &Docker,
+    deployment_dir: &Path,
+    container: &str,
+) -> Res<ContainerMatch> {
+    Ok(match_container(
+        get_project_containers(docker, deployment_dir).await?,
+        container,
+    ))
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+/// An action that can be performed on a container
+pub enum ContainerAction {
+    Start,
+    Stop,
+    Restart,
+}
+
+/// Starts, stops or restarts a container
+pub async fn run_container_action(
+    docker: &Docker,
+    container_id: &str,
+    action: ContainerAction,
+) -> Res<()> {
+    match action {
+        ContainerAction::Start => {
+            docker
+                .start_container(container_id, None::<StartContainerOptions>)
+                .await?
+        }
+        ContainerAction::Stop => {
+            docker
+                .stop_container(container_id, None::<StopContainerOptions>)
+                .await?
+        }
+        ContainerAction::Restart => {
+            docker
+                .restart_container(container_id, None::<RestartContainerOptions>)
+                .await?
+        }
+    }
+
+    Ok(())
+}
+
+/// A chunk of the logs of a container
+pub struct LogChunk {
+    /// The stream the chunk was written to (`stdout`, `stderr`, `stdin` or `console` for TTY containers)
+    pub stream: &'static str,
+    pub message: String,
+}
+
+/// Returns a stream of the stdout and stderr logs of a container
+///
+/// - `since`: Only return logs since this UNIX timestamp
+/// - `tail`: Only return this number of lines from the end of the logs (or `all`)
+/// - `follow`: Keep streaming new logs
+pub fn stream_logs(
+    docker: &Docker,
+    container_id: &str,
+    since: Option<i32>,
+    tail: &str,
+    follow: bool,
+) -> impl Stream<Item = Res<LogChunk>> + use<> {
+    let mut options = LogsOptionsBuilder::new()
+        .stdout(true)
+        .stderr(true)
+        .follow(follow)
+        .tail(tail);
+    if let Some(since) = since {
+        options = options.since(since);
+    }
+
+    docker
+        .logs(container_id, Some(options.build()))
+        .map(|output| {
+            let (stream, message) = match output? {
+                LogOutput::StdOut { message } => ("stdout", message),
+                LogOutput::StdErr { message } => ("stderr", message),
+                LogOutput::StdIn { message } => ("stdin", message),
+                LogOutput::Console { message } => ("console", message),
+            };
+
+            Ok(LogChunk {
+                stream,
+                message: String::from_utf8_lossy(&message).into_owned(),
+            })
+        })
+}
+
+#[derive(Serialize)]
+/// Resource usage statistics of a container
+pub struct ContainerStats {
+    id: String,
+    /// Container name (without the leading `/`)
+    name: String,
+    /// CPU usage as a percentage of a single CPU core (can exceed 100% on multi-core hosts)
+    cpu_percent: f64,
+    /// Memory usage excluding the page cache (in bytes)
+    memory_usage: u64,
+    /// Memory limit of the container (in bytes)
+    memory_limit: u64,
+    /// Memory usage as a percentage of the limit
+    memory_percent: f64,
+    /// Total bytes received over all networks
+    network_rx: u64,
+    /// Total bytes sent over all networks
+    network_tx: u64,
+    /// Total bytes read from block devices
+    block_read: u64,
+    /// Total bytes written to block devices
+    block_write: u64,
+}
+
+/// Computes the resource usage of a container from a raw docker stats response, in the same way as the `docker stats` command
+fn compute_stats(id: String, stats: ContainerStatsResponse) -> ContainerStats {
+    let total_usage = |cpu_stats: &Option<ContainerCpuStats>| {
+        cpu_stats
+            .as_ref()
+            .and_then(|cpu| cpu.cpu_usage.as_ref())
+            .and_then(|usage| usage.total_usage)
+            .unwrap_or(0)
+    };
+    let system_usage = |cpu_stats: &Option<ContainerCpuStats>| {
+        cpu_stats
+            .as_ref()
+            .and_then(|cpu| cpu.system_cpu_usage)
+            .unwrap_or(0)
+    };
+
+    let cpu_delta = total_usage(&stats.cpu_stats).saturating_sub(total_usage(&stats.precpu_stats));
+    let system_delta =
+        system_usage(&stats.cpu_stats).saturating_sub(system_usage(&stats.precpu_stats));
+    let online_cpus = stats
+        .cpu_stats
+        .as_ref()
+        .and_then(|cpu| {
+            cpu.online_cpus.or(cpu
+                .cpu_usage
+                .as_ref()
+                .and_then(|usage| usage.percpu_usage.as_ref())
+                .map(|percpu| percpu.len() as u32))
+        })
+        .unwrap_or(1);
+
+    let cpu_percent = if cpu_delta > 0 && system_delta > 0 {
+        cpu_delta as f64 / system_delta as f64 * online_cpus as f64 * 100.0
+    } else {
+        0.0
+    };
+
+    // The page cache is not counted as used memory (`inactive_file` on cgroup v2, `total_inactive_file` on cgroup v1)
+    let (memory_usage, memory_limit) = stats
+        .memory_stats
+        .as_ref()
+        .map(|memory| {
+            let cache = memory
+                .stats
+                .as_ref()
+                .and_then(|stats| {
+                    stats
+                        .get("inactive_file")
+                        .or(stats.get("total_inactive_file"))
+                        .copied()
+                })
+                .unwrap_or(0);
+
+            (
+                memory.usage.unwrap_or(0).saturating_sub(cache),
+                memory.limit.unwrap_or(0),
+            )
+        })
+        .unwrap_or((0, 0));
+
+    let memory_percent = if memory_limit > 0 {
+        memory_usage as f64 / memory_limit as f64 * 100.0
+    } else {
+        0.0
+    };
+
+    let (network_rx, network_tx) = stats
+        .networks
+        .iter()
+        .flat_map(|networks| networks.values())
+        .fold((0, 0), |(rx, tx), network| {
+            (
+                rx + network.rx_bytes.unwrap_or(0),
+                tx + network.tx_bytes.unwrap_or(0),
+            )
+        });
+
+    let (block_read, block_write) = stats
+        .blkio_stats
+        .and_then(|blkio| blkio.io_service_bytes_recursive)
+        .unwrap_or_default()
+        .into_iter()
+        .fold((0, 0), |(read, write), entry| {
+            let value = entry.value.unwrap_or(0);
+
+            match entry.op.as_deref().map(|op| op.to_lowercase()).as_deref() {
+                Some("read") => (read + value, write),
+                Some("write") => (read, write + value),
+                _ => (read, write),
+            }
+        });
+
+    ContainerStats {
+        name: stats
+            .name
+            .map(|name| name.trim_start_matches('/').to_string())
+            .unwrap_or(id.clone()),
+        id,
+        cpu_percent,
+        memory_usage,
+        memory_limit,
+        memory_percent,
+        network_rx,
+        network_tx,
+        block_read,
+        block_write,
+    }
+}
+
+/// Returns a stream of the resource usage statistics of a container (one item roughly every second)
+pub fn stream_stats(
+    docker: &Docker,
+    container_id: &str,
+) -> impl Stream<Item = Res<ContainerStats>> + use<> {
+    let id = container_id.to_string();
+
+    docker
+        .stats(
+            container_id,
+            Some(StatsOptionsBuilder::new().stream(true).build()),
+        )
+        .map(move |stats| Ok(compute_stats(id.clone(), stats?)))
+}
+
+/// Returns a snapshot of the resource usage statistics of a container
+pub async fn get_stats(docker: &Docker, container_id: &str) -> Res<ContainerStats> {
+    let stats = docker
+        .stats(
+            container_id,
+            Some(StatsOptionsBuilder::new().stream(false).build()),
+        )
+        .next()
+        .await
+        .ok_or(anyhow!(
+            "Error: No stats received for container {container_id}."
+        ))??;
+
+    Ok(compute_stats(container_id.to_string(), stats))
+}
+
+/// Returns the ids of the running containers of the compose projects inside a deployment's directory
+pub async fn get_running_project_containers(
+    docker: &Docker,
+    deployment_dir: &Path,
+) -> Res<Vec<String>> {
+    Ok(get_project_containers(docker, deployment_dir)
+        .await?
+        .into_iter()
+        .filter(|container| container.state == Some(ContainerSummaryStateEnum::RUNNING))
+        .filter_map(|container| container.id)
+        .collect())
+}
+
+#[derive(Serialize)]
+/// A port exposed by a container
+pub struct ContainerPort {
+    /// Host IP the port is published on
+    ip: Option<String>,
+    /// Port inside the container
+    private_port: u16,
+    /// Port published on the host
+    public_port: Option<u16>,
+    /// Protocol (`tcp`, `udp` or `sctp`)
+    protocol: Option<String>,
+}
+
+#[derive(Serialize)]
+/// Information about a container of a deployment
+pub struct ContainerInfo {
+    id: String,
+    /// Container name (without the leading `/`)
+    name: String,
+    /// Name of the compose service the container belongs to
+    service: Option<String>,
+    image: Option<String>,
+    /// Container state (eg: `running`, `exited`)
+    state: Option<String>,
+    /// Human-readable status (eg: `Up 2 hours`)
+    status: Option<String>,
+    ports: Vec<ContainerPort>,
+    /// Number of times the container has been restarted by docker
+    restart_count: Option<i64>,
+    /// Creation time (UNIX timestamp in seconds)
+    created: Option<i64>,
+}
+
+/// Returns the name of a container (without the leading `/`), falling back to the id
+pub fn get_container_name(container: &ContainerSummary) -> Option<String> {
+    container
+        .names
+        .as_ref()
+        .and_then(|names| names.first())
+        .map(|name| name.trim_start_matches('/').to_string())
+        .or(container.id.clone())
+}
+
+/// Returns information about all the containers of a deployment
+pub async fn get_deployment_containers(
+    docker: &Docker,
+    deployment_dir: &Path,
+) -> Res<Vec<ContainerInfo>> {
+    let mut containers = Vec::new();
+
+    for container in get_project_containers(docker, deployment_dir).await? {
+        let Some(id) = container.id.clone() else {
+            continue;
+        };
+
+        // The restart count is not included in the container list
+        let restart_count = docker
+            .inspect_container(&id, None::<InspectContainerOptions>)
+            .await?
+            .restart_count;
+
+        containers.push(ContainerInfo {
+            name: get_container_name(&container).unwrap_or(id.clone()),
+            id,
+            service: container
+                .labels
+                .as_ref()
+                .and_then(|labels| labels.get(COMPOSE_SERVICE_LABEL))
+                .cloned(),
+            image: container.image,
+            state: container.state.map(|state| state.to_string()),
+            status: container.status,
+            ports: container
+                .ports
+                .unwrap_or_default()
+                .into_iter()
+                .map(|port| ContainerPort {
+                    ip: port.ip,
+                    private_port: port.private_port,
+                    public_port: port.public_port,
+                    protocol: port.typ.map(|typ| typ.to_string()),
+                })
+                .collect(),
+            restart_count,
+            created: container.created,
+        });
+    }
+
+    Ok(containers)
+}
+
+/// Returns the compose project directory of a container, if it is part of a compose project
+pub fn get_container_working_dir(container: &ContainerSummary) -> Option<PathBuf> {
+    container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(COMPOSE_WORKING_DIR_LABEL))
+        .map(PathBuf::from)
+}
+
+/// Checks whether an image was built locally (i.e., it was never pulled from or pushed to a registry)
+pub async fn is_locally_built(docker: &Docker, image: &str) -> Res<bool> {
+    let image = docker.inspect_image(image).await?;
+
+    Ok(image.repo_digests.is_none_or(|digests| digests.is_empty()))
+}
+
+/// Returns the id of an image
+pub async fn get_image_id(docker: &Docker, image: &str) -> Res<String> {
+    docker
+        .inspect_image(image)
+        .await?
+        .id
+        .ok_or(anyhow!("Error: Image {image} has no id."))
+}
+
+/// Files of a deployment's directory that are never sent to the docker daemon: the git directory and the env files (secrets)
+const BUILD_CONTEXT_EXCLUDED: [&str; 3] = [".git", ".env", ".env.bak"];
+
+/// Size of the chunks the build context archive is streamed in
+const BUILD_CONTEXT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes the build context archive into a channel, in chunks
+struct ChannelWriter(tokio::sync::mpsc::Sender<std::io::Result<bytes::Bytes>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(bytes::Bytes::copy_from_slice(buf)))
+            // The build stopped reading the context
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Appends the entries of a directory of the build context to its archive, recursively, skipping the excluded paths
+fn append_build_context_dir<W: std::io::Write>(
+    archive: &mut tar::Builder<W>,
+    context: &Path,
+    dir: &Path,
+    ignore: &DockerIgnore,
+    dockerfile: &Path,
+) -> Res<()> {
+    for entry in std::fs::read_dir(context.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+
+        if dir.as_os_str().is_empty()
+            && BUILD_CONTEXT_EXCLUDED.contains(&entry.file_name().to_string_lossy().as_ref())
+        {
+            continue;
+        }
+
+        // The Dockerfile and `.dockerignore` are always sent, like `docker build` does
+        let excluded =
+            ignore.is_excluded(&path) && path != dockerfile && path != Path::new(".dockerignore");
+
+        if is_dir {
+            if !excluded {
+                archive.append_dir(&path, entry.path())?;
+            }
+            // Exceptions can re-include paths inside an excluded directory
+            if !excluded || ignore.has_exceptions() || dockerfile.starts_with(&path) {
+                append_build_context_dir(archive, context, &path, ignore, dockerfile)?;
+            }
+        } else if !excluded {
+            archive.append_path_with_name(entry.path(), &path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes an (uncompressed) tar archive of a build context directory, excluding the paths matched by its `.dockerignore` file, the `.git` directory and the env files
+fn archive_build_context(
+    context: &Path,
+    dockerfile: &Path,
+    writer: impl std::io::Write,
+) -> Res<()> {
+    let ignore = match std::fs::read_to_string(context.join(".dockerignore")) {
+        Ok(contents) => DockerIgnore::parse(&contents),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => DockerIgnore::default(),
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut archive = tar::Builder::new(writer);
+    archive.follow_symlinks(false);
+
+    append_build_context_dir(&mut archive, context, Path::new(""), &ignore, dockerfile)?;
+    archive.into_inner()?.flush()?;
+
+    Ok(())
+}
+
+/// The options of an image build
+pub struct BuildOptions<'a> {
+    /// Path of the Dockerfile, relative to the build context
+    pub dockerfile: &'a str,
+    /// Build arguments
+    pub args: &'a BTreeMap<String, String>,
+    /// The build stage to build (the last stage by default)
+    pub target: Option<&'a str>,
+}
+
+impl Default for BuildOptions<'_> {
+    fn default() -> Self {
+        static NO_ARGS: BTreeMap<String, String> = BTreeMap::new();
+
+        Self {
+            dockerfile: "Dockerfile",
+            args: &NO_ARGS,
+            target: None,
+        }
+    }
+}
+
+/// Builds an image from a build context directory and tags it
+///
+/// Returns the build output.
+pub async fn build_image(
+    docker: &Docker,
+    context: &Path,
+    tag: &str,
+    build_options: BuildOptions<'_>,
+) -> Res<String> {
+    // The archive is streamed to the daemon while it is written
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+    let context = context.to_owned();
+    let dockerfile: PathBuf = Path::new(build_options.dockerfile)
+        .components()
+        .filter(|component| matches!(component, std::path::Component::Normal(_)))
+        .collect();
+    let archiving = tokio::task::spawn_blocking(move || {
+        let writer = std::io::BufWriter::with_capacity(
+            BUILD_CONTEXT_CHUNK_SIZE,
+            ChannelWriter(sender.clone()),
+        );
+        let result = archive_build_context(&context, &dockerfile, writer);
+
+        // Fails the request body, so that the daemon does not build a truncated context
+        if let Err(error) = &result {
+            let _ = sender.blocking_send(Err(std::io::Error::other(error.to_string())));
+        }
+
+        result
+    });
+    let body = futures_util::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+
+    let mut options = BuildImageOptionsBuilder::new()
+        .dockerfile(build_options.dockerfile)
+        .t(tag)
+        .rm(true);
+    if !build_options.args.is_empty() {
+        options = options.buildargs(&build_options.args.clone().into_iter().collect());
+    }
+    if let Some(target) = build_options.target {
+        options = options.target(target);
+    }
+
+    let build_stream =
+        docker.build_image(options.build(), None, Some(bollard::body_try_stream(body)));
+    let output = read_build_output(build_stream, tag).await;
+
+    match archiving.await? {
+        // The archiving stops when the build fails before reading the whole context
+        Err(error)
+            if error
+                .downcast_ref::<std::io::Error>()
+                .is_none_or(|error| error.kind() != std::io::ErrorKind::BrokenPipe) =>
+        {
+            Err(error.context(format!("Error archiving the build context of image {tag}")))
+        }
+        _ => output,
+    }
+}
+
+/// Collects the output of an image build, failing on its first error
+async fn read_build_output(
+    build_stream: impl Stream<Item = Result<bollard::models::BuildInfo, bollard::errors::Error>>,
+    tag: &str,
+) -> Res<String> {
+    let mut build_stream = std::pin::pin!(build_stream);
+
+    let mut output = String::new();
+    while let Some(info) = build_stream.next().await {
+        let info = info?;
+
+        if let Some(error) = info.error {
+            return Err(anyhow!("Error building image {tag}: {error}"));
+        }
+
+        if let Some(stream) = info.stream {
+            output.push_str(&stream);
+        }
+    }
+
+    Ok(output)
+}
+
+/// Splits an image reference into the repository and the tag or digest (`latest` if there is neither)
+fn split_image_reference(image: &str) -> (&str, &str) {
+    if let Some((repository, digest)) = image.split_once('@') {
+        return (repository, digest);
+    }
+
+    match image.rsplit_once(':') {
+        // A `:` followed by a `/` is a registry port, not a tag
+        Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+        _ => (image, "latest"),
+    }
+}
+
+/// Pulls an image from its registry
+///
+/// Returns the pull output.
+pub async fn pull_image(docker: &Docker, image: &str) -> Res<String> {
+    let (repository, tag) = split_image_reference(image);
+    let options = CreateImageOptionsBuilder::new()
+        .from_image(repository)
+        .tag(tag)
+        .build();
+
+    let mut output = String::new();
+    let mut pull_stream = docker.create_image(Some(options), None, None);
+    while let Some(info) = pull_stream.next().await {
+        let info = info?;
+
+        if let Some(error) = info.error {
+            return Err(anyhow!("Error pulling image {image}: {error}"));
+        }
+
+        // Only the final status lines, not the progress of each layer
+        if let (Some(status), None) = (info.status, info.id) {
+            output.push_str(&format!("{status}\n"));
+        }
+    }
+
+    Ok(output)
+}
+
+/// Stops and removes a container, and creates and starts a new one with the same name and configuration (using the latest image with the same tag)
+pub async fn recreate_container(docker: &Docker, container_id: &str) -> Res<()> {
+    let container = docker
+        .inspect_container(container_id, None::<InspectContainerOptions>)
+        .await?;
+
+    let name = container
+        .name
+        .as_deref()
+        .ok_or(anyhow!("Error: Container {container_id} has no name."))?
+        .trim_start_matches('/')
+        .to_string();
+    let config = container
+        .config
+        .ok_or(anyhow!("Error: Container {name} has no configuration."))?;
+
+    // Only keep the user-defined endpoint settings, the rest are assigned by docker
+    let endpoints_config = container
+        .network_settings
+        .and_then(|settings| settings.networks)
+        .map(|networks| {
+            networks
+                .into_iter()
+                .map(|(network, endpoint)| {
+                    (
+                        network,
+                        EndpointSettings {
+                            aliases: endpoint.aliases,
+                            links: endpoint.links,
+                            ipam_config: endpoint.ipam_config,
+                            driver_opts: endpoint.driver_opts,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect()
+        });
+
+    let create_body = ContainerCreateBody {
+        user: config.user,
+        exposed_ports: config.exposed_ports,
+        tty: config.tty,
+        open_stdin: config.open_stdin,
+        env: config.env,
+        cmd: config.cmd,
+        healthcheck: config.healthcheck,
+        image: config.image,
+        volumes: config.volumes,
+        working_dir: config.working_dir,
+        entrypoint: config.entrypoint,
+        labels: config.labels,
+        stop_signal: config.stop_signal,
+        stop_timeout: config.stop_timeout,
+        host_config: container.host_config,
+        networking_config: Some(NetworkingConfig { endpoints_config }),
+        ..Default::default()
+    };
+
+    let mut stop_options = StopContainerOptionsBuilder::new();
+    if let Some(timeout) = config.stop_timeout {
+        stop_options = stop_options.t(timeout as i32);
+    }
+
+    docker
+        .stop_container(container_id, Some(stop_options.build()))
+        .await?;
+    docker
+        .remove_container(container_id, None::<RemoveContainerOptions>)
+        .await?;
+
+    let new_container = docker
+        .create_container(
+            Some(CreateContainerOptionsBuilder::new().name(&name).build()),
+            create_body,
+        )
+        .await?;
+    docker
+        .start_container(&new_container.id, None::<StartContainerOptions>)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the id of the container matched among a few test containers, or `ambiguous`
+    fn matched_id(container: &str) -> Option<String> {
+        let containers = [
+            ("abcdef123456", "gyft-web-1"),
+            ("abcdef654321", "gyft-db-1"),
+            ("123456abcdef", "abcdef"),
+        ]
+        .into_iter()
+        .map(|(id, name)| ContainerSummary {
+            id: Some(id.into()),
+            names: Some(vec![format!("/{name}")]),
+            ..Default::default()
+        })
+        .collect();
+
+        match match_container(containers, container) {
+            ContainerMatch::Found(id) => Some(id),
+            ContainerMatch::NotFound => None,
+            ContainerMatch::Ambiguous => Some("ambiguous".into()),
+        }
+    }
+
+    #[test]
+    fn build_context_follows_dockerignore_and_excludes_env_files() {
+        let context = tempfile::tempdir().unwrap();
+        for file in [
+            ".git/HEAD",
+            ".env",
+            ".env.bak",
+            "Dockerfile",
+            "README.md",
+            "node_modules/react/index.js",
+            "src/main.rs",
+            "src/.env",
+        ] {
+            let path = context.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+        std::fs::write(
+            context.path().join(".dockerignore"),
+            "node_modules\n*.md\nDockerfile\n",
+        )
+        .unwrap();
+
+        let mut archive = Vec::new();
+        archive_build_context(context.path(), Path::new("Dockerfile"), &mut archive).unwrap();
+
+        let mut paths: Vec<String> = tar::Archive::new(archive.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .path()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        paths.sort();
+
+        assert_eq!(
+            paths,
+            [
+                ".dockerignore",
+                "Dockerfile",
+                "src",
+                "src/.env",
+                "src/main.rs"
+            ]
+        );
+    }
+
+    #[test]
+    fn containers_are_matched_by_exact_name_id_or_unique_prefix() {
+        assert_eq!(matched_id("gyft-db-1").as_deref(), Some("abcdef654321"));
+        assert_eq!(matched_id("abcdef123456").as_deref(), Some("abcdef123456"));
+        // Exact names take precedence over id prefixes
+        assert_eq!(matched_id("abcdef").as_deref(), Some("123456abcdef"));
+        assert_eq!(matched_id("abcdef1").as_deref(), Some("abcdef123456"));
+        assert_eq!(matched_id("abcd").as_deref(), Some("ambiguous"));
+        // Too short prefixes and partial names do not match
+        assert_eq!(matched_id("123"), None);
+        assert_eq!(matched_id("gyft"), None);
+        assert_eq!(matched_id(""), None);
+    }
+}
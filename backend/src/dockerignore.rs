@@ -0,0 +1,168 @@
+//! Parsing and matching of `.dockerignore` files, which exclude paths from the build context of an image
+//!
+//! Follows the rules of `docker build`: each line is a pattern relative to the root of the build context, matched segment by segment with `*`, `?` and `[...]` wildcards, and `**` matching any number of directories. A pattern prefixed by `!` is an exception, re-including what earlier patterns excluded. The last matching pattern decides, and a path is also matched by the patterns matching one of its parent directories.
+
+use std::path::{Component, Path};
+
+/// A pattern of a `.dockerignore` file
+struct Pattern {
+    /// Whether the pattern is an exception (prefixed by `!`)
+    exception: bool,
+    /// The segments of the pattern, separated by `/`
+    segments: Vec<String>,
+}
+
+/// The patterns of a `.dockerignore` file
+#[derive(Default)]
+pub struct DockerIgnore {
+    patterns: Vec<Pattern>,
+}
+
+impl DockerIgnore {
+    /// Parses the contents of a `.dockerignore` file
+    pub fn parse(contents: &str) -> Self {
+        let patterns = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (exception, pattern) = match line.strip_prefix('!') {
+                    Some(pattern) => (true, pattern.trim()),
+                    None => (false, line),
+                };
+
+                let segments: Vec<String> = pattern
+                    .split('/')
+                    .filter(|segment| !segment.is_empty() && *segment != ".")
+                    .map(String::from)
+                    .collect();
+
+                (!segments.is_empty()).then_some(Pattern {
+                    exception,
+                    segments,
+                })
+            })
+            .collect();
+
+        Self { patterns }
+    }
+
+    /// Checks whether there are exceptions, which can re-include paths inside an excluded directory
+    pub fn has_exceptions(&self) -> bool {
+        self.patterns.iter().any(|pattern| pattern.exception)
+    }
+
+    /// Checks whether a path (relative to the root of the build context) is excluded
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let segments: Vec<&str> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(segment) => segment.to_str(),
+                _ => None,
+            })
+            .collect();
+
+        let mut excluded = false;
+        for pattern in &self.patterns {
+            // A pattern matching a parent directory matches everything inside it
+            let matches = (1..=segments.len())
+                .any(|length| match_segments(&pattern.segments, &segments[..length]));
+
+            if matches {
+                excluded = !pattern.exception;
+            }
+        }
+
+        excluded
+    }
+}
+
+/// Matches the segments of a path against the segments of a pattern, `**` matching any number of segments
+fn match_segments(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skipped| match_segments(rest, &path[skipped..]))
+        }
+        Some((first, rest)) => path.split_first().is_some_and(|(segment, path_rest)| {
+            match_glob(first.as_bytes(), segment.as_bytes()) && match_segments(rest, path_rest)
+        }),
+    }
+}
+
+/// Matches a single path segment against a glob with `*`, `?`, `[...]` (negated with `^`) and `\` escapes
+fn match_glob(glob: &[u8], text: &[u8]) -> bool {
+    match glob.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skipped| match_glob(rest, &text[skipped..])),
+        Some((b'?', rest)) => !text.is_empty() && match_glob(rest, &text[1..]),
+        Some((b'[', class)) => {
+            let Some(end) = class.iter().skip(1).position(|byte| *byte == b']') else {
+                // An unterminated class is matched literally
+                return text.first() == Some(&b'[') && match_glob(class, &text[1..]);
+            };
+            let (class, rest) = (&class[..end + 1], &class[end + 2..]);
+
+            text.split_first().is_some_and(|(byte, text_rest)| {
+                match_class(class, *byte) && match_glob(rest, text_rest)
+            })
+        }
+        Some((b'\\', [escaped, rest @ ..])) => {
+            text.first() == Some(escaped) && match_glob(rest, &text[1..])
+        }
+        Some((byte, rest)) => text.first() == Some(byte) && match_glob(rest, &text[1..]),
+    }
+}
+
+/// Checks whether a byte is in a character class (the contents of `[...]`), with `a-z` ranges
+fn match_class(class: &[u8], byte: u8) -> bool {
+    let (negated, mut class) = match class.split_first() {
+        Some((b'^', class)) => (true, class),
+        _ => (false, class),
+    };
+
+    let mut matched = false;
+    while let Some((&start, rest)) = class.split_first() {
+        match rest {
+            [b'-', end, rest @ ..] => {
+                matched |= (start..=*end).contains(&byte);
+                class = rest;
+            }
+            _ => {
+                matched |= start == byte;
+                class = rest;
+            }
+        }
+    }
+
+    matched != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_excluded_like_docker_build_does() {
+        let ignore = DockerIgnore::parse(
+            "# Dependencies\nnode_modules\n/dist/\n*.log\n**/secret?.txt\ndocs/[a-c]*\n\n*.md\n!README.md\n",
+        );
+
+        let excluded = |path: &str| ignore.is_excluded(Path::new(path));
+
+        assert!(excluded("node_modules"));
+        assert!(excluded("node_modules/react/index.js"));
+        assert!(excluded("dist/main.js"));
+        assert!(excluded("server.log"));
+        // `*` does not match across directories
+        assert!(!excluded("logs/server.log"));
+        assert!(excluded("secret1.txt"));
+        assert!(excluded("config/prod/secret2.txt"));
+        assert!(excluded("docs/api.html"));
+        assert!(!excluded("docs/intro.html"));
+        assert!(excluded("CHANGELOG.md"));
+        assert!(!excluded("README.md"));
+        assert!(!excluded("src/main.rs"));
+        assert!(ignore.has_exceptions());
+    }
+}
@@ -8,6 +8,8 @@ use clap::Parser;
 use hmac::{Hmac, Mac, digest::InvalidLength};
 use sha2::Sha256;
 
+use crate::permissions::TeamRole;
+
 #[derive(Parser, Clone)]
 pub struct EnvVars {
     // Auth
@@ -26,11 +28,56 @@ pub struct EnvVars {
     #[arg(env, default_value = "")]
     /// Github organization name
     pub gh_org_name: String,
+    #[arg(env, default_value = "")]
+    /// Roles (`viewer`, `operator` or `admin`) granted to the members of Github organization teams on all the deployments (as a list of `team-slug:role` pairs separated by commas `team1:admin, team2:viewer`)
+    gh_team_roles: String,
+    #[arg(env, default_value = "https://github.com")]
+    /// Base URL of Github, used for OAuth (change it for Github Enterprise)
+    pub gh_base_url: String,
+    #[arg(env, default_value = "https://api.github.com")]
+    /// Base URL of the Github REST API (change it for Github Enterprise, eg: `https://github.example.com/api/v3`)
+    pub gh_api_base_url: String,
+    #[arg(env, default_value = "")]
+    /// Secret of the Github push webhooks (`POST /webhooks/github`), used to verify their signatures. Webhooks are rejected if it is empty.
+    pub gh_webhook_secret: String,
 
     // Config
     #[arg(env, default_value = "/deployments")]
     /// Directory in which all the project deployments are stored
     pub deployments_dir: PathBuf,
+    #[arg(env, default_value = "/data")]
+    /// Directory in which maintos stores its own data (eg: the audit log)
+    pub data_dir: PathBuf,
+    #[arg(env, default_value = "")]
+    /// Github token used to fetch the repositories of the deployments over HTTPS (with read access to the contents of the private ones). Repositories with SSH remotes are fetched with the keys of the SSH agent (`SSH_AUTH_SOCK`).
+    pub gh_fetch_token: String,
+    #[arg(env, default_value = "1800")]
+    /// Maximum duration of an interactive container shell session (in seconds)
+    pub shell_session_timeout: u64,
+    #[arg(env, default_value = "300")]
+    /// Duration for which the Github role lookups (collaborator roles and team memberships) are cached (in seconds)
+    pub gh_cache_ttl: u64,
+    #[arg(env, default_value = "3600")]
+    /// Interval (in seconds) at which a user's organization membership is re-checked, on their next request (the Github response is cached for `GH_CACHE_TTL`). Users no longer in the organization are logged out.
+    pub membership_check_interval: u64,
+    #[arg(env, default_value = "maintos")]
+    /// Issuer (`iss` claim) of the JWTs
+    pub jwt_issuer: String,
+    #[arg(env, default_value = "maintos-dashboard")]
+    /// Audience (`aud` claim) of the JWTs
+    pub jwt_audience: String,
+    #[arg(env, default_value = "900")]
+    /// Lifetime of the JWTs (access tokens), in seconds
+    pub access_token_lifetime: u64,
+    #[arg(env, default_value = "604800")]
+    /// Lifetime of a login session (in seconds) after its refresh token was last used
+    pub refresh_token_lifetime: u64,
+    #[arg(env, default_value = "2592000")]
+    /// Maximum lifetime of a login session (in seconds) since the login, after which the user has to log in again
+    pub session_max_lifetime: u64,
+    #[arg(env, default_value = "60")]
+    /// Tolerated clock skew (in seconds) when validating the time claims of JWTs
+    pub jwt_clock_skew: u64,
 
     // Server
     #[arg(env, default_value = "8080")]
@@ -44,6 +91,15 @@ pub struct EnvVars {
 }
 
 impl EnvVars {
+    /// Returns the roles granted to the members of Github organization teams
+    pub fn get_team_roles(&self) -> Result<Vec<TeamRole>, anyhow::Error> {
+        self.gh_team_roles
+            .split(',')
+            .filter(|team_role| !team_role.trim().is_empty())
+            .map(|team_role| team_role.parse())
+            .collect()
+    }
+
     /// Returns the JWT signing key
     pub fn get_jwt_key(&self) -> Result<Hmac<Sha256>, InvalidLength> {
         Hmac::new_from_slice(self.jwt_secret.as_bytes())
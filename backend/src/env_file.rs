@@ -0,0 +1,389 @@
+//! Utils for reading and editing the `.env` files of deployments
+//!
+//! The files are edited line by line so that comments, blank lines and the order of the keys are preserved.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::utils::Res;
+
+/// Name of the environment file of a deployment
+const ENV_FILE_NAME: &str = ".env";
+/// Name of the environment file template of a deployment
+const ENV_TEMPLATE_FILE_NAME: &str = ".env.template";
+/// Name of the backup of the previous environment file, created before every write
+const ENV_BACKUP_FILE_NAME: &str = ".env.bak";
+
+/// A line of an environment file
+enum EnvLine {
+    /// A `KEY=value` line (optionally prefixed with `export`)
+    Entry { key: String, value: String },
+    /// A comment, blank or unparseable line, kept as-is
+    Other(String),
+}
+
+/// A parsed environment file
+struct EnvFile {
+    lines: Vec<EnvLine>,
+}
+
+/// Checks whether a string is a valid environment variable name
+pub fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+}
+
+/// Quotes a value if it contains characters that are not safe to write unquoted
+fn quote_value(value: &str) -> String {
+    let is_safe = value
+        .chars()
+        .all(|char| char.is_ascii_alphanumeric() || "_-./:@,+".contains(char));
+
+    if is_safe {
+        value.to_string()
+    } else if !value.contains('\'') {
+        format!("'{value}'")
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Unquotes a value written by [`quote_value`] (or by hand). Unquoted values are trimmed of trailing comments.
+fn unquote_value(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        value[1..value.len() - 1].to_string()
+    } else if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut unquoted = String::new();
+        let mut chars = value[1..value.len() - 1].chars();
+
+        while let Some(char) = chars.next() {
+            match (char, chars.clone().next()) {
+                ('\\', Some('n')) => {
+                    unquoted.push('\n');
+                    chars.next();
+                }
+                ('\\', Some(escaped @ ('\\' | '"'))) => {
+                    unquoted.push(escaped);
+                    chars.next();
+                }
+                _ => unquoted.push(char),
+            }
+        }
+
+        unquoted
+    } else {
+        value
+            .split_once(" #")
+            .map_or(value, |(value, _)| value)
+            .trim()
+            .to_string()
+    }
+}
+
+impl EnvFile {
+    /// Parses the contents of an environment file
+    fn parse(content: &str) -> Self {
+        let lines = content
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim_start();
+                let entry = trimmed.strip_prefix("export ").unwrap_or(trimmed);
+
+                match entry.split_once('=') {
+                    Some((key, value)) if !trimmed.starts_with('#') && is_valid_key(key.trim()) => {
+                        EnvLine::Entry {
+                            key: key.trim().to_string(),
+                            value: value.trim().to_string(),
+                        }
+                    }
+                    _ => EnvLine::Other(line.to_string()),
+                }
+            })
+            .collect();
+
+        Self { lines }
+    }
+
+    /// Returns the keys defined in the file (in order, without duplicates)
+    fn keys(&self) -> Vec<&str> {
+        let mut seen = HashSet::new();
+
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                EnvLine::Entry { key, .. } if seen.insert(key.as_str()) => Some(key.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Sets the value of a key. Replaces the first definition of the key if it exists (and removes the others), appends it otherwise.
+    fn set(&mut self, key: &str, value: &str) {
+        let new_value = quote_value(value);
+        let mut replaced = false;
+
+        self.lines.retain_mut(|line| match line {
+            EnvLine::Entry {
+                key: line_key,
+                value,
+            } if line_key == key => {
+                if replaced {
+                    false
+                } else {
+                    *value = new_value.clone();
+                    replaced = true;
+                    true
+                }
+            }
+            _ => true,
+        });
+
+        if !replaced {
+            self.lines.push(EnvLine::Entry {
+                key: key.to_string(),
+                value: new_value,
+            });
+        }
+    }
+
+    /// Removes all the definitions of a key. Returns whether the key was defined.
+    fn unset(&mut self, key: &str) -> bool {
+        let len = self.lines.len();
+        self.lines.retain(
+            |line| !matches!(line, EnvLine::Entry { key: line_key, .. } if line_key == key),
+        );
+
+        self.lines.len() != len
+    }
+
+    /// Checks whether a key has a non-empty value
+    fn has_value(&self, key: &str) -> bool {
+        self.lines.iter().any(|line| {
+            matches!(line, EnvLine::Entry { key: line_key, value } if line_key == key && !value.trim_matches(['"', '\'']).is_empty())
+        })
+    }
+
+    /// Returns the variables defined in the file, with their values unquoted (the last definition of a key wins)
+    fn vars(&self) -> HashMap<String, String> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                EnvLine::Entry { key, value } => Some((key.clone(), unquote_value(value))),
+                EnvLine::Other(_) => None,
+            })
+            .collect()
+    }
+
+    /// Serializes the file back to text
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        for line in &self.lines {
+            match line {
+                EnvLine::Entry { key, value } => text.push_str(&format!("{key}={value}")),
+                EnvLine::Other(line) => text.push_str(line),
+            }
+            text.push('\n');
+        }
+
+        text
+    }
+}
+
+/// Resolves a directory of a deployment containing an environment file (eg: the directory of a compose file). `dir` must be a relative path inside the deployment directory (the deployment directory itself if `None`).
+///
+/// Returns `None` if the path is invalid, does not exist or is outside the deployment directory.
+pub async fn resolve_env_dir(deployment_dir: &Path, dir: Option<&str>) -> Res<Option<PathBuf>> {
+    let dir = Path::new(dir.unwrap_or(""));
+
+    if !dir
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Ok(None);
+    }
+
+    let Ok(resolved) = fs::canonicalize(deployment_dir.join(dir)).await else {
+        return Ok(None);
+    };
+
+    // Symlinks could point outside the deployment
+    if resolved.starts_with(fs::canonicalize(deployment_dir).await?) && resolved.is_dir() {
+        Ok(Some(resolved))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads and parses an environment file. Returns `None` if it does not exist.
+async fn read_env_file(path: &Path) -> Res<Option<EnvFile>> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(Some(EnvFile::parse(&content))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads the variables defined in an environment file (with their values unquoted). Returns `None` if it does not exist.
+pub async fn read_env_vars(path: &Path) -> Res<Option<HashMap<String, String>>> {
+    Ok(read_env_file(path).await?.map(|env_file| env_file.vars()))
+}
+
+/// Atomically writes the environment file in a directory, backing up the previous file (if any) to [`ENV_BACKUP_FILE_NAME`]
+async fn write_env_file(dir: &Path, env_file: &EnvFile) -> Res<()> {
+    let path = dir.join(ENV_FILE_NAME);
+    let temp_path = dir.join(format!("{ENV_FILE_NAME}.tmp"));
+
+    // Created readable only by the owner, the file contains secrets. A leftover temp file from an interrupted write is replaced.
+    if fs::try_exists(&temp_path).await? {
+        fs::remove_file(&temp_path).await?;
+    }
+    let mut temp_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .await?;
+    temp_file.write_all(env_file.to_text().as_bytes()).await?;
+    temp_file.sync_all().await?;
+
+    if fs::try_exists(&path).await? {
+        // Keep the permissions of the previous file, it may contain secrets
+        fs::set_permissions(&temp_path, fs::metadata(&path).await?.permissions()).await?;
+        fs::copy(&path, dir.join(ENV_BACKUP_FILE_NAME)).await?;
+    }
+
+    fs::rename(&temp_path, &path).await?;
+
+    Ok(())
+}
+
+#[derive(Default)]
+/// Locks serializing the edits of the environment files of each deployment, so that concurrent edits are not lost
+pub struct EnvFileLocks {
+    locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl EnvFileLocks {
+    /// Waits for the lock of a deployment's environment files. The files must only be edited while holding the returned guard.
+    pub async fn lock(&self, deployment: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .locks
+            .lock()
+            .await
+            .entry(deployment.to_string())
+            .or_default()
+            .clone();
+
+        lock.lock_owned().await
+    }
+}
+
+/// The value shown in place of the (secret) values of environment variables
+pub const MASKED_VALUE: &str = "********";
+
+#[derive(Serialize)]
+/// A key of an environment file, with its value masked
+pub struct EnvKey {
+    key: String,
+    /// `********` if the key has a value, empty otherwise
+    value: String,
+}
+
+#[derive(Serialize)]
+/// The keys of an environment file, compared with its template
+pub struct EnvSummary {
+    /// Whether the environment file exists
+    exists: bool,
+    keys: Vec<EnvKey>,
+    /// Whether the environment file template exists
+    template_exists: bool,
+    /// Keys in the template that are not in the environment file
+    missing_keys: Vec<String>,
+    /// Keys in the environment file that are not in the template (empty if there is no template)
+    extra_keys: Vec<String>,
+}
+
+/// Returns the (masked) keys of the environment file in a directory and compares them with the environment file template
+pub async fn get_env_summary(dir: &Path) -> Res<EnvSummary> {
+    let env_file = read_env_file(&dir.join(ENV_FILE_NAME)).await?;
+    let template = read_env_file(&dir.join(ENV_TEMPLATE_FILE_NAME)).await?;
+
+    let env_keys = env_file
+        .as_ref()
+        .map(|file| file.keys())
+        .unwrap_or_default();
+    let template_keys = template
+        .as_ref()
+        .map(|file| file.keys())
+        .unwrap_or_default();
+
+    let keys = env_keys
+        .iter()
+        .map(|key| EnvKey {
+            key: key.to_string(),
+            value: if env_file.as_ref().is_some_and(|file| file.has_value(key)) {
+                MASKED_VALUE.into()
+            } else {
+                String::new()
+            },
+        })
+        .collect();
+
+    let missing_keys = template_keys
+        .iter()
+        .filter(|key| !env_keys.contains(key))
+        .map(|key| key.to_string())
+        .collect();
+    let extra_keys = if template.is_some() {
+        env_keys
+            .iter()
+            .filter(|key| !template_keys.contains(key))
+            .map(|key| key.to_string())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    Ok(EnvSummary {
+        exists: env_file.is_some(),
+        keys,
+        template_exists: template.is_some(),
+        missing_keys,
+        extra_keys,
+    })
+}
+
+/// Sets a key in the environment file in a directory, creating the file if it does not exist
+pub async fn set_env_key(dir: &Path, key: &str, value: &str) -> Res<()> {
+    let mut env_file = read_env_file(&dir.join(ENV_FILE_NAME))
+        .await?
+        .unwrap_or(EnvFile { lines: Vec::new() });
+
+    env_file.set(key, value);
+    write_env_file(dir, &env_file).await
+}
+
+/// Unsets a key in the environment file in a directory. Returns whether the key was defined.
+pub async fn unset_env_key(dir: &Path, key: &str) -> Res<bool> {
+    let Some(mut env_file) = read_env_file(&dir.join(ENV_FILE_NAME)).await? else {
+        return Ok(false);
+    };
+
+    if env_file.unset(key) {
+        write_env_file(dir, &env_file).await?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
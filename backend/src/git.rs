@@ -0,0 +1,438 @@
+//! Utils for inspecting and managing the git repositories of deployments
+
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use git2::{
+    AutotagOption, BranchType, Cred, CredentialType, FetchOptions, RemoteCallbacks, Repository,
+    StatusOptions, build::CheckoutBuilder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::Res;
+
+#[derive(Serialize)]
+/// Information about a single commit
+pub struct CommitInfo {
+    /// Full SHA of the commit
+    sha: String,
+    /// Name of the commit author
+    author: String,
+    /// Full commit message
+    message: String,
+    /// Commit time (UNIX timestamp in seconds)
+    timestamp: i64,
+}
+
+#[derive(Serialize)]
+/// The live state of a deployment's git repository
+pub struct GitState {
+    /// Name of the checked-out branch (`None` if the HEAD is detached)
+    branch: Option<String>,
+    /// The commit currently checked out
+    head: CommitInfo,
+    /// Name of the upstream tracking branch (eg: `origin/main`), if any
+    upstream: Option<String>,
+    /// Number of local commits not on the upstream branch
+    ahead: Option<usize>,
+    /// Number of upstream commits not on the local branch
+    behind: Option<usize>,
+    /// Whether the working tree has uncommitted changes to tracked files
+    dirty: bool,
+    /// Whether the deployment is redeployed automatically on push (see [`is_auto_deploy_enabled`])
+    auto_deploy: bool,
+}
+
+/// Git config key with which a deployment opts in to automatic redeploys on push (`git config maintos.autoDeploy true` in the deployment's repository)
+const AUTO_DEPLOY_CONFIG_KEY: &str = "maintos.autoDeploy";
+
+/// Returns the commit info of a commit
+fn get_commit_info(commit: &git2::Commit) -> CommitInfo {
+    CommitInfo {
+        sha: commit.id().to_string(),
+        author: commit.author().name().unwrap_or_default().to_string(),
+        message: commit.message().unwrap_or_default().trim().to_string(),
+        timestamp: commit.time().seconds(),
+    }
+}
+
+/// Returns the working directory of a (non-bare) repository
+pub fn get_workdir(repo: &Repository) -> Res<PathBuf> {
+    Ok(repo
+        .workdir()
+        .ok_or(anyhow!("Error: Repository is bare."))?
+        .to_owned())
+}
+
+/// Returns whether the working tree has uncommitted changes to tracked files (untracked and ignored files are not considered)
+pub fn is_dirty(repo: &Repository) -> Res<bool> {
+    let mut status_opts = StatusOptions::new();
+    status_opts.include_untracked(false).include_ignored(false);
+
+    Ok(!repo.statuses(Some(&mut status_opts))?.is_empty())
+}
+
+/// Returns whether a deployment opted in to automatic redeploys on push (the `maintos.autoDeploy` git config of its repository)
+pub fn is_auto_deploy_enabled(repo: &Repository) -> Res<bool> {
+    match repo.config()?.get_bool(AUTO_DEPLOY_CONFIG_KEY) {
+        Ok(enabled) => Ok(enabled),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Returns the name of the checked-out branch (`None` if the HEAD is detached)
+pub fn get_branch(repo: &Repository) -> Res<Option<String>> {
+    let head = repo.head()?;
+
+    Ok(if head.is_branch() {
+        head.shorthand().map(|name| name.to_string())
+    } else {
+        None
+    })
+}
+
+/// Reads the current branch, HEAD commit, upstream tracking status and working tree status of a repository
+pub fn get_git_state(repo: &Repository) -> Res<GitState> {
+    let head_commit = repo.head()?.peel_to_commit()?;
+    let branch = get_branch(repo)?;
+
+    let (mut upstream, mut ahead, mut behind) = (None, None, None);
+    if let Some(branch_name) = &branch
+        && let Ok(upstream_branch) = repo
+            .find_branch(branch_name, BranchType::Local)
+            .and_then(|local| local.upstream())
+    {
+        upstream = upstream_branch.name()?.map(|name| name.to_string());
+
+        if let Some(upstream_oid) = upstream_branch.get().target() {
+            let (commits_ahead, commits_behind) =
+                repo.graph_ahead_behind(head_commit.id(), upstream_oid)?;
+
+            ahead = Some(commits_ahead);
+            behind = Some(commits_behind);
+        }
+    }
+
+    Ok(GitState {
+        branch,
+        head: get_commit_info(&head_commit),
+        upstream,
+        ahead,
+        behind,
+        dirty: is_dirty(repo)?,
+        auto_deploy: is_auto_deploy_enabled(repo)?,
+    })
+}
+
+/// Returns the callbacks providing the credentials of a fetch: the keys of the SSH agent for SSH remotes, the Github token (if not empty) for HTTPS remotes
+fn credential_callbacks(token: &str) -> RemoteCallbacks<'_> {
+    let mut attempted = false;
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |_url, username, allowed| {
+        // libgit2 asks again when the credentials are rejected
+        if std::mem::replace(&mut attempted, true) {
+            return Err(git2::Error::from_str(
+                "Error: The remote rejected the credentials.",
+            ));
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !token.is_empty() {
+            Cred::userpass_plaintext("x-access-token", token)
+        } else {
+            Err(git2::Error::from_str(
+                "Error: The remote requires credentials, but none are configured.",
+            ))
+        }
+    });
+
+    callbacks
+}
+
+/// Fetches all branches and tags from the `origin` remote, authenticating with a Github token (HTTPS remotes, anonymous if empty) or the SSH agent (SSH remotes)
+pub fn fetch_origin(repo: &Repository, token: &str) -> Res<()> {
+    let mut remote = repo.find_remote("origin")?;
+    let mut fetch_opts = FetchOptions::new();
+    fetch_opts
+        .download_tags(AutotagOption::All)
+        .remote_callbacks(credential_callbacks(token));
+    remote.fetch(&[] as &[&str], Some(&mut fetch_opts), None)?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+/// A branch or tag
+pub struct RefInfo {
+    /// Name of the branch (without the `origin/` prefix) or tag
+    name: String,
+    /// The commit the ref points to
+    commit: CommitInfo,
+}
+
+#[derive(Serialize)]
+/// The branches and tags of the `origin` remote
+pub struct RemoteRefs {
+    branches: Vec<RefInfo>,
+    tags: Vec<RefInfo>,
+}
+
+/// Returns the branches and tags of the `origin` remote, as of the last fetch (sorted by name)
+pub fn get_remote_refs(repo: &Repository) -> Res<RemoteRefs> {
+    let mut branches = Vec::new();
+    for branch in repo.branches(Some(BranchType::Remote))? {
+        let (branch, _) = branch?;
+
+        if let Some(name) = branch.name()?.and_then(|name| name.strip_prefix("origin/"))
+            && name != "HEAD"
+        {
+            branches.push(RefInfo {
+                name: name.to_string(),
+                commit: get_commit_info(&branch.get().peel_to_commit()?),
+            });
+        }
+    }
+
+    let mut tags = Vec::new();
+    for name in repo.tag_names(None)?.iter().flatten() {
+        // Tags of other objects (eg: a tree or a blob) cannot be checked out
+        let Ok(commit) = repo
+            .find_reference(&format!("refs/tags/{name}"))
+            .and_then(|tag| tag.peel_to_commit())
+        else {
+            continue;
+        };
+
+        tags.push(RefInfo {
+            name: name.to_string(),
+            commit: get_commit_info(&commit),
+        });
+    }
+
+    branches.sort_by(|a, b| a.name.cmp(&b.name));
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(RemoteRefs { branches, tags })
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+/// The type of a ref to switch to
+pub enum RefKind {
+    /// A branch of the `origin` remote, checked out as a local branch tracking it
+    Branch,
+    /// A tag, checked out as a detached HEAD
+    Tag,
+}
+
+/// Switches the checked-out ref to a branch of the `origin` remote or a tag (as of the last fetch). Returns the full SHA of the new HEAD.
+///
+/// A remote branch is checked out as a local branch tracking it: an existing local branch is fast-forwarded to the remote branch, a missing one is created. Refuses (returns an error) if the working tree is dirty, the ref does not exist or the local branch has diverged from the remote branch.
+pub fn switch_ref(repo: &Repository, kind: RefKind, name: &str) -> Res<String> {
+    if is_dirty(repo)? {
+        return Err(anyhow!(
+            "Refusing to switch: The working tree has uncommitted changes."
+        ));
+    }
+
+    let commit = match kind {
+        RefKind::Branch => {
+            let remote_branch = repo
+                .find_branch(&format!("origin/{name}"), BranchType::Remote)
+                .map_err(|_| anyhow!("Error: Branch {name} not found on origin."))?;
+            let remote_commit = remote_branch.get().peel_to_commit()?;
+
+            let mut local_branch = match repo.find_branch(name, BranchType::Local) {
+                Ok(mut local_branch) => {
+                    let local_oid = local_branch.get().peel_to_commit()?.id();
+                    let (ahead, _) = repo.graph_ahead_behind(local_oid, remote_commit.id())?;
+
+                    if ahead > 0 {
+                        return Err(anyhow!(
+                            "Refusing to switch: Local branch {name} has diverged from origin/{name}."
+                        ));
+                    }
+
+                    local_branch.get_mut().set_target(
+                        remote_commit.id(),
+                        &format!("maintos: Fast-forward to {}", remote_commit.id()),
+                    )?;
+                    local_branch
+                }
+                Err(_) => repo.branch(name, &remote_commit, false)?,
+            };
+            local_branch.set_upstream(Some(&format!("origin/{name}")))?;
+
+            let branch_ref = local_branch
+                .get()
+                .name()
+                .ok_or(anyhow!("Error: Reference name is not valid UTF-8."))?
+                .to_string();
+            repo.checkout_tree(
+                remote_commit.as_object(),
+                Some(CheckoutBuilder::new().force()),
+            )?;
+            repo.set_head(&branch_ref)?;
+
+            remote_commit
+        }
+        RefKind::Tag => {
+            let commit = repo
+                .find_reference(&format!("refs/tags/{name}"))
+                .and_then(|tag| tag.peel_to_commit())
+                .map_err(|_| anyhow!("Error: Tag {name} not found."))?;
+
+            repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().force()))?;
+            repo.set_head_detached(commit.id())?;
+
+            commit
+        }
+    };
+
+    Ok(commit.id().to_string())
+}
+
+/// The result of a fast-forward
+pub enum FastForward {
+    /// The branch was already up to date with its upstream
+    UpToDate,
+    /// The branch was fast-forwarded from the first commit to the second
+    Updated(String, String),
+}
+
+/// Fast-forwards the checked-out branch to its upstream tracking branch and checks out the new HEAD
+///
+/// Refuses (returns an error) if the working tree is dirty, the HEAD is detached, there is no upstream or the branch has diverged from its upstream.
+pub fn fast_forward(repo: &Repository) -> Res<FastForward> {
+    if is_dirty(repo)? {
+        return Err(anyhow!(
+            "Refusing to fast-forward: The working tree has uncommitted changes."
+        ));
+    }
+
+    let head = repo.head()?;
+    if !head.is_branch() {
+        return Err(anyhow!("Refusing to fast-forward: The HEAD is detached."));
+    }
+
+    let branch_name = head
+        .shorthand()
+        .ok_or(anyhow!("Error: Branch name is not valid UTF-8."))?;
+    let upstream = repo
+        .find_branch(branch_name, BranchType::Local)?
+        .upstream()
+        .map_err(|_| {
+            anyhow!("Refusing to fast-forward: Branch {branch_name} has no upstream branch.")
+        })?;
+
+    let upstream_commit = repo.reference_to_annotated_commit(upstream.get())?;
+    let (analysis, _) = repo.merge_analysis(&[&upstream_commit])?;
+
+    if analysis.is_up_to_date() {
+        Ok(FastForward::UpToDate)
+    } else if analysis.is_fast_forward() {
+        let old_sha = head.peel_to_commit()?.id().to_string();
+        let new_sha = upstream_commit.id().to_string();
+
+        let mut branch_ref = repo.find_reference(
+            head.name()
+                .ok_or(anyhow!("Error: Reference name is not valid UTF-8."))?,
+        )?;
+        branch_ref.set_target(
+            upstream_commit.id(),
+            &format!("maintos: Fast-forward to {new_sha}"),
+        )?;
+        repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+
+        Ok(FastForward::Updated(old_sha, new_sha))
+    } else {
+        Err(anyhow!(
+            "Refusing to fast-forward: Branch {branch_name} has diverged from its upstream."
+        ))
+    }
+}
+
+/// Returns the full SHA of the HEAD commit
+pub fn get_head_sha(repo: &Repository) -> Res<String> {
+    Ok(repo.head()?.peel_to_commit()?.id().to_string())
+}
+
+/// Resets the checked-out branch (and the working tree) to a commit in its history, eg: to roll back a deployment. Returns the full SHA of the commit. A later fast-forward brings the branch back to its upstream.
+///
+/// Refuses (returns an error) if the working tree is dirty, the HEAD is detached, or the commit is not in the history of the branch or of its upstream branch.
+pub fn reset_to_commit(repo: &Repository, sha: &str) -> Res<String> {
+    if sha.len() < 4 || !sha.chars().all(|char| char.is_ascii_hexdigit()) {
+        return Err(anyhow!("Error: {sha} is not a commit SHA."));
+    }
+
+    if is_dirty(repo)? {
+        return Err(anyhow!(
+            "Refusing to roll back: The working tree has uncommitted changes."
+        ));
+    }
+
+    let head = repo.head()?;
+    if !head.is_branch() {
+        return Err(anyhow!("Refusing to roll back: The HEAD is detached."));
+    }
+
+    let commit = repo
+        .revparse_single(sha)
+        .and_then(|object| object.peel_to_commit())
+        .map_err(|_| anyhow!("Error: Commit {sha} not found."))?;
+
+    let head_oid = head.peel_to_commit()?.id();
+    let upstream_oid = head
+        .shorthand()
+        .and_then(|branch_name| repo.find_branch(branch_name, BranchType::Local).ok())
+        .and_then(|branch| branch.upstream().ok())
+        .and_then(|upstream| upstream.get().target());
+
+    let in_history = |tip: git2::Oid| {
+        tip == commit.id() || repo.graph_descendant_of(tip, commit.id()).unwrap_or(false)
+    };
+    if !in_history(head_oid) && !upstream_oid.is_some_and(in_history) {
+        return Err(anyhow!(
+            "Refusing to roll back: Commit {sha} is not in the history of the checked-out branch."
+        ));
+    }
+
+    repo.reset(
+        commit.as_object(),
+        git2::ResetType::Hard,
+        Some(CheckoutBuilder::new().force()),
+    )?;
+
+    Ok(commit.id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_of_other_objects_than_commits_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+
+        let signature = git2::Signature::now("alice", "alice@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let commit = repo
+            .commit(Some("HEAD"), &signature, &signature, "Initial", &tree, &[])
+            .unwrap();
+
+        repo.tag_lightweight("v1", &repo.find_object(commit, None).unwrap(), false)
+            .unwrap();
+        repo.tag_lightweight("tree", tree.as_object(), false)
+            .unwrap();
+
+        let refs = get_remote_refs(&repo).unwrap();
+        let tags: Vec<&str> = refs.tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(tags, ["v1"]);
+    }
+}
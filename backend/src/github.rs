@@ -1,44 +1,154 @@
+//! Github API client
+//!
+//! All the Github API requests go through the [`GithubApi`] trait, implemented by [`GithubClient`] for the real (or enterprise) Github API and by [`fake::FakeGithub`] in tests.
+
+use std::time::Duration;
+
 use anyhow::{Ok, anyhow};
+use async_trait::async_trait;
 use http::StatusCode;
 use reqwest::Client;
 use serde::Deserialize;
 
-use crate::utils::Res;
+use crate::{env::EnvVars, utils::Res};
 
-#[derive(Deserialize)]
-struct GithubAccessTokenResponse {
-    access_token: String,
+#[async_trait]
+/// The Github API operations used by maintos
+pub trait GithubApi: Send + Sync {
+    /// Fetches the access token generated from a Github OAuth request
+    async fn get_access_token(&self, code: &str) -> Res<String>;
+
+    /// Fetches the username of the user an access token belongs to
+    async fn get_username(&self, access_token: &str) -> Res<String>;
+
+    /// Checks whether a user is a member of an organization
+    async fn check_membership(&self, org: &str, username: &str) -> Res<bool>;
+
+    /// Checks whether a user is an active member of an organization team
+    async fn check_team_membership(&self, org: &str, team: &str, username: &str) -> Res<bool>;
+
+    /// Fetches a user's collaborator role name on a repository (eg: `write`, `admin`). Returns `None` if the user is not a collaborator.
+    async fn get_collaborator_role(
+        &self,
+        owner: &str,
+        repo: &str,
+        username: &str,
+    ) -> Res<Option<String>>;
+}
+
+/// [`GithubApi`] implementation making requests to the Github API
+pub struct GithubClient {
+    client: Client,
+    /// Base URL of Github (for OAuth)
+    base_url: String,
+    /// Base URL of the Github REST API
+    api_base_url: String,
+    client_id: String,
+    client_secret: String,
+    /// An org admin's Github token, used for the organization and repository requests
+    admin_token: String,
+}
+
+impl GithubClient {
+    /// Creates a client configured by the environment variables
+    pub fn new(env_vars: &EnvVars) -> Self {
+        Self {
+            client: Client::new(),
+            base_url: env_vars.gh_base_url.trim_end_matches('/').to_string(),
+            api_base_url: env_vars.gh_api_base_url.trim_end_matches('/').to_string(),
+            client_id: env_vars.gh_client_id.clone(),
+            client_secret: env_vars.gh_client_secret.clone(),
+            admin_token: env_vars.gh_org_admin_token.clone(),
+        }
+    }
+
+    /// Runs a Github API request authenticated with the admin access token
+    ///
+    /// Rate limited requests are retried after waiting for the time specified in the `Retry-After` or `X-RateLimit-Reset` headers (or with an exponential backoff), up to [`MAX_RATE_LIMIT_ATTEMPTS`] times.
+    async fn admin_request(&self, path: String) -> Res<reqwest::Response> {
+        let mut attempt = 1;
+
+        loop {
+            let response = self
+                .client
+                .get(format!("{}/{path}", self.api_base_url))
+                .header("Authorization", format!("Bearer {}", self.admin_token))
+                .header("User-Agent", "bruh why is this required")
+                .send()
+                .await?;
+
+            if let Some(remaining) = response
+                .headers()
+                .get("x-ratelimit-remaining")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u32>().ok())
+                && remaining < 100
+            {
+                tracing::warn!(
+                    "Github API rate limit almost exhausted: {remaining} requests remaining."
+                );
+            }
+
+            match get_rate_limit_wait(&response, attempt) {
+                Some(wait) if attempt < MAX_RATE_LIMIT_ATTEMPTS && wait <= MAX_RATE_LIMIT_WAIT => {
+                    tracing::warn!(
+                        "Github API rate limit exceeded, retrying in {} seconds.",
+                        wait.as_secs()
+                    );
+
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                }
+                _ => return Ok(response),
+            }
+        }
+    }
 }
-/// Fetches the access token generated from a Github OAuth request
-pub async fn get_access_token(
-    client: &Client,
-    client_id: &str,
-    client_secret: &str,
-    code: &str,
-) -> Res<String> {
-    // get the access token for authenticating other endpoints
-    let response = client
-        .get(format!(
-            "https://github.com/login/oauth/access_token?client_id={}&client_secret={}&code={}",
-            client_id, client_secret, code
-        ))
-        .header("accept", "application/json")
-        .send()
-        .await?;
 
-    if response.status() != StatusCode::OK {
-        tracing::error!(
-            "Github OAuth error getting access token: {}",
-            response.text().await?
-        );
+/// Maximum number of attempts of a rate limited Github API request
+const MAX_RATE_LIMIT_ATTEMPTS: u32 = 3;
+/// Maximum time to wait for a rate limit to reset before giving up
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
 
-        return Err(anyhow!("Github API response error."));
+/// Returns how long to wait before retrying a request if the response is a rate limit error, `None` otherwise
+///
+/// See https://docs.github.com/en/rest/using-the-rest-api/rate-limits-for-the-rest-api?apiVersion=2022-11-28#exceeding-the-rate-limit
+fn get_rate_limit_wait(response: &reqwest::Response, attempt: u32) -> Option<Duration> {
+    let status = response.status();
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
     }
 
-    let access_token =
-        serde_json::from_slice::<GithubAccessTokenResponse>(&response.bytes().await?)?.access_token;
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<i64>().ok())
+    };
 
-    Ok(access_token)
+    if let Some(retry_after) = header("retry-after") {
+        // Secondary rate limit
+        Some(Duration::from_secs(retry_after.max(0).unsigned_abs()))
+    } else if header("x-ratelimit-remaining") == Some(0) {
+        // Primary rate limit, wait until the reset time
+        let reset = header("x-ratelimit-reset").unwrap_or_default();
+        Some(Duration::from_secs(
+            (reset - chrono::Utc::now().timestamp())
+                .max(1)
+                .unsigned_abs(),
+        ))
+    } else if status == StatusCode::TOO_MANY_REQUESTS {
+        // Exponential backoff if Github does not say how long to wait
+        Some(Duration::from_secs(2u64.pow(attempt)))
+    } else {
+        None
+    }
+}
+
+#[derive(Deserialize)]
+struct GithubAccessTokenResponse {
+    access_token: String,
 }
 
 #[derive(Deserialize)]
@@ -46,105 +156,260 @@ struct GithubUserResponse {
     login: String,
 }
 
-pub async fn get_username(client: &Client, access_token: &str) -> Res<String> {
-    let response = client
-        .get("https://api.github.com/user")
-        .header("Authorization", format!("Bearer {access_token}"))
-        .header("User-Agent", "bruh") // Why is this required :ded:
-        .send()
-        .await?;
-
-    if response.status() != StatusCode::OK {
-        tracing::error!(
-            "Github OAuth error getting username: {}",
-            response.text().await?
-        );
-
-        return Err(anyhow!("Github API response error."));
-    }
-
-    let username = serde_json::from_slice::<GithubUserResponse>(&response.bytes().await?)?.login;
-    Ok(username)
+#[derive(Deserialize)]
+struct GithubTeamMembershipResponse {
+    state: String,
 }
 
-/// Runs a Github API request authenticated with the admin access token
-pub async fn admin_gh_request(
-    client: &Client,
-    admin_token: &str,
-    path: String,
-) -> Result<reqwest::Response, reqwest::Error> {
-    client
-        .get(format!("https://api.github.com/{path}",))
-        .header("Authorization", format!("Bearer {}", admin_token))
-        .header("User-Agent", "bruh why is this required")
-        .send()
-        .await
+#[derive(Deserialize)]
+struct GithubCollabResponse {
+    role_name: String,
 }
 
-pub async fn check_membership(
-    client: &Client,
-    admin_token: &str,
-    org: &str,
-    username: &str,
-) -> Res<bool> {
-    let response = admin_gh_request(
-        client,
-        admin_token,
-        format!("orgs/{}/members/{}", org, username),
-    )
-    .await?;
-
-    // See API: https://docs.github.com/en/rest/orgs/members?apiVersion=2022-11-28#check-organization-membership-for-a-user
-    match response.status().as_u16() {
-        302 => Err(anyhow!(
-            "Error: Github API token is from a non-organization member."
-        )),
-        404 => Ok(false),
-        204 => Ok(true),
-        code => {
+#[async_trait]
+impl GithubApi for GithubClient {
+    async fn get_access_token(&self, code: &str) -> Res<String> {
+        // get the access token for authenticating other endpoints
+        let response = self
+            .client
+            .get(format!("{}/login/oauth/access_token", self.base_url))
+            .query(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("code", code),
+            ])
+            .header("accept", "application/json")
+            .send()
+            .await?;
+
+        if response.status() != StatusCode::OK {
             tracing::error!(
-                "Error getting org membership data ({code}): {}",
+                "Github OAuth error getting access token: {}",
                 response.text().await?
             );
-            Err(anyhow!("Github API response error."))
+
+            return Err(anyhow!("Github API response error."));
         }
+
+        let access_token =
+            serde_json::from_slice::<GithubAccessTokenResponse>(&response.bytes().await?)?
+                .access_token;
+
+        Ok(access_token)
     }
-}
 
-#[derive(Deserialize)]
-struct GithubCollabResponse {
-    role_name: String,
-}
+    async fn get_username(&self, access_token: &str) -> Res<String> {
+        let response = self
+            .client
+            .get(format!("{}/user", self.api_base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .header("User-Agent", "bruh") // Why is this required :ded:
+            .send()
+            .await?;
 
-// See https://docs.github.com/en/rest/collaborators/collaborators?apiVersion=2022-11-28#get-repository-permissions-for-a-user
-pub async fn get_collaborator_role(
-    client: &Client,
-    admin_token: &str,
-    org: &str,
-    repo: &str,
-    username: &str,
-) -> Res<Option<String>> {
-    let response = admin_gh_request(
-        client,
-        admin_token,
-        format!("repos/{org}/{repo}/collaborators/{username}/permission"),
-    )
-    .await?;
-
-    match response.status() {
-        StatusCode::OK => {
-            let collab_role =
-                serde_json::from_slice::<GithubCollabResponse>(&response.bytes().await?)?.role_name;
-
-            Ok(collab_role.into())
-        }
-        StatusCode::NOT_FOUND => Ok(None),
-        _ => {
+        if response.status() != StatusCode::OK {
             tracing::error!(
-                "Error fetching {username}'s collaborator role on {org}/{repo}: {}",
+                "Github OAuth error getting username: {}",
                 response.text().await?
             );
-            Err(anyhow!("Error fetching {username}'s collaborator role."))
+
+            return Err(anyhow!("Github API response error."));
+        }
+
+        let username =
+            serde_json::from_slice::<GithubUserResponse>(&response.bytes().await?)?.login;
+        Ok(username)
+    }
+
+    async fn check_membership(&self, org: &str, username: &str) -> Res<bool> {
+        let response = self
+            .admin_request(format!("orgs/{}/members/{}", org, username))
+            .await?;
+
+        // See API: https://docs.github.com/en/rest/orgs/members?apiVersion=2022-11-28#check-organization-membership-for-a-user
+        match response.status().as_u16() {
+            302 => Err(anyhow!(
+                "Error: Github API token is from a non-organization member."
+            )),
+            404 => Ok(false),
+            204 => Ok(true),
+            code => {
+                tracing::error!(
+                    "Error getting org membership data ({code}): {}",
+                    response.text().await?
+                );
+                Err(anyhow!("Github API response error."))
+            }
+        }
+    }
+
+    async fn check_team_membership(&self, org: &str, team: &str, username: &str) -> Res<bool> {
+        // See https://docs.github.com/en/rest/teams/members?apiVersion=2022-11-28#get-team-membership-for-a-user
+        let response = self
+            .admin_request(format!("orgs/{org}/teams/{team}/memberships/{username}"))
+            .await?;
+
+        match response.status() {
+            // Pending memberships (invitations not yet accepted) do not count
+            StatusCode::OK => Ok(serde_json::from_slice::<GithubTeamMembershipResponse>(
+                &response.bytes().await?,
+            )?
+            .state
+                == "active"),
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => {
+                tracing::error!(
+                    "Error fetching {username}'s membership of team {org}/{team}: {}",
+                    response.text().await?
+                );
+                Err(anyhow!("Error fetching {username}'s team membership."))
+            }
+        }
+    }
+
+    async fn get_collaborator_role(
+        &self,
+        owner: &str,
+        repo: &str,
+        username: &str,
+    ) -> Res<Option<String>> {
+        // See https://docs.github.com/en/rest/collaborators/collaborators?apiVersion=2022-11-28#get-repository-permissions-for-a-user
+        let response = self
+            .admin_request(format!(
+                "repos/{owner}/{repo}/collaborators/{username}/permission"
+            ))
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => {
+                let collab_role =
+                    serde_json::from_slice::<GithubCollabResponse>(&response.bytes().await?)?
+                        .role_name;
+
+                Ok(collab_role.into())
+            }
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => {
+                tracing::error!(
+                    "Error fetching {username}'s collaborator role on {owner}/{repo}: {}",
+                    response.text().await?
+                );
+                Err(anyhow!("Error fetching {username}'s collaborator role."))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    //! An in-memory [`GithubApi`] implementation for tests
+
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+
+    use super::GithubApi;
+    use crate::utils::Res;
+
+    #[derive(Default)]
+    /// A fake Github with users, organization and team members, and repository collaborators. The access token of a user is `token-{username}`.
+    pub struct FakeGithub {
+        /// OAuth codes and the usernames they authenticate
+        codes: HashMap<String, String>,
+        /// (org, username) pairs
+        org_members: HashSet<(String, String)>,
+        /// (org, team slug, username) triplets
+        team_members: HashSet<(String, String, String)>,
+        /// Collaborator role names, keyed by (owner, repo, username)
+        collaborators: HashMap<(String, String, String), String>,
+        /// Number of organization and repository requests made
+        requests: AtomicUsize,
+    }
+
+    impl FakeGithub {
+        /// Adds a user who can log in with the given OAuth code
+        pub fn with_user(mut self, code: &str, username: &str) -> Self {
+            self.codes.insert(code.into(), username.into());
+            self
+        }
+
+        pub fn with_org_member(mut self, org: &str, username: &str) -> Self {
+            self.org_members.insert((org.into(), username.into()));
+            self
+        }
+
+        pub fn with_team_member(mut self, org: &str, team: &str, username: &str) -> Self {
+            self.team_members
+                .insert((org.into(), team.into(), username.into()));
+            self
+        }
+
+        pub fn with_collaborator(
+            mut self,
+            owner: &str,
+            repo: &str,
+            username: &str,
+            role_name: &str,
+        ) -> Self {
+            self.collaborators.insert(
+                (owner.into(), repo.into(), username.into()),
+                role_name.into(),
+            );
+            self
+        }
+
+        /// Returns the number of organization and repository requests made so far
+        pub fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+
+        fn count_request(&self) {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[async_trait]
+    impl GithubApi for FakeGithub {
+        async fn get_access_token(&self, code: &str) -> Res<String> {
+            self.codes
+                .get(code)
+                .map(|username| format!("token-{username}"))
+                .ok_or(anyhow!("Github API response error."))
+        }
+
+        async fn get_username(&self, access_token: &str) -> Res<String> {
+            access_token
+                .strip_prefix("token-")
+                .filter(|username| self.codes.values().any(|user| user == username))
+                .map(str::to_string)
+                .ok_or(anyhow!("Github API response error."))
+        }
+
+        async fn check_membership(&self, org: &str, username: &str) -> Res<bool> {
+            self.count_request();
+            Ok(self.org_members.contains(&(org.into(), username.into())))
+        }
+
+        async fn check_team_membership(&self, org: &str, team: &str, username: &str) -> Res<bool> {
+            self.count_request();
+            Ok(self
+                .team_members
+                .contains(&(org.into(), team.into(), username.into())))
+        }
+
+        async fn get_collaborator_role(
+            &self,
+            owner: &str,
+            repo: &str,
+            username: &str,
+        ) -> Res<Option<String>> {
+            self.count_request();
+            Ok(self
+                .collaborators
+                .get(&(owner.into(), repo.into(), username.into()))
+                .cloned())
         }
     }
 }
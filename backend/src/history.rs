@@ -0,0 +1,105 @@
+//! History of the redeploys and rollbacks of each deployment
+//!
+//! The HEAD commit and image ids of a deployment are recorded before each deploy, so that a broken release can be rolled back (see [`crate::deploy::DeployKind::Rollback`]). The history is stored as a JSON file.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::utils::{self, Res};
+
+/// Maximum number of records kept per deployment, older records are pruned
+const MAX_HISTORY_LEN: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone)]
+/// A redeploy or rollback of a deployment
+pub struct DeployRecord {
+    /// Id of the job that ran the deploy
+    pub job_id: String,
+    /// The operation (`redeploy` or `rollback`)
+    pub kind: String,
+    /// Username of the user who started the deploy
+    pub username: String,
+    /// Start time (UNIX timestamp in seconds)
+    pub timestamp: i64,
+    /// SHA of the HEAD commit before the deploy
+    pub previous_sha: String,
+    /// Ids of the images used by the deployment's containers before the deploy, by image name
+    pub previous_images: HashMap<String, String>,
+    /// SHA of the HEAD commit after the deploy (`None` until it finishes)
+    pub sha: Option<String>,
+    /// Whether the deploy succeeded (`None` until it finishes)
+    pub success: Option<bool>,
+}
+
+/// The deploy history store
+pub struct DeployHistory {
+    path: PathBuf,
+    /// Records by deployment name, oldest first
+    records: Mutex<HashMap<String, Vec<DeployRecord>>>,
+}
+
+impl DeployHistory {
+    /// Loads the history stored in the given (JSON) file. The file is created on the first deploy.
+    pub fn load(path: PathBuf) -> Res<Self> {
+        let records = utils::read_json_store(&path)?;
+
+        Ok(Self {
+            path,
+            records: Mutex::new(records),
+        })
+    }
+
+    /// Records the start of a deploy
+    pub async fn record(&self, deployment: &str, record: DeployRecord) -> Res<()> {
+        let mut records = self.records.lock().await;
+        records
+            .entry(deployment.to_string())
+            .or_default()
+            .push(record);
+
+        self.save(&mut records).await
+    }
+
+    /// Records the outcome of a deploy
+    pub async fn finish(
+        &self,
+        deployment: &str,
+        job_id: &str,
+        sha: Option<String>,
+        success: bool,
+    ) -> Res<()> {
+        let mut records = self.records.lock().await;
+        if let Some(record) = records
+            .get_mut(deployment)
+            .and_then(|records| records.iter_mut().find(|record| record.job_id == job_id))
+        {
+            record.sha = sha;
+            record.success = Some(success);
+        }
+
+        self.save(&mut records).await
+    }
+
+    /// Returns the history of a deployment, newest first
+    pub async fn list(&self, deployment: &str) -> Vec<DeployRecord> {
+        self.records
+            .lock()
+            .await
+            .get(deployment)
+            .map(|records| records.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Prunes the oldest records and writes the history to the file
+    async fn save(&self, records: &mut HashMap<String, Vec<DeployRecord>>) -> Res<()> {
+        for deployment_records in records.values_mut() {
+            let excess = deployment_records.len().saturating_sub(MAX_HISTORY_LEN);
+            deployment_records.drain(..excess);
+        }
+
+        utils::write_json_atomic(&self.path, records).await
+    }
+}
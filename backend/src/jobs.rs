@@ -0,0 +1,527 @@
+//! Background jobs, for long-running operations (eg: redeploys)
+//!
+//! Each job runs on a tokio task and its status is persisted in a JSON file, and its output in an append-only file per job, so that they can be fetched (or streamed) after the request that started the job. Jobs of the same deployment run one at a time, so that eg: two redeploys never overlap.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures_util::{Stream, stream};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, broadcast};
+
+use crate::utils::{self, Res};
+
+/// Duration for which finished jobs are kept (in seconds)
+const JOB_RETENTION: i64 = 7 * 24 * 60 * 60;
+/// Maximum length of the output of a job (in bytes), the rest is discarded
+const MAX_OUTPUT_LEN: usize = 1024 * 1024;
+/// Capacity of the live event channel of a job, subscribers lagging further behind miss events
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+/// The status of a job
+pub enum JobStatus {
+    /// Waiting for the previous jobs of the deployment to finish
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    /// Whether the job has finished
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+/// A background job
+pub struct Job {
+    pub id: String,
+    /// The operation run by the job (eg: `redeploy`)
+    pub kind: String,
+    /// The deployment the job operates on
+    pub deployment: String,
+    /// Username of the user who started the job
+    pub username: String,
+    pub status: JobStatus,
+    /// Output of the job so far (stored in the job's output file, not in the jobs file)
+    #[serde(default)]
+    pub output: String,
+    /// Creation time (UNIX timestamp in seconds)
+    pub created_at: i64,
+    /// Time the job started running (UNIX timestamp in seconds)
+    pub started_at: Option<i64>,
+    /// Time the job finished (UNIX timestamp in seconds)
+    pub finished_at: Option<i64>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// A live update of a job
+pub enum JobEvent {
+    /// Output written by the job
+    Output { text: String },
+    /// The job's status changed
+    Status { status: JobStatus },
+}
+
+/// The jobs and the live event channels of the unfinished jobs
+struct JobsData {
+    /// Jobs by id
+    jobs: HashMap<String, Job>,
+    /// Event channels of the unfinished jobs, by job id
+    channels: HashMap<String, broadcast::Sender<JobEvent>>,
+}
+
+/// The job queue
+pub struct JobQueue {
+    path: PathBuf,
+    /// Directory of the output files of the jobs
+    output_dir: PathBuf,
+    data: Mutex<JobsData>,
+    /// Locks serializing the jobs of each deployment, by deployment name
+    deployment_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+/// A handle given to a running job, to write its output
+pub struct JobHandle {
+    queue: Arc<JobQueue>,
+    id: String,
+}
+
+impl JobHandle {
+    /// Returns the id of the job
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Appends text to the job's output
+    pub async fn output(&self, text: &str) {
+        if let Err(err) = self.queue.append_output(&self.id, text).await {
+            tracing::error!("Error writing the output of job {}: {err}", self.id);
+        }
+    }
+}
+
+impl Job {
+    /// Returns a copy of the job without its output, as stored in the jobs file
+    fn without_output(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            kind: self.kind.clone(),
+            deployment: self.deployment.clone(),
+            username: self.username.clone(),
+            status: self.status,
+            output: String::new(),
+            created_at: self.created_at,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+        }
+    }
+}
+
+/// Returns the path of the output file of a job
+fn output_path(output_dir: &Path, id: &str) -> PathBuf {
+    output_dir.join(format!("{id}.log"))
+}
+
+/// Appends text to an output file, creating it (readable only by the owner, the output may contain secrets) if needed
+fn append_to_output_file(path: &Path, text: &str) -> Res<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(text.as_bytes())?;
+
+    Ok(())
+}
+
+impl JobQueue {
+    /// Loads the jobs stored in the given (JSON) file, and their output from the `job_output` directory next to it. The files are created when the first job is started. Jobs that were interrupted (by a restart) are marked as failed.
+    pub fn load(path: PathBuf) -> Res<Self> {
+        let mut jobs: HashMap<String, Job> = utils::read_json_store(&path)?;
+        let output_dir = path.with_file_name("job_output");
+        std::fs::create_dir_all(&output_dir)?;
+
+        let now = chrono::Utc::now().timestamp();
+        for job in jobs.values_mut() {
+            let output_path = output_path(&output_dir, &job.id);
+
+            match std::fs::read_to_string(&output_path) {
+                Ok(output) => job.output = output,
+                // The output file is created on the job's first output
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            if !job.status.is_finished() {
+                let message = "Error: Job interrupted by a server restart.\n";
+                append_to_output_file(&output_path, message)?;
+                job.output.push_str(message);
+                job.status = JobStatus::Failed;
+                job.finished_at = Some(now);
+            }
+        }
+
+        Ok(Self {
+            path,
+            output_dir,
+            data: Mutex::new(JobsData {
+                jobs,
+                channels: HashMap::new(),
+            }),
+            deployment_locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Starts a job running `run` on a deployment, after the previous jobs of the deployment have finished. The job fails if `run` returns an error or panics.
+    pub async fn spawn<F, Fut>(
+        self: &Arc<Self>,
+        kind: &str,
+        deployment: &str,
+        username: &str,
+        run: F,
+    ) -> Res<Job>
+    where
+        F: FnOnce(JobHandle) -> Fut + Send + 'static,
+        Fut: Future<Output = Res<()>> + Send + 'static,
+    {
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind: kind.to_string(),
+            deployment: deployment.to_string(),
+            username: username.to_string(),
+            status: JobStatus::Queued,
+            output: String::new(),
+            created_at: chrono::Utc::now().timestamp(),
+            started_at: None,
+            finished_at: None,
+        };
+
+        {
+            let mut data = self.data.lock().await;
+            data.jobs.insert(job.id.clone(), job.clone());
+            data.channels
+                .insert(job.id.clone(), broadcast::channel(EVENT_CHANNEL_CAPACITY).0);
+            self.save(&mut data).await?;
+        }
+
+        let deployment_lock = self
+            .deployment_locks
+            .lock()
+            .await
+            .entry(deployment.to_string())
+            .or_default()
+            .clone();
+
+        let queue = self.clone();
+        let id = job.id.clone();
+        tokio::spawn(async move {
+            let _guard = deployment_lock.lock().await;
+            queue.set_status(&id, JobStatus::Running).await;
+
+            let handle = JobHandle {
+                queue: queue.clone(),
+                id: id.clone(),
+            };
+            // Run on its own task, so that a panic fails the job instead of leaving it running
+            let error = match tokio::spawn(run(handle)).await {
+                Ok(Ok(())) => None,
+                Ok(Err(err)) => Some(format!("Error: {err:#}\n")),
+                Err(err) => {
+                    tracing::error!("Job {id} panicked: {err}");
+                    Some("Error: The job crashed.\n".to_string())
+                }
+            };
+
+            let status = match error {
+                None => JobStatus::Succeeded,
+                Some(error) => {
+                    if let Err(err) = queue.append_output(&id, &error).await {
+                        tracing::error!("Error writing the output of job {id}: {err}");
+                    }
+                    JobStatus::Failed
+                }
+            };
+
+            queue.set_status(&id, status).await;
+        });
+
+        Ok(job)
+    }
+
+    /// Returns a job
+    pub async fn get(&self, id: &str) -> Option<Job> {
+        self.data.lock().await.jobs.get(id).cloned()
+    }
+
+    /// Returns a job and a stream of its events: its output so far, then its live output and status changes until it finishes
+    pub async fn events(&self, id: &str) -> Option<(Job, impl Stream<Item = JobEvent> + use<>)> {
+        let data = self.data.lock().await;
+        let job = data.jobs.get(id)?.clone();
+        // Subscribed while holding the lock, so that no output is missed or repeated
+        let receiver = data.channels.get(id).map(|sender| sender.subscribe());
+
+        let past_events = vec![
+            JobEvent::Output {
+                text: job.output.clone(),
+            },
+            JobEvent::Status { status: job.status },
+        ];
+        let live_events = stream::unfold(receiver, |receiver| async move {
+            let mut receiver = receiver?;
+
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                // The skipped events are replaced by a marker, the full output can be fetched again
+                Err(broadcast::error::RecvError::Lagged(skipped)) => JobEvent::Output {
+                    text: format!(
+                        "\n[... {skipped} output updates skipped, reload the job to see its full output ...]\n"
+                    ),
+                },
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+
+            Some((event, Some(receiver)))
+        });
+
+        Some((
+            job,
+            futures_util::StreamExt::chain(stream::iter(past_events), live_events),
+        ))
+    }
+
+    /// Appends text to the output of a job and its output file, truncating it to [`MAX_OUTPUT_LEN`]
+    async fn append_output(&self, id: &str, text: &str) -> Res<()> {
+        let mut data = self.data.lock().await;
+        let Some(job) = data.jobs.get_mut(id) else {
+            return Ok(());
+        };
+
+        let remaining = MAX_OUTPUT_LEN.saturating_sub(job.output.len());
+        let text = if text.len() > remaining {
+            let mut end = remaining;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            &text[..end]
+        } else {
+            text
+        };
+        if text.is_empty() {
+            return Ok(());
+        }
+        job.output.push_str(text);
+
+        if let Some(sender) = data.channels.get(id) {
+            // Fails only if there are no subscribers
+            let _ = sender.send(JobEvent::Output {
+                text: text.to_string(),
+            });
+        }
+        drop(data);
+
+        // The output of a job is written by one task at a time, so the appends are in order without holding the lock
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(output_path(&self.output_dir, id))
+            .await?;
+        file.write_all(text.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    /// Sets the status of a job. The event channel of a finished job is closed.
+    async fn set_status(&self, id: &str, status: JobStatus) {
+        let mut data = self.data.lock().await;
+        let Some(job) = data.jobs.get_mut(id) else {
+            return;
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        job.status = status;
+        if status == JobStatus::Running {
+            job.started_at = Some(now);
+        } else if status.is_finished() {
+            job.finished_at = Some(now);
+        }
+
+        let sender = if status.is_finished() {
+            data.channels.remove(id)
+        } else {
+            data.channels.get(id).cloned()
+        };
+        if let Some(sender) = sender {
+            let _ = sender.send(JobEvent::Status { status });
+        }
+
+        if let Err(err) = self.save(&mut data).await {
+            tracing::error!("Error saving the status of job {id}: {err}");
+        }
+    }
+
+    /// Prunes the old finished jobs (and their output files) and writes the jobs (without their output) to the file
+    async fn save(&self, data: &mut JobsData) -> Res<()> {
+        let now = chrono::Utc::now().timestamp();
+        let expired: Vec<String> = data
+            .jobs
+            .values()
+            .filter(|job| {
+                job.finished_at
+                    .is_some_and(|finished_at| finished_at + JOB_RETENTION <= now)
+            })
+            .map(|job| job.id.clone())
+            .collect();
+
+        for id in expired {
+            data.jobs.remove(&id);
+            match fs::remove_file(output_path(&self.output_dir, &id)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+
+        let jobs: HashMap<&String, Job> = data
+            .jobs
+            .iter()
+            .map(|(id, job)| (id, job.without_output()))
+            .collect();
+
+        utils::write_json_atomic(&self.path, &jobs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn jobs_of_a_deployment_run_one_at_a_time() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let queue = Arc::new(JobQueue::load(data_dir.path().join("jobs.json")).unwrap());
+
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let first = queue
+            .spawn("redeploy", "gyft", "alice", |job| async move {
+                job.output("first\n").await;
+                released.await?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        let second = queue
+            .spawn("redeploy", "gyft", "alice", |job| async move {
+                job.output("second\n").await;
+                Err(anyhow::anyhow!("Build failed."))
+            })
+            .await
+            .unwrap();
+
+        let (_, events) = queue.events(&first.id).await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(
+            queue.get(&second.id).await.unwrap().status,
+            JobStatus::Queued
+        );
+
+        release.send(()).unwrap();
+        let statuses: Vec<JobStatus> = events
+            .filter_map(|event| async move {
+                match event {
+                    JobEvent::Status { status } => Some(status),
+                    JobEvent::Output { .. } => None,
+                }
+            })
+            .collect()
+            .await;
+        assert_eq!(statuses.last(), Some(&JobStatus::Succeeded));
+
+        let (_, events) = queue.events(&second.id).await.unwrap();
+        events.collect::<Vec<_>>().await;
+        let second = queue.get(&second.id).await.unwrap();
+        assert_eq!(second.status, JobStatus::Failed);
+        assert_eq!(second.output, "second\nError: Build failed.\n");
+
+        // Jobs are persisted, with their output in separate files
+        let jobs_file = std::fs::read_to_string(data_dir.path().join("jobs.json")).unwrap();
+        assert!(!jobs_file.contains("first\n"));
+        let queue = JobQueue::load(data_dir.path().join("jobs.json")).unwrap();
+        assert_eq!(
+            queue.get(&first.id).await.unwrap().output,
+            "first\n".to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn panicking_jobs_fail() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let queue = Arc::new(JobQueue::load(data_dir.path().join("jobs.json")).unwrap());
+
+        let job = queue
+            .spawn("redeploy", "gyft", "alice", |job| async move {
+                job.output("building\n").await;
+                panic!("Unexpected state.");
+            })
+            .await
+            .unwrap();
+
+        // The events end when the job finishes
+        let (_, events) = queue.events(&job.id).await.unwrap();
+        events.collect::<Vec<_>>().await;
+
+        let job = queue.get(&job.id).await.unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.output, "building\nError: The job crashed.\n");
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_are_told_about_skipped_output() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let queue = Arc::new(JobQueue::load(data_dir.path().join("jobs.json")).unwrap());
+
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let job = queue
+            .spawn("redeploy", "gyft", "alice", |job| async move {
+                released.await?;
+                for line in 0..EVENT_CHANNEL_CAPACITY + 10 {
+                    job.output(&format!("{line}\n")).await;
+                }
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        // Subscribed before the job writes more output than the channel holds
+        let (_, events) = queue.events(&job.id).await.unwrap();
+        release.send(()).unwrap();
+        while !queue.get(&job.id).await.unwrap().status.is_finished() {
+            tokio::task::yield_now().await;
+        }
+
+        let output: String = events
+            .filter_map(|event| async move {
+                match event {
+                    JobEvent::Output { text } => Some(text),
+                    JobEvent::Status { .. } => None,
+                }
+            })
+            .collect()
+            .await;
+        assert!(output.contains("output updates skipped"));
+        assert!(output.ends_with(&format!("{}\n", EVENT_CHANNEL_CAPACITY + 9)));
+    }
+}
@@ -0,0 +1,23 @@
+//! The maintos backend, see [`routing::get_router`] for the API
+
+mod api_tokens;
+mod audit;
+mod auth;
+mod cache;
+mod compose;
+mod denylist;
+mod deploy;
+mod docker;
+mod dockerignore;
+pub mod env;
+mod env_file;
+mod git;
+pub mod github;
+mod history;
+mod jobs;
+mod permissions;
+pub mod routing;
+mod sessions;
+mod shell;
+mod utils;
+mod webhook;
@@ -4,16 +4,10 @@ use bollard::Docker;
 use clap::Parser;
 use tracing_subscriber::prelude::*;
 
-use crate::utils::Res;
-
-mod auth;
-mod env;
-mod github;
-mod routing;
-mod utils;
+use maintos_backend::{env, github, routing};
 
 #[tokio::main]
-async fn main() -> Res<()> {
+async fn main() -> Result<(), anyhow::Error> {
     // Read .env file if it exists
     if dotenvy::dotenv().is_ok() {
         println!("Loaded .env file.");
@@ -30,11 +24,18 @@ async fn main() -> Res<()> {
     // Docker API connection
     let docker = Docker::connect_with_local_defaults()?;
 
+    // Github API client
+    let github = github::GithubClient::new(&env_vars);
+
     // Server
     let listener =
         tokio::net::TcpListener::bind(format!("0.0.0.0:{}", env_vars.server_port)).await?;
     tracing::info!("Starting server on port {}", env_vars.server_port);
-    axum::serve(listener, routing::get_router(&env_vars, Arc::new(docker))).await?;
+    axum::serve(
+        listener,
+        routing::get_router(&env_vars, Arc::new(docker), Arc::new(github))?,
+    )
+    .await?;
 
     Ok(())
 }
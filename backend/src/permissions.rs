@@ -0,0 +1,165 @@
+//! Role-based permission model for deployments
+//!
+//! A user's [`Role`] on a deployment is the highest of the role mapped from their collaborator role on the deployment's repository and the roles granted to the organization teams they are a member of (see the `GH_TEAM_ROLES` environment variable). Each [`Action`] requires a minimum role, defined in [`Action::required_role`].
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+/// The role of a user on a deployment. Each role includes the permissions of the roles before it.
+pub enum Role {
+    /// Read-only access (deployment details, containers, stats, logs and environment variable names), only granted through team roles
+    Viewer,
+    /// Can operate the deployment (start/stop/restart containers, redeploy, edit environment variables, manage API tokens)
+    Operator,
+    /// Full access, including interactive shells in containers
+    Admin,
+}
+
+impl Role {
+    /// Maps a Github collaborator role name to a role. The `read`, `triage` and `write` roles have no access, the viewer role can only be granted through team roles.
+    pub fn from_collaborator_role(role_name: &str) -> Option<Self> {
+        match role_name {
+            "maintain" => Some(Self::Operator),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Viewer => write!(f, "viewer"),
+            Self::Operator => write!(f, "operator"),
+            Self::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "viewer" => Ok(Self::Viewer),
+            "operator" => Ok(Self::Operator),
+            "admin" => Ok(Self::Admin),
+            role => Err(anyhow!("Invalid role `{role}`.")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// A role granted to all the members of a Github organization team (on all the deployments)
+pub struct TeamRole {
+    /// Team slug
+    pub team: String,
+    pub role: Role,
+}
+
+impl FromStr for TeamRole {
+    type Err = anyhow::Error;
+
+    /// Parses a `team-slug:role` pair
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (team, role) = s.split_once(':').ok_or(anyhow!(
+            "Invalid team role `{s}`, expected `team-slug:role`."
+        ))?;
+
+        Ok(Self {
+            team: team.trim().to_string(),
+            role: role.parse()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// An action that can be performed on a deployment
+pub enum Action {
+    /// View the deployment's details, containers, stats, environment variable names and audit log
+    View,
+    /// Read the logs of the deployment's containers
+    ViewLogs,
+    /// Start, stop and restart containers
+    Operate,
+    /// Pull and redeploy the deployment
+    Redeploy,
+    /// Set and unset environment variables
+    EditEnv,
+    /// Open an interactive shell in a container
+    Shell,
+    /// Create, list and revoke the deployment's API tokens
+    ManageTokens,
+}
+
+impl Action {
+    /// The policy table: the minimum role required to perform each action
+    pub fn required_role(self) -> Role {
+        match self {
+            Self::View => Role::Viewer,
+            Self::ViewLogs => Role::Viewer,
+            Self::Operate => Role::Operator,
+            Self::Redeploy => Role::Operator,
+            Self::EditEnv => Role::Operator,
+            Self::Shell => Role::Admin,
+            Self::ManageTokens => Role::Operator,
+        }
+    }
+
+    /// Checks whether a role is allowed to perform the action
+    pub fn is_allowed(self, role: Role) -> bool {
+        role >= self.required_role()
+    }
+}
+
+/// A permission that can be required by a handler, see [`crate::routing`]'s `DeploymentAccess` extractor
+pub trait Permission {
+    const ACTION: Action;
+}
+
+/// Permission to perform [`Action::View`]
+pub struct View;
+impl Permission for View {
+    const ACTION: Action = Action::View;
+}
+
+/// Permission to perform [`Action::ViewLogs`]
+pub struct ViewLogs;
+impl Permission for ViewLogs {
+    const ACTION: Action = Action::ViewLogs;
+}
+
+/// Permission to perform [`Action::Operate`]
+pub struct Operate;
+impl Permission for Operate {
+    const ACTION: Action = Action::Operate;
+}
+
+/// Permission to perform [`Action::Redeploy`]
+pub struct Redeploy;
+impl Permission for Redeploy {
+    const ACTION: Action = Action::Redeploy;
+}
+
+/// Permission to perform [`Action::EditEnv`]
+pub struct EditEnv;
+impl Permission for EditEnv {
+    const ACTION: Action = Action::EditEnv;
+}
+
+/// Permission to perform [`Action::Shell`]
+pub struct Shell;
+impl Permission for Shell {
+    const ACTION: Action = Action::Shell;
+}
+
+/// Permission to perform [`Action::ManageTokens`]
+pub struct ManageTokens;
+impl Permission for ManageTokens {
+    const ACTION: Action = Action::ManageTokens;
+}
@@ -0,0 +1,110 @@
+//! Extractors for the axum handlers
+
+use std::{marker::PhantomData, sync::Arc};
+
+use axum::{
+    extract::{FromRequestParts, RawPathParams},
+    response::{IntoResponse, Response},
+};
+use git2::Repository;
+use http::{StatusCode, request::Parts};
+
+use crate::{
+    auth::{Auth, AuthKind},
+    permissions::{Permission, Role},
+    utils::{Deployment, get_deployment},
+};
+
+use super::{AppError, BackendResponse, RouterState};
+
+/// Extracts the deployment from the `{name}` path parameter of a route and checks that the authenticated user has the permission `P` on it (and that their API token, if any, allows it). Must be used on routes behind the JWT middleware.
+///
+/// Rejects the request with a not found response if the deployment does not exist or the user has no role on it, and a forbidden response if the user's role does not allow the action.
+pub struct DeploymentAccess<P: Permission> {
+    pub deployment: Deployment,
+    pub repo: Repository,
+    /// The user's role on the deployment
+    pub role: Role,
+    /// The authenticated user
+    pub auth: Auth,
+    _permission: PhantomData<P>,
+}
+
+impl<P: Permission> FromRequestParts<Arc<RouterState>> for DeploymentAccess<P> {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<RouterState>,
+    ) -> Result<Self, Self::Rejection> {
+        let not_found = || {
+            BackendResponse::<()>::error(
+                "Error: Deployment not found.".into(),
+                StatusCode::NOT_FOUND,
+            )
+            .into_response()
+        };
+
+        let auth = parts.extensions.get::<Auth>().cloned().ok_or_else(|| {
+            BackendResponse::<()>::error(
+                "Error: User unauthorized.".into(),
+                StatusCode::UNAUTHORIZED,
+            )
+            .into_response()
+        })?;
+
+        let path_params = RawPathParams::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let name = path_params
+            .iter()
+            .find_map(|(key, value)| (key == "name").then(|| value.to_string()))
+            .ok_or_else(not_found)?;
+
+        // API tokens are restricted to a deployment and a set of actions
+        if let AuthKind::ApiToken {
+            deployment,
+            actions,
+        } = &auth.kind
+            && (deployment != &name || !actions.contains(&P::ACTION))
+        {
+            return Err(BackendResponse::<()>::error(
+                "Error: The API token does not allow this action.".into(),
+                StatusCode::FORBIDDEN,
+            )
+            .into_response());
+        }
+
+        let Some((deployment, repo, role)) = get_deployment(
+            &state.env_vars,
+            state.github.as_ref(),
+            &state.role_cache,
+            &auth.username,
+            &name,
+        )
+        .await
+        .map_err(|err| AppError::from(err).into_response())?
+        else {
+            return Err(not_found());
+        };
+
+        if !P::ACTION.is_allowed(role) {
+            return Err(BackendResponse::<()>::error(
+                format!(
+                    "Error: The {} role is required to perform this action.",
+                    P::ACTION.required_role()
+                ),
+                StatusCode::FORBIDDEN,
+            )
+            .into_response());
+        }
+
+        Ok(Self {
+            deployment,
+            repo,
+            role,
+            auth,
+            _permission: PhantomData,
+        })
+    }
+}
@@ -4,17 +4,36 @@
 //!
 //! The request format is described
 
+use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
 
-use axum::extract::State;
-use axum::{Extension, extract::Json, http::StatusCode};
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State, WebSocketUpgrade};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, extract::Json, http::HeaderMap, http::StatusCode};
+use futures_util::{StreamExt, future, stream};
 use serde::Deserialize;
 use serde::Serialize;
 
-use crate::auth::{self, Auth};
-use crate::utils::{Deployment, get_deployments};
+use crate::api_tokens::ApiToken;
+use crate::audit::{AuditEntry, AuditFilter, AuditOutcome};
+use crate::auth::{self, Auth, AuthKind};
+use crate::compose::{self, ComposeProject, ops::ComposeOperation};
+use crate::deploy::{self, DeployKind};
+use crate::docker::{self, ContainerAction, ContainerInfo, ContainerMatch, ContainerStats};
+use crate::env_file::{self, EnvSummary};
+use crate::git::{self, GitState, RefKind, RemoteRefs};
+use crate::history::DeployRecord;
+use crate::jobs::{Job, JobEvent};
+use crate::permissions::{self, Action, EditEnv, ManageTokens, Operate, Role, View, ViewLogs};
+use crate::sessions::Refresh;
+use crate::shell;
+use crate::utils::{self, Deployment, get_deployments};
+use crate::webhook::{self, PushEvent};
 
-use super::{AppError, BackendResponse, RouterState};
+use super::{AppError, BackendResponse, RouterState, extractors::DeploymentAccess};
 
 /// The return type of a handler function. T is the data type returned if the operation was a success
 type HandlerReturn<T> = Result<(StatusCode, BackendResponse<T>), AppError>;
@@ -27,29 +46,116 @@ pub async fn healthcheck() -> HandlerReturn<()> {
     Ok(BackendResponse::ok("Hello, World.".into(), ()))
 }
 
+#[derive(Deserialize)]
+/// The request format for the OAuth start endpoint
+pub struct OAuthStartReq {
+    /// SHA-256 hash (hex encoded) of a random verifier kept by the browser, to be sent back to the OAuth endpoint
+    binding: String,
+}
+
+#[derive(Serialize)]
+/// The response format for the OAuth start endpoint
+pub struct OAuthStartRes {
+    /// Signed, short-lived `state` parameter, to be sent back to the OAuth endpoint with the code
+    state: String,
+    /// The Github OAuth authorization URL to redirect the user to
+    redirect_url: String,
+}
+
+/// Starts the Github OAuth login flow. Returns a signed `state` parameter, bound to the browser by the hash of its verifier, and the URL to redirect the user to.
+///
+/// Request format - [`OAuthStartReq`] (URL query parameters)
+pub async fn oauth_start(
+    State(state): HandlerState,
+    Query(query): Query<OAuthStartReq>,
+) -> HandlerReturn<OAuthStartRes> {
+    if query.binding.len() != 64 || !query.binding.chars().all(|char| char.is_ascii_hexdigit()) {
+        return Ok(BackendResponse::error(
+            "Error: The binding must be a hex encoded SHA-256 hash.".into(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let oauth_state = auth::generate_oauth_state(
+        &state.oauth_state_key,
+        &state.env_vars,
+        &query.binding.to_ascii_lowercase(),
+    )?;
+    let redirect_url = reqwest::Url::parse_with_params(
+        &format!(
+            "{}/login/oauth/authorize",
+            state.env_vars.gh_base_url.trim_end_matches('/')
+        ),
+        [
+            ("client_id", state.env_vars.gh_client_id.as_str()),
+            ("state", oauth_state.as_str()),
+        ],
+    )?;
+
+    Ok(BackendResponse::ok(
+        "Successfully started the OAuth flow.".into(),
+        OAuthStartRes {
+            state: oauth_state,
+            redirect_url: redirect_url.into(),
+        },
+    ))
+}
+
 #[derive(Deserialize)]
 /// The request format for the OAuth endpoint
 pub struct OAuthReq {
     code: String,
+    /// The `state` parameter from the OAuth start endpoint, returned by Github with the code
+    state: String,
+    /// The verifier whose hash was sent to the OAuth start endpoint, kept by the browser that started the login
+    verifier: String,
 }
 
 #[derive(Serialize)]
-/// The response format for the OAuth endpoint
+/// The response format for the OAuth and refresh token endpoints
 pub struct OAuthRes {
+    /// Short-lived JWT auth token
     token: String,
+    /// Single-use token to get a new JWT auth token (and refresh token) from the refresh token endpoint
+    refresh_token: String,
 }
 
-/// Takes a Github OAuth code and returns a JWT auth token to log in a user if authorized
+/// Takes a Github OAuth code and returns a JWT auth token and a refresh token to log in a user if authorized. The `state` parameter must be a valid, unused state from the OAuth start endpoint, sent with the verifier of its binding.
 ///
 /// Request format - [`OAuthReq`]
 pub async fn oauth(
     State(state): HandlerState,
     Json(body): Json<OAuthReq>,
 ) -> HandlerReturn<OAuthRes> {
-    if let Some(token) = auth::authenticate_user(&body.code, &state.env_vars).await? {
+    // Marking the state as used is atomic, so a state can not be used by concurrent requests
+    if !auth::verify_oauth_state(
+        &body.state,
+        &body.verifier,
+        &state.oauth_state_key,
+        &state.env_vars,
+    )
+    .is_some_and(|nonce| state.used_oauth_states.insert_if_absent(nonce, ()))
+    {
+        return Ok(BackendResponse::error(
+            "Error: OAuth state invalid or expired.".into(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    if let Some(tokens) = auth::authenticate_user(
+        &body.code,
+        &state.env_vars,
+        state.github.as_ref(),
+        &state.sessions,
+    )
+    .await?
+    {
         Ok(BackendResponse::ok(
             "Successfully authorized the user.".into(),
-            OAuthRes { token },
+            OAuthRes {
+                token: tokens.token,
+                refresh_token: tokens.refresh_token,
+            },
         ))
     } else {
         Ok(BackendResponse::error(
@@ -77,6 +183,123 @@ pub async fn profile(Extension(auth): Extension<Auth>) -> HandlerReturn<ProfileR
     ))
 }
 
+#[derive(Deserialize)]
+/// The request format for the refresh token endpoint
+pub struct RefreshReq {
+    refresh_token: String,
+}
+
+/// Exchanges a refresh token for a new JWT auth token and refresh token. Each refresh token can only be used once, reusing one revokes its whole session.
+///
+/// Request format - [`RefreshReq`]
+pub async fn refresh_token(
+    State(state): HandlerState,
+    Json(body): Json<RefreshReq>,
+) -> HandlerReturn<OAuthRes> {
+    match state.sessions.refresh(&body.refresh_token).await? {
+        Refresh::Rotated {
+            username,
+            session_id,
+            refresh_token,
+        } => {
+            if !utils::check_org_membership(
+                state.github.as_ref(),
+                &state.env_vars,
+                &state.role_cache,
+                &username,
+            )
+            .await?
+            {
+                state.revoke_user(&username).await?;
+
+                return Ok(BackendResponse::error(
+                    "Error: User is no longer a member of the organization.".into(),
+                    StatusCode::UNAUTHORIZED,
+                ));
+            }
+
+            Ok(BackendResponse::ok(
+                "Successfully refreshed the token.".into(),
+                OAuthRes {
+                    token: auth::generate_token(&username, &session_id, &state.env_vars).await?,
+                    refresh_token,
+                },
+            ))
+        }
+        Refresh::Reused { session_id } => {
+            state.token_denylist.revoke_session(&session_id).await?;
+
+            Ok(BackendResponse::error(
+                "Error: Refresh token already used, the session has been revoked.".into(),
+                StatusCode::UNAUTHORIZED,
+            ))
+        }
+        Refresh::Invalid => Ok(BackendResponse::error(
+            "Error: Refresh token invalid or expired.".into(),
+            StatusCode::UNAUTHORIZED,
+        )),
+    }
+}
+
+/// Logs out a user by revoking their JWT and ending its login session
+pub async fn logout(
+    State(state): HandlerState,
+    Extension(auth): Extension<Auth>,
+) -> HandlerReturn<()> {
+    let AuthKind::Session { session_id } = &auth.kind else {
+        return Ok(BackendResponse::error(
+            "Error: Only login sessions can be logged out.".into(),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+
+    state
+        .token_denylist
+        .revoke_token(&auth.token_id, auth.expiration)
+        .await?;
+    state.token_denylist.revoke_session(session_id).await?;
+    state.sessions.revoke(session_id).await?;
+
+    Ok(BackendResponse::ok("Successfully logged out.".into(), ()))
+}
+
+#[derive(Deserialize)]
+/// The request format for the revoke user tokens endpoint
+pub struct RevokeReq {
+    /// Username of the user whose tokens are revoked
+    username: String,
+}
+
+/// Revokes all the JWTs and login sessions of a user (eg: an offboarded maintainer). Only allowed for admins (members of a team with the admin role).
+///
+/// Request format - [`RevokeReq`]
+pub async fn revoke_user_tokens(
+    State(state): HandlerState,
+    Extension(auth): Extension<Auth>,
+    Json(body): Json<RevokeReq>,
+) -> HandlerReturn<()> {
+    if !utils::is_admin(
+        state.github.as_ref(),
+        &state.env_vars,
+        &state.role_cache,
+        &auth.username,
+    )
+    .await?
+    {
+        return Ok(BackendResponse::error(
+            "Error: Only admins can revoke tokens.".into(),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+
+    state.revoke_user(&body.username).await?;
+
+    Ok(BackendResponse::ok(
+        format!("Successfully revoked all the tokens of {}.", body.username),
+        (),
+    ))
+}
+
 /// Returns a list of all deployments
 pub async fn deployments(
     State(state): HandlerState,
@@ -84,6 +307,883 @@ pub async fn deployments(
 ) -> HandlerReturn<Vec<Deployment>> {
     Ok(BackendResponse::ok(
         "Successfully fetched deployments".into(),
-        get_deployments(&state.env_vars, &auth.username).await?,
+        get_deployments(
+            &state.env_vars,
+            state.github.as_ref(),
+            &state.role_cache,
+            &auth.username,
+        )
+        .await?,
+    ))
+}
+
+#[derive(Serialize)]
+/// The response format for the deployment details endpoint
+pub struct DeploymentDetailsRes {
+    #[serde(flatten)]
+    deployment: Deployment,
+    /// The user's role on the deployment
+    role: Role,
+    git: GitState,
+    /// The parsed compose file, with its validation errors and with the values of the environment variables and build arguments masked (`None` if there is no compose file or it could not be parsed)
+    compose: Option<ComposeProject>,
+    /// The error encountered while parsing the compose file
+    compose_error: Option<String>,
+}
+
+/// Returns the details of a deployment, including the live state of its git repository and its parsed compose file
+pub async fn deployment_details(
+    State(state): HandlerState,
+    access: DeploymentAccess<View>,
+) -> HandlerReturn<DeploymentDetailsRes> {
+    let deployment_dir = state.env_vars.deployments_dir.join(&access.deployment.name);
+    let (compose, compose_error) = match compose::load_project(&deployment_dir).await {
+        Ok(Some(mut project)) => {
+            project.check_external_resources(&state.docker).await;
+            project.mask_secrets();
+            (Some(project), None)
+        }
+        Ok(None) => (None, None),
+        Err(err) => (None, Some(err.to_string())),
+    };
+
+    Ok(BackendResponse::ok(
+        "Successfully fetched deployment details".into(),
+        DeploymentDetailsRes {
+            git: git::get_git_state(&access.repo)?,
+            compose,
+            compose_error,
+            deployment: access.deployment,
+            role: access.role,
+        },
+    ))
+}
+
+/// Starts a job pulling the latest changes of a deployment, and rebuilding and recreating its containers. Returns the job, see [`job`] and [`job_events`] for its progress.
+pub async fn redeploy(
+    State(state): HandlerState,
+    access: DeploymentAccess<permissions::Redeploy>,
+) -> HandlerReturn<Job> {
+    let job = spawn_deploy(
+        &state,
+        &access.deployment.name,
+        &access.auth.username,
+        DeployKind::Redeploy,
+    )
+    .await?;
+
+    Ok(BackendResponse::ok("Started the redeploy job.".into(), job))
+}
+
+/// Starts a job rolling back a deployment to a commit in the history of its checked-out branch, and rebuilding and recreating its containers. Returns the job, see [`job`] and [`job_events`] for its progress.
+pub async fn rollback(
+    State(state): HandlerState,
+    access: DeploymentAccess<permissions::Redeploy>,
+    Path((_, sha)): Path<(String, String)>,
+) -> HandlerReturn<Job> {
+    let job = spawn_deploy(
+        &state,
+        &access.deployment.name,
+        &access.auth.username,
+        DeployKind::Rollback(sha),
+    )
+    .await?;
+
+    Ok(BackendResponse::ok("Started the rollback job.".into(), job))
+}
+
+/// Starts a job running a compose operation (`build`, `pull`, `up` or `down`) on the compose project of a deployment, without pulling its repository (see [`deploy::run_compose_operation`]). Returns the job, see [`job`] and [`job_events`] for its progress.
+pub async fn compose_operation(
+    State(state): HandlerState,
+    access: DeploymentAccess<permissions::Redeploy>,
+    Path((_, operation)): Path<(String, ComposeOperation)>,
+) -> HandlerReturn<Job> {
+    let docker = state.docker.clone();
+    let path = git::get_workdir(&access.repo)?;
+
+    let job = state
+        .jobs
+        .spawn(
+            &format!("compose {}", operation.name()),
+            &access.deployment.name,
+            &access.auth.username,
+            move |job| async move {
+                deploy::run_compose_operation(&docker, &path, operation, &job).await
+            },
+        )
+        .await?;
+
+    Ok(BackendResponse::ok(
+        format!("Started the compose {} job.", operation.name()),
+        job,
+    ))
+}
+
+/// Returns the branches and tags of a deployment's repository, as of the last fetch of `origin` (see [`fetch`])
+pub async fn deployment_refs(access: DeploymentAccess<View>) -> HandlerReturn<RemoteRefs> {
+    let repo = access.repo;
+    let refs = tokio::task::spawn_blocking(move || git::get_remote_refs(&repo)).await??;
+
+    Ok(BackendResponse::ok(
+        "Successfully fetched the branches and tags.".into(),
+        refs,
+    ))
+}
+
+/// Starts a job fetching the branches and tags of `origin` for a deployment, to list them with [`deployment_refs`]. Returns the job, see [`job`] and [`job_events`] for its progress.
+pub async fn fetch(
+    State(state): HandlerState,
+    access: DeploymentAccess<permissions::Redeploy>,
+) -> HandlerReturn<Job> {
+    let path = git::get_workdir(&access.repo)?;
+    let fetch_token = state.env_vars.gh_fetch_token.clone();
+
+    let job = state
+        .jobs
+        .spawn(
+            "fetch",
+            &access.deployment.name,
+            &access.auth.username,
+            move |job| async move {
+                job.output("==> fetch origin\n").await;
+                tokio::task::spawn_blocking(move || {
+                    git::fetch_origin(&git2::Repository::open(path)?, &fetch_token)
+                })
+                .await??;
+                job.output("Fetched the branches and tags.\n").await;
+
+                Ok(())
+            },
+        )
+        .await?;
+
+    Ok(BackendResponse::ok("Started the fetch job.".into(), job))
+}
+
+#[derive(Deserialize)]
+/// The request format for the checkout endpoint
+pub struct CheckoutReq {
+    /// The type of the ref (`branch` or `tag`)
+    kind: RefKind,
+    /// Name of the branch (on `origin`, eg: `release`) or tag
+    name: String,
+}
+
+/// Starts a job fetching `origin` and switching the checked-out ref of a deployment to a branch or tag (see [`git::switch_ref`]). The containers are not rebuilt, redeploy the deployment to deploy the new ref. Returns the job, see [`job`] and [`job_events`] for its progress.
+///
+/// Request format - [`CheckoutReq`]
+pub async fn checkout(
+    State(state): HandlerState,
+    access: DeploymentAccess<permissions::Redeploy>,
+    Json(body): Json<CheckoutReq>,
+) -> HandlerReturn<Job> {
+    let path = git::get_workdir(&access.repo)?;
+    let fetch_token = state.env_vars.gh_fetch_token.clone();
+
+    let job = state
+        .jobs
+        .spawn(
+            "checkout",
+            &access.deployment.name,
+            &access.auth.username,
+            move |job| async move {
+                let CheckoutReq { kind, name } = body;
+
+                job.output(&format!("==> checkout {name}\n")).await;
+                let sha = tokio::task::spawn_blocking(move || {
+                    let repo = git2::Repository::open(path)?;
+                    git::fetch_origin(&repo, &fetch_token)?;
+                    git::switch_ref(&repo, kind, &name)
+                })
+                .await??;
+                job.output(&format!("Checked out {sha}.\n")).await;
+
+                Ok(())
+            },
+        )
+        .await?;
+
+    Ok(BackendResponse::ok("Started the checkout job.".into(), job))
+}
+
+/// Returns the redeploys and rollbacks of a deployment (newest first), with the commit and images in use before each
+pub async fn deploy_history(
+    State(state): HandlerState,
+    access: DeploymentAccess<View>,
+) -> HandlerReturn<Vec<DeployRecord>> {
+    Ok(BackendResponse::ok(
+        "Successfully fetched the deploy history.".into(),
+        state.deploy_history.list(&access.deployment.name).await,
+    ))
+}
+
+/// Starts a redeploy or rollback job for a deployment
+async fn spawn_deploy(
+    state: &Arc<RouterState>,
+    name: &str,
+    username: &str,
+    kind: DeployKind,
+) -> Result<Job, AppError> {
+    let docker = state.docker.clone();
+    let history = state.deploy_history.clone();
+    let path = state.env_vars.deployments_dir.join(name);
+    let (deployment, user) = (name.to_string(), username.to_string());
+    let fetch_token = state.env_vars.gh_fetch_token.clone();
+
+    Ok(state
+        .jobs
+        .spawn(kind.name(), name, username, move |job| async move {
+            let repo = git2::Repository::open(path)?;
+            deploy::deploy(
+                &docker,
+                &history,
+                &deployment,
+                &user,
+                kind,
+                repo,
+                &fetch_token,
+                &job,
+            )
+            .await
+        })
+        .await?)
+}
+
+/// Returns a job if the user may view its deployment (and their API token, if any, is for this deployment)
+async fn find_job(state: &RouterState, auth: &Auth, id: &str) -> Result<Option<Job>, AppError> {
+    let Some(job) = state.jobs.get(id).await else {
+        return Ok(None);
+    };
+
+    if let AuthKind::ApiToken { deployment, .. } = &auth.kind
+        && deployment != &job.deployment
+    {
+        return Ok(None);
+    }
+
+    let role = utils::get_deployment(
+        &state.env_vars,
+        state.github.as_ref(),
+        &state.role_cache,
+        &auth.username,
+        &job.deployment,
+    )
+    .await?
+    .map(|(_, _, role)| role);
+
+    Ok(role
+        .is_some_and(|role| Action::View.is_allowed(role))
+        .then_some(job))
+}
+
+/// Returns a job (eg: a redeploy), including its status and output. Requires the `viewer` role on the job's deployment.
+pub async fn job(
+    State(state): HandlerState,
+    Extension(auth): Extension<Auth>,
+    Path(id): Path<String>,
+) -> HandlerReturn<Job> {
+    match find_job(&state, &auth, &id).await? {
+        Some(job) => Ok(BackendResponse::ok(
+            "Successfully fetched the job.".into(),
+            job,
+        )),
+        None => Ok(BackendResponse::error(
+            "Error: Job not found.".into(),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+/// Streams the output and status of a job as Server-Sent Events, until the job finishes. Sends an `output` event with the output so far and a `status` event with the current status, then an event for each new output and status change. Requires the `viewer` role on the job's deployment.
+pub async fn job_events(
+    State(state): HandlerState,
+    Extension(auth): Extension<Auth>,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let events = match find_job(&state, &auth, &id).await? {
+        Some(job) => state.jobs.events(&job.id).await,
+        None => None,
+    };
+    let Some((_, events)) = events else {
+        return Ok(BackendResponse::<()>::error(
+            "Error: Job not found.".into(),
+            StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    };
+
+    let events = events.map(|event| {
+        Ok::<_, Infallible>(match event {
+            JobEvent::Output { text } => Event::default().event("output").data(text),
+            JobEvent::Status { status } => match Event::default().event("status").json_data(status)
+            {
+                Ok(event) => event,
+                Err(err) => Event::default().event("error").data(err.to_string()),
+            },
+        })
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Receives Github push webhooks and starts redeploy jobs for the deployments of the pushed branch that opted in to auto-deploy. Returns the jobs.
+///
+/// The payload must be signed with `GH_WEBHOOK_SECRET`. Events other than pushes are ignored.
+pub async fn github_webhook(
+    State(state): HandlerState,
+    headers: HeaderMap,
+    body: Bytes,
+) -> HandlerReturn<Vec<Job>> {
+    let secret = &state.env_vars.gh_webhook_secret;
+    let signature = headers
+        .get(webhook::SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    if secret.is_empty()
+        || !signature.is_some_and(|signature| webhook::verify_signature(secret, &body, signature))
+    {
+        return Ok(BackendResponse::error(
+            "Error: Webhook signature invalid.".into(),
+            StatusCode::UNAUTHORIZED,
+        ));
+    }
+
+    if headers
+        .get(webhook::EVENT_HEADER)
+        .is_none_or(|event| event != "push")
+    {
+        return Ok(BackendResponse::ok("Event ignored.".into(), Vec::new()));
+    }
+
+    let Ok(event) = serde_json::from_slice::<PushEvent>(&body) else {
+        return Ok(BackendResponse::error(
+            "Error: Invalid push event payload.".into(),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+
+    let targets = webhook::get_auto_deploy_targets(&state.env_vars.deployments_dir, &event).await?;
+
+    let mut jobs = Vec::new();
+    for name in targets {
+        let job = spawn_deploy(&state, &name, &event.sender.login, DeployKind::Redeploy).await?;
+
+        let entry = AuditEntry {
+            timestamp: chrono::Utc::now().timestamp(),
+            username: event.sender.login.clone(),
+            deployment: Some(name),
+            action: "POST /webhooks/github".into(),
+            params: serde_json::json!({ "ref": event.git_ref, "after": event.after }),
+            outcome: AuditOutcome {
+                success: true,
+                status_code: StatusCode::OK.as_u16(),
+                message: Some(format!("Started the redeploy job {}.", job.id)),
+            },
+        };
+        if let Err(err) = state.audit_log.record(&entry).await {
+            tracing::error!("Error recording audit log entry: {err}");
+        }
+
+        jobs.push(job);
+    }
+
+    Ok(BackendResponse::ok(
+        "Started the redeploy jobs of the pushed branch's deployments.".into(),
+        jobs,
+    ))
+}
+
+/// Returns the containers of a deployment
+pub async fn deployment_containers(
+    State(state): HandlerState,
+    access: DeploymentAccess<View>,
+) -> HandlerReturn<Vec<ContainerInfo>> {
+    Ok(BackendResponse::ok(
+        "Successfully fetched deployment containers".into(),
+        docker::get_deployment_containers(&state.docker, &git::get_workdir(&access.repo)?).await?,
+    ))
+}
+
+/// Finds a container of a deployment (in its directory) by its id, name or unique id prefix (see [`docker::find_project_container`]). Returns its id, or the error response if it is not found or the id prefix is ambiguous.
+async fn find_container(
+    state: &RouterState,
+    deployment_dir: &std::path::Path,
+    container: &str,
+) -> Result<Result<String, (StatusCode, BackendResponse<()>)>, AppError> {
+    let found = docker::find_project_container(&state.docker, deployment_dir, container).await?;
+
+    Ok(match found {
+        ContainerMatch::Found(id) => Ok(id),
+        ContainerMatch::NotFound => Err(BackendResponse::error(
+            "Error: Container not found.".into(),
+            StatusCode::NOT_FOUND,
+        )),
+        ContainerMatch::Ambiguous => Err(BackendResponse::error(
+            "Error: The container id prefix matches several containers.".into(),
+            StatusCode::CONFLICT,
+        )),
+    })
+}
+
+/// Starts, stops or restarts a container of a deployment
+pub async fn container_action(
+    State(state): HandlerState,
+    access: DeploymentAccess<Operate>,
+    Path((_, container, action)): Path<(String, String, ContainerAction)>,
+) -> HandlerReturn<()> {
+    let container_id =
+        match find_container(&state, &git::get_workdir(&access.repo)?, &container).await? {
+            Ok(container_id) => container_id,
+            Err(response) => return Ok(response),
+        };
+
+    docker::run_container_action(&state.docker, &container_id, action).await?;
+
+    Ok(BackendResponse::ok(
+        "Successfully performed the container action.".into(),
+        (),
+    ))
+}
+
+#[derive(Deserialize)]
+/// The request format for the container logs endpoint
+pub struct LogsReq {
+    /// Only return logs since this UNIX timestamp
+    since: Option<i32>,
+    /// Number of lines to return from the end of the logs, or `all` (default: `100`)
+    tail: Option<String>,
+    /// Whether to keep streaming new logs (default: `false`)
+    follow: Option<bool>,
+}
+
+/// Streams the logs of a container of a deployment as Server-Sent Events. Each event is named after the stream the log was written to (`stdout` or `stderr`, `console` for TTY containers). An `error` event is sent if reading the logs fails.
+///
+/// Request format - [`LogsReq`] (URL query parameters)
+pub async fn container_logs(
+    State(state): HandlerState,
+    access: DeploymentAccess<ViewLogs>,
+    Path((_, container)): Path<(String, String)>,
+    Query(query): Query<LogsReq>,
+) -> Result<Response, AppError> {
+    let container_id =
+        match find_container(&state, &git::get_workdir(&access.repo)?, &container).await? {
+            Ok(container_id) => container_id,
+            Err(response) => return Ok(response.into_response()),
+        };
+
+    let events = docker::stream_logs(
+        &state.docker,
+        &container_id,
+        query.since,
+        query.tail.as_deref().unwrap_or("100"),
+        query.follow.unwrap_or(false),
+    )
+    .map(|chunk| {
+        Ok::<_, Infallible>(match chunk {
+            Ok(chunk) => Event::default()
+                .event(chunk.stream)
+                .data(chunk.message.trim_end_matches('\n')),
+            Err(err) => Event::default().event("error").data(err.to_string()),
+        })
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Returns a snapshot of the resource usage statistics of all the running containers of a deployment
+pub async fn deployment_stats(
+    State(state): HandlerState,
+    access: DeploymentAccess<View>,
+) -> HandlerReturn<Vec<ContainerStats>> {
+    let container_ids =
+        docker::get_running_project_containers(&state.docker, &git::get_workdir(&access.repo)?)
+            .await?;
+
+    let stats = future::try_join_all(
+        container_ids
+            .iter()
+            .map(|container_id| docker::get_stats(&state.docker, container_id)),
+    )
+    .await?;
+
+    Ok(BackendResponse::ok(
+        "Successfully fetched deployment stats".into(),
+        stats,
+    ))
+}
+
+/// Streams the resource usage statistics of all the running containers of a deployment as Server-Sent Events. Each `stats` event contains the JSON serialized [`ContainerStats`] of one container. An `error` event is sent if reading the stats fails.
+pub async fn deployment_stats_stream(
+    State(state): HandlerState,
+    access: DeploymentAccess<View>,
+) -> Result<Response, AppError> {
+    let container_ids =
+        docker::get_running_project_containers(&state.docker, &git::get_workdir(&access.repo)?)
+            .await?;
+
+    let events = stream::select_all(
+        container_ids
+            .iter()
+            .map(|container_id| docker::stream_stats(&state.docker, container_id).boxed()),
+    )
+    .map(|stats| {
+        Ok::<_, Infallible>(
+            match stats.and_then(|stats| Ok(Event::default().event("stats").json_data(stats)?)) {
+                Ok(event) => event,
+                Err(err) => Event::default().event("error").data(err.to_string()),
+            },
+        )
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+#[derive(Deserialize)]
+/// The request format for the container shell endpoint
+pub struct ShellReq {
+    /// The command to run (split on whitespace, default: `/bin/sh`)
+    cmd: Option<String>,
+}
+
+/// Opens an interactive shell in a container of a deployment over a WebSocket. Requires the `admin` role on the deployment. See [`shell`] for the protocol and for authenticating from browsers.
+///
+/// Request format - [`ShellReq`] (URL query parameters)
+pub async fn container_shell(
+    State(state): HandlerState,
+    access: DeploymentAccess<permissions::Shell>,
+    Path((_, container)): Path<(String, String)>,
+    Query(query): Query<ShellReq>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let container_id =
+        match find_container(&state, &git::get_workdir(&access.repo)?, &container).await? {
+            Ok(container_id) => container_id,
+            Err(response) => return Ok(response.into_response()),
+        };
+
+    let cmd = query
+        .cmd
+        .as_deref()
+        .unwrap_or("/bin/sh")
+        .split_whitespace()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<String>>();
+    if cmd.is_empty() {
+        return Ok(BackendResponse::<()>::error(
+            "Error: Command is empty.".into(),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response());
+    }
+
+    tracing::info!(
+        "{} opened a shell in container {container_id} of deployment {}.",
+        access.auth.username,
+        access.deployment.name
+    );
+
+    let timeout = Duration::from_secs(state.env_vars.shell_session_timeout);
+    Ok(ws
+        .protocols([shell::SHELL_PROTOCOL])
+        .on_upgrade(move |socket| async move {
+            shell::run_shell_session(&state.docker, &container_id, cmd, timeout, socket).await
+        }))
+}
+
+/// Maximum lifetime of an API token (in days)
+const MAX_API_TOKEN_LIFETIME_DAYS: i64 = 365;
+
+/// Returns the API tokens of a deployment (without the tokens themselves)
+pub async fn api_tokens(
+    State(state): HandlerState,
+    access: DeploymentAccess<ManageTokens>,
+) -> HandlerReturn<Vec<ApiToken>> {
+    Ok(BackendResponse::ok(
+        "Successfully fetched the API tokens.".into(),
+        state.api_tokens.list(&access.deployment.name).await,
+    ))
+}
+
+#[derive(Deserialize)]
+/// The request format for the create API token endpoint
+pub struct CreateApiTokenReq {
+    /// A name describing the token's use (eg: `github-actions`)
+    name: String,
+    /// The actions the token allows (eg: `["redeploy"]`)
+    actions: Vec<Action>,
+    /// Number of days after which the token expires
+    expires_in_days: i64,
+}
+
+#[derive(Serialize)]
+/// The response format for the create API token endpoint
+pub struct CreateApiTokenRes {
+    /// The token, to be sent as a bearer token. It cannot be retrieved again.
+    token: String,
+    #[serde(flatten)]
+    info: ApiToken,
+}
+
+/// Creates a personal API token for a deployment, acting on behalf of the user and restricted to a set of actions. The user must be allowed to perform all these actions.
+///
+/// Request format - [`CreateApiTokenReq`]
+pub async fn create_api_token(
+    State(state): HandlerState,
+    access: DeploymentAccess<ManageTokens>,
+    Json(body): Json<CreateApiTokenReq>,
+) -> HandlerReturn<CreateApiTokenRes> {
+    if body.name.trim().is_empty() || body.actions.is_empty() {
+        return Ok(BackendResponse::error(
+            "Error: The token name and actions are required.".into(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    if !(1..=MAX_API_TOKEN_LIFETIME_DAYS).contains(&body.expires_in_days) {
+        return Ok(BackendResponse::error(
+            format!("Error: Tokens must expire in 1 to {MAX_API_TOKEN_LIFETIME_DAYS} days."),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    if body.actions.contains(&Action::ManageTokens) {
+        return Ok(BackendResponse::error(
+            "Error: API tokens cannot manage API tokens.".into(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    if let Some(action) = body
+        .actions
+        .iter()
+        .find(|action| !action.is_allowed(access.role))
+    {
+        return Ok(BackendResponse::error(
+            format!(
+                "Error: The {} role is required to create a token allowing {action:?}.",
+                action.required_role()
+            ),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+
+    let expiration = chrono::Utc::now().timestamp() + body.expires_in_days * 24 * 60 * 60;
+    let (token, info) = state
+        .api_tokens
+        .create(
+            body.name.trim(),
+            &access.auth.username,
+            &access.deployment.name,
+            body.actions,
+            expiration,
+        )
+        .await?;
+
+    Ok(BackendResponse::ok(
+        "Successfully created the API token.".into(),
+        CreateApiTokenRes { token, info },
+    ))
+}
+
+/// Revokes an API token of a deployment
+pub async fn revoke_api_token(
+    State(state): HandlerState,
+    access: DeploymentAccess<ManageTokens>,
+    Path((_, id)): Path<(String, String)>,
+) -> HandlerReturn<()> {
+    if state
+        .api_tokens
+        .revoke(&access.deployment.name, &id)
+        .await?
+    {
+        Ok(BackendResponse::ok(
+            "Successfully revoked the API token.".into(),
+            (),
+        ))
+    } else {
+        Ok(BackendResponse::error(
+            "Error: API token not found.".into(),
+            StatusCode::NOT_FOUND,
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+/// The request format for the environment file endpoint
+pub struct EnvReq {
+    /// Directory of the environment file, relative to the deployment directory (default: the deployment directory)
+    dir: Option<String>,
+}
+
+/// Returns the keys of a deployment's `.env` file (with masked values), and the keys missing from or not in its `.env.template`
+///
+/// Request format - [`EnvReq`] (URL query parameters)
+pub async fn env(
+    access: DeploymentAccess<View>,
+    Query(query): Query<EnvReq>,
+) -> HandlerReturn<EnvSummary> {
+    let Some(env_dir) =
+        env_file::resolve_env_dir(&git::get_workdir(&access.repo)?, query.dir.as_deref()).await?
+    else {
+        return Ok(BackendResponse::error(
+            "Error: Directory not found.".into(),
+            StatusCode::NOT_FOUND,
+        ));
+    };
+
+    Ok(BackendResponse::ok(
+        "Successfully fetched the environment file".into(),
+        env_file::get_env_summary(&env_dir).await?,
+    ))
+}
+
+#[derive(Deserialize)]
+/// The request format for the set environment variable endpoint
+pub struct SetEnvReq {
+    /// Directory of the environment file, relative to the deployment directory (default: the deployment directory)
+    dir: Option<String>,
+    key: String,
+    value: String,
+}
+
+/// Sets a key in a deployment's `.env` file. The previous file is backed up to `.env.bak`.
+///
+/// Request format - [`SetEnvReq`]
+pub async fn set_env(
+    State(state): HandlerState,
+    access: DeploymentAccess<EditEnv>,
+    Json(body): Json<SetEnvReq>,
+) -> HandlerReturn<EnvSummary> {
+    if !env_file::is_valid_key(&body.key) {
+        return Ok(BackendResponse::error(
+            "Error: Invalid environment variable name.".into(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    if body.value.contains(['\n', '\r']) {
+        return Ok(BackendResponse::error(
+            "Error: Environment variable values cannot contain newlines.".into(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let Some(env_dir) =
+        env_file::resolve_env_dir(&git::get_workdir(&access.repo)?, body.dir.as_deref()).await?
+    else {
+        return Ok(BackendResponse::error(
+            "Error: Directory not found.".into(),
+            StatusCode::NOT_FOUND,
+        ));
+    };
+
+    let _env_lock = state.env_file_locks.lock(&access.deployment.name).await;
+    env_file::set_env_key(&env_dir, &body.key, &body.value).await?;
+
+    Ok(BackendResponse::ok(
+        "Successfully set the environment variable".into(),
+        env_file::get_env_summary(&env_dir).await?,
+    ))
+}
+
+#[derive(Deserialize)]
+/// The request format for the unset environment variable endpoint
+pub struct UnsetEnvReq {
+    /// Directory of the environment file, relative to the deployment directory (default: the deployment directory)
+    dir: Option<String>,
+    key: String,
+}
+
+/// Removes a key from a deployment's `.env` file. The previous file is backed up to `.env.bak`.
+///
+/// Request format - [`UnsetEnvReq`]
+pub async fn unset_env(
+    State(state): HandlerState,
+    access: DeploymentAccess<EditEnv>,
+    Json(body): Json<UnsetEnvReq>,
+) -> HandlerReturn<EnvSummary> {
+    let Some(env_dir) =
+        env_file::resolve_env_dir(&git::get_workdir(&access.repo)?, body.dir.as_deref()).await?
+    else {
+        return Ok(BackendResponse::error(
+            "Error: Directory not found.".into(),
+            StatusCode::NOT_FOUND,
+        ));
+    };
+
+    let _env_lock = state.env_file_locks.lock(&access.deployment.name).await;
+    if !env_file::unset_env_key(&env_dir, &body.key).await? {
+        return Ok(BackendResponse::error(
+            "Error: Environment variable not found.".into(),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    Ok(BackendResponse::ok(
+        "Successfully unset the environment variable".into(),
+        env_file::get_env_summary(&env_dir).await?,
+    ))
+}
+
+#[derive(Deserialize)]
+/// The request format for the audit log endpoint
+pub struct AuditReq {
+    /// Only return entries of this deployment
+    deployment: Option<String>,
+    /// Only return entries of this user
+    user: Option<String>,
+    /// Only return entries since this UNIX timestamp
+    since: Option<i64>,
+    /// Maximum number of entries returned, the newest ones (100 by default, at most 1000)
+    limit: Option<usize>,
+    /// Number of the newest entries skipped, to page through older entries
+    offset: Option<usize>,
+}
+
+/// Maximum number of entries returned by the audit log endpoint
+const MAX_AUDIT_LIMIT: usize = 1000;
+
+/// Returns a page of the audit log entries matching the filters (the newest ones), oldest first. Only entries of the deployments the user can manage (and the user's own entries not related to a deployment) are returned.
+///
+/// Request format - [`AuditReq`] (URL query parameters)
+pub async fn audit(
+    State(state): HandlerState,
+    Extension(auth): Extension<Auth>,
+    Query(query): Query<AuditReq>,
+) -> HandlerReturn<Vec<AuditEntry>> {
+    let deployments = get_deployments(
+        &state.env_vars,
+        state.github.as_ref(),
+        &state.role_cache,
+        &auth.username,
+    )
+    .await?;
+
+    let filter = AuditFilter {
+        deployment: query.deployment.as_deref(),
+        username: query.user.as_deref(),
+        since: query.since,
+        limit: query.limit.unwrap_or(100).min(MAX_AUDIT_LIMIT),
+        offset: query.offset.unwrap_or(0),
+    };
+    let entries = state
+        .audit_log
+        .query(&filter, |entry| match &entry.deployment {
+            Some(name) => deployments
+                .iter()
+                .any(|deployment| &deployment.name == name),
+            None => entry.username == auth.username,
+        })
+        .await?;
+
+    Ok(BackendResponse::ok(
+        "Successfully fetched the audit log".into(),
+        entries,
     ))
 }
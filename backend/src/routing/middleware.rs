@@ -1,52 +1,238 @@
 //! Middleware for the axum router
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use axum::{
-    extract::{Request, State},
+    Extension,
+    body::Body,
+    extract::{MatchedPath, Query, RawPathParams, Request, State},
     middleware::Next,
     response::{IntoResponse, Response},
 };
-use http::{HeaderMap, StatusCode};
+use http::{HeaderMap, Method, StatusCode, header};
 
-use crate::auth;
+use crate::api_tokens::API_TOKEN_PREFIX;
+use crate::audit::{self, AuditEntry, AuditOutcome};
+use crate::auth::{self, Auth, TokenError};
+use crate::shell::WEBSOCKET_TOKEN_PROTOCOL_PREFIX;
+use crate::utils;
 
 use super::{AppError, BackendResponse, RouterState};
 
-/// Verifies the JWT and authenticates a user. If the JWT is invalid, the user is sent an unauthorized status code. If the JWT is valid, the authentication is added to the state.
+/// Verifies the JWT (or personal API token) and authenticates a user. The token is read from the `Authorization` header, or from the `Sec-WebSocket-Protocol` header of WebSocket upgrade requests (see [`shell`](crate::shell)). If the token is invalid, the user is sent an unauthorized status code. If the JWT is valid, the authentication is added to the state.
 pub async fn verify_jwt_middleware(
     State(state): State<Arc<RouterState>>,
     headers: HeaderMap,
     mut request: Request,
     next: Next,
 ) -> Result<Response, AppError> {
-    if let Some(auth_header) = headers.get("Authorization") {
-        if let Some(jwt) = auth_header.to_str()?.strip_prefix("Bearer ") {
-            let auth = auth::verify_token(jwt, &state.env_vars).await;
-
-            if let Ok(auth) = auth {
-                // If auth is fine, add it to the request extensions
-                request.extensions_mut().insert(auth);
-                Ok(next.run(request).await)
-            } else {
-                Ok(BackendResponse::<()>::error(
-                    "Authorization token invalid.".into(),
+    let jwt = match headers.get("Authorization") {
+        Some(auth_header) => match auth_header.to_str()?.strip_prefix("Bearer ") {
+            Some(jwt) => jwt,
+            None => {
+                return Ok(BackendResponse::<()>::error(
+                    "Authorization header format invalid.".into(),
                     StatusCode::UNAUTHORIZED,
                 )
-                .into_response())
+                .into_response());
             }
-        } else {
-            Ok(BackendResponse::<()>::error(
-                "Authorization header format invalid.".into(),
+        },
+        // Browsers cannot set headers on WebSocket connections
+        None => match websocket_protocol_token(&headers) {
+            Some(jwt) => jwt,
+            None => {
+                return Ok(BackendResponse::<()>::error(
+                    "Authorization header missing.".into(),
+                    StatusCode::UNAUTHORIZED,
+                )
+                .into_response());
+            }
+        },
+    };
+
+    let auth = if jwt.starts_with(API_TOKEN_PREFIX) {
+        let Some(api_token) = state.api_tokens.verify(jwt).await else {
+            return Ok(BackendResponse::<()>::error(
+                "API token invalid, expired or revoked.".into(),
                 StatusCode::UNAUTHORIZED,
             )
-            .into_response())
+            .into_response());
+        };
+
+        // API tokens are scoped to a deployment (and its jobs), and cannot manage API tokens
+        let is_deployment_route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str())
+            .is_some_and(|path| {
+                path == "/deployments/{name}"
+                    || (path.starts_with("/deployments/{name}/")
+                        && !path.starts_with("/deployments/{name}/tokens"))
+                    || path.starts_with("/jobs/{id}")
+            });
+        if !is_deployment_route {
+            return Ok(BackendResponse::<()>::error(
+                "API tokens can only be used for deployment actions.".into(),
+                StatusCode::FORBIDDEN,
+            )
+            .into_response());
         }
+
+        Auth::from_api_token(jwt, api_token)
     } else {
-        Ok(BackendResponse::<()>::error(
-            "Authorization header missing.".into(),
-            StatusCode::UNAUTHORIZED,
+        match auth::verify_token(jwt, &state.env_vars, &state.token_denylist).await {
+            Ok(auth) => auth,
+            Err(err) => {
+                // Rejected tokens are sent the reason, other errors are internal errors
+                let token_err = err.downcast::<TokenError>()?;
+
+                return Ok(BackendResponse::<()>::error(
+                    token_err.to_string(),
+                    StatusCode::UNAUTHORIZED,
+                )
+                .into_response());
+            }
+        }
+    };
+
+    // Users removed from the organization must lose access before their tokens expire, their membership is re-checked at most every `MEMBERSHIP_CHECK_INTERVAL` (regardless of the age of the token)
+    if state.membership_checks.get(&auth.username).is_none() {
+        if !utils::check_org_membership(
+            state.github.as_ref(),
+            &state.env_vars,
+            &state.role_cache,
+            &auth.username,
         )
-        .into_response())
+        .await?
+        {
+            state.revoke_user(&auth.username).await?;
+
+            return Ok(BackendResponse::<()>::error(
+                "User is no longer a member of the organization.".into(),
+                StatusCode::UNAUTHORIZED,
+            )
+            .into_response());
+        }
+
+        state.membership_checks.insert(auth.username.clone(), ());
     }
+
+    // If auth is fine, add it to the request extensions
+    request.extensions_mut().insert(auth);
+    Ok(next.run(request).await)
+}
+
+/// Returns the token sent in the `Sec-WebSocket-Protocol` header of a WebSocket upgrade request, as a subprotocol prefixed with [`WEBSOCKET_TOKEN_PROTOCOL_PREFIX`]
+fn websocket_protocol_token(headers: &HeaderMap) -> Option<&str> {
+    if !headers
+        .get(header::UPGRADE)
+        .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"))
+    {
+        return None;
+    }
+
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|protocols| protocols.to_str().ok())
+        .flat_map(|protocols| protocols.split(','))
+        .find_map(|protocol| {
+            protocol
+                .trim()
+                .strip_prefix(WEBSOCKET_TOKEN_PROTOCOL_PREFIX)
+        })
+}
+
+/// Maximum size of a request or response body read by the audit middleware
+const AUDIT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+#[derive(serde::Deserialize)]
+/// The fields of a [`BackendResponse`] recorded in the audit log
+struct AuditedResponse {
+    status: String,
+    message: String,
+}
+
+/// Records every mutating request (all non-`GET` requests and WebSocket upgrades) in the audit log, with the username, deployment, action, parameters (path, query and JSON body, with secrets redacted) and outcome. Must be run after [`verify_jwt_middleware`].
+pub async fn audit_middleware(
+    State(state): State<Arc<RouterState>>,
+    Extension(auth): Extension<Auth>,
+    matched_path: MatchedPath,
+    path_params: RawPathParams,
+    Query(query): Query<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if request.method() == Method::GET && !request.headers().contains_key(header::UPGRADE) {
+        return Ok(next.run(request).await);
+    }
+
+    let action = format!("{} {}", request.method(), matched_path.as_str());
+
+    let mut deployment = None;
+    let mut params = serde_json::Map::new();
+    for (name, value) in &path_params {
+        if name == "name" {
+            deployment = Some(value.to_string());
+        } else {
+            params.insert(name.into(), value.into());
+        }
+    }
+    params.extend(query.into_iter().map(|(name, value)| (name, value.into())));
+
+    // The body has to be read to record the parameters, and put back for the handler
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, AUDIT_BODY_LIMIT).await?;
+    if let Ok(serde_json::Value::Object(body_params)) = serde_json::from_slice(&body) {
+        params.extend(body_params);
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    // Only JSON responses (backend responses) are read, other responses (eg: WebSocket upgrades) are passed through as-is
+    let status_code = response.status();
+    let (response, backend_response) = if response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/json")
+    {
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, AUDIT_BODY_LIMIT).await?;
+        let backend_response = serde_json::from_slice::<AuditedResponse>(&body).ok();
+
+        (
+            Response::from_parts(parts, Body::from(body)),
+            backend_response,
+        )
+    } else {
+        (response, None)
+    };
+
+    let outcome = AuditOutcome {
+        success: (status_code.is_success() || status_code == StatusCode::SWITCHING_PROTOCOLS)
+            && backend_response
+                .as_ref()
+                .is_none_or(|response| response.status == "success"),
+        status_code: status_code.as_u16(),
+        message: backend_response.map(|response| response.message),
+    };
+
+    let mut params = serde_json::Value::Object(params);
+    audit::redact_params(&mut params);
+
+    let entry = AuditEntry {
+        timestamp: chrono::Utc::now().timestamp(),
+        username: auth.username,
+        deployment,
+        action,
+        params,
+        outcome,
+    };
+
+    if let Err(err) = state.audit_log.record(&entry).await {
+        tracing::error!("Error recording audit log entry: {err}");
+    }
+
+    Ok(response)
 }
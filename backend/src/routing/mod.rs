@@ -1,36 +1,182 @@
 //! Router, [`handlers`], [`middleware`], state, and response utils.
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::{extract::Json, http::StatusCode, response::IntoResponse};
 use bollard::Docker;
+use hmac::Hmac;
 use http::{HeaderValue, Method};
 use serde::Serialize;
+use sha2::Sha256;
 use tower_http::{
     cors::{Any, CorsLayer},
     trace::{self, TraceLayer},
 };
 
-use crate::env::EnvVars;
+use crate::{
+    api_tokens::ApiTokenStore,
+    audit::AuditLog,
+    auth::{self, OAUTH_STATE_LIFETIME},
+    cache::TtlCache,
+    denylist::TokenDenylist,
+    env::EnvVars,
+    env_file::EnvFileLocks,
+    github::GithubApi,
+    history::DeployHistory,
+    jobs::JobQueue,
+    sessions::SessionStore,
+    utils::Res,
+    utils::RoleCache,
+};
 
+mod extractors;
 mod handlers;
 mod middleware;
 
-/// Returns the Axum router for maintos
-pub fn get_router(env_vars: &EnvVars, docker: Arc<Docker>) -> axum::Router {
+/// Returns the Axum router for maintos. Fails if a data file cannot be read or the token denylist is corrupt, the other corrupt data files are moved aside (see [`utils::read_json_store`](crate::utils::read_json_store)).
+pub fn get_router(
+    env_vars: &EnvVars,
+    docker: Arc<Docker>,
+    github: Arc<dyn GithubApi>,
+) -> Res<axum::Router> {
     let state = Arc::new(RouterState {
         env_vars: env_vars.clone(),
         docker,
+        github,
+        audit_log: Arc::new(AuditLog::new(env_vars.data_dir.join("audit.jsonl"))),
+        role_cache: Arc::new(RoleCache::new(Duration::from_secs(env_vars.gh_cache_ttl))),
+        token_denylist: Arc::new(TokenDenylist::load(
+            env_vars.data_dir.join("token_denylist.json"),
+            env_vars.access_token_lifetime as i64,
+            env_vars.jwt_clock_skew as i64,
+        )?),
+        api_tokens: Arc::new(ApiTokenStore::load(
+            env_vars.data_dir.join("api_tokens.json"),
+        )?),
+        membership_checks: Arc::new(TtlCache::new(Duration::from_secs(
+            env_vars.membership_check_interval,
+        ))),
+        oauth_state_key: auth::generate_oauth_state_key()?,
+        used_oauth_states: Arc::new(TtlCache::new(Duration::from_secs(
+            OAUTH_STATE_LIFETIME + env_vars.jwt_clock_skew,
+        ))),
+        deploy_history: Arc::new(DeployHistory::load(
+            env_vars.data_dir.join("deploy_history.json"),
+        )?),
+        env_file_locks: Arc::new(EnvFileLocks::default()),
+        jobs: Arc::new(JobQueue::load(env_vars.data_dir.join("jobs.json"))?),
+        sessions: Arc::new(SessionStore::load(
+            env_vars.data_dir.join("sessions.json"),
+            env_vars.refresh_token_lifetime as i64,
+            env_vars.session_max_lifetime as i64,
+        )?),
     });
 
-    axum::Router::new()
+    Ok(axum::Router::new()
         .route("/profile", axum::routing::get(handlers::profile))
+        .route("/logout", axum::routing::post(handlers::logout))
+        .route(
+            "/admin/revoke",
+            axum::routing::post(handlers::revoke_user_tokens),
+        )
+        .route("/jobs/{id}", axum::routing::get(handlers::job))
+        .route(
+            "/jobs/{id}/events",
+            axum::routing::get(handlers::job_events),
+        )
         .route("/deployments", axum::routing::get(handlers::deployments))
+        .route(
+            "/deployments/{name}",
+            axum::routing::get(handlers::deployment_details),
+        )
+        .route(
+            "/deployments/{name}/redeploy",
+            axum::routing::post(handlers::redeploy),
+        )
+        .route(
+            "/deployments/{name}/compose/{operation}",
+            axum::routing::post(handlers::compose_operation),
+        )
+        .route(
+            "/deployments/{name}/refs",
+            axum::routing::get(handlers::deployment_refs),
+        )
+        .route(
+            "/deployments/{name}/fetch",
+            axum::routing::post(handlers::fetch),
+        )
+        .route(
+            "/deployments/{name}/checkout",
+            axum::routing::post(handlers::checkout),
+        )
+        .route(
+            "/deployments/{name}/history",
+            axum::routing::get(handlers::deploy_history),
+        )
+        .route(
+            "/deployments/{name}/rollback/{sha}",
+            axum::routing::post(handlers::rollback),
+        )
+        .route(
+            "/deployments/{name}/containers",
+            axum::routing::get(handlers::deployment_containers),
+        )
+        .route(
+            "/deployments/{name}/tokens",
+            axum::routing::get(handlers::api_tokens).post(handlers::create_api_token),
+        )
+        .route(
+            "/deployments/{name}/tokens/{id}/revoke",
+            axum::routing::post(handlers::revoke_api_token),
+        )
+        .route("/deployments/{name}/env", axum::routing::get(handlers::env))
+        .route(
+            "/deployments/{name}/env/set",
+            axum::routing::post(handlers::set_env),
+        )
+        .route(
+            "/deployments/{name}/env/unset",
+            axum::routing::post(handlers::unset_env),
+        )
+        .route(
+            "/deployments/{name}/stats",
+            axum::routing::get(handlers::deployment_stats),
+        )
+        .route(
+            "/deployments/{name}/stats/stream",
+            axum::routing::get(handlers::deployment_stats_stream),
+        )
+        .route(
+            "/deployments/{name}/containers/{container}/logs",
+            axum::routing::get(handlers::container_logs),
+        )
+        .route(
+            "/deployments/{name}/containers/{container}/shell",
+            axum::routing::get(handlers::container_shell),
+        )
+        .route(
+            "/deployments/{name}/containers/{container}/{action}",
+            axum::routing::post(handlers::container_action),
+        )
+        .route("/audit", axum::routing::get(handlers::audit))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::audit_middleware,
+        ))
         .route_layer(axum::middleware::from_fn_with_state(
             state.clone(),
             middleware::verify_jwt_middleware,
         ))
+        .route("/oauth/start", axum::routing::get(handlers::oauth_start))
         .route("/oauth", axum::routing::post(handlers::oauth))
+        .route(
+            "/token/refresh",
+            axum::routing::post(handlers::refresh_token),
+        )
+        .route(
+            "/webhooks/github",
+            axum::routing::post(handlers::github_webhook),
+        )
         .route("/healthcheck", axum::routing::get(handlers::healthcheck))
         .with_state(state)
         .layer(
@@ -54,7 +200,7 @@ pub fn get_router(env_vars: &EnvVars, docker: Arc<Docker>) -> axum::Router {
                         })
                         .collect::<Vec<HeaderValue>>(),
                 ),
-        )
+        ))
 }
 
 #[derive(Clone)]
@@ -62,6 +208,37 @@ pub fn get_router(env_vars: &EnvVars, docker: Arc<Docker>) -> axum::Router {
 struct RouterState {
     pub env_vars: EnvVars,
     pub docker: Arc<Docker>,
+    pub github: Arc<dyn GithubApi>,
+    pub audit_log: Arc<AuditLog>,
+    /// Cached Github role lookups, shared by all the requests
+    pub role_cache: Arc<RoleCache>,
+    /// Revoked JWTs
+    pub token_denylist: Arc<TokenDenylist>,
+    /// Login sessions and their refresh tokens
+    pub sessions: Arc<SessionStore>,
+    /// Users whose organization membership was checked recently, by username
+    pub membership_checks: Arc<TtlCache<String, ()>>,
+    /// Key signing the OAuth states, generated on startup
+    pub oauth_state_key: Hmac<Sha256>,
+    /// Nonces of the OAuth states that were already used, to reject replays
+    pub used_oauth_states: Arc<TtlCache<String, ()>>,
+    /// Personal API tokens
+    pub api_tokens: Arc<ApiTokenStore>,
+    /// Locks serializing the `.env` file edits of each deployment
+    pub env_file_locks: Arc<EnvFileLocks>,
+    /// Background jobs (eg: redeploys)
+    pub jobs: Arc<JobQueue>,
+    /// Redeploys and rollbacks of the deployments
+    pub deploy_history: Arc<DeployHistory>,
+}
+
+impl RouterState {
+    /// Revokes all the JWTs, login sessions and API tokens of a user
+    pub async fn revoke_user(&self, username: &str) -> Res<()> {
+        self.token_denylist.revoke_user(username).await?;
+        self.sessions.revoke_user(username).await?;
+        self.api_tokens.revoke_user(username).await
+    }
 }
 
 #[derive(Clone, Copy)]
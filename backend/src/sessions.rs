@@ -0,0 +1,274 @@
+//! Server-side store of login sessions and their refresh tokens
+//!
+//! Logging in starts a session (a family of refresh tokens). Each refresh token can only be used once: using it returns a new refresh token (rotation) and extends the session, up to its maximum lifetime. Using an already rotated refresh token means it was stolen, and the whole session is revoked.
+//!
+//! Only SHA-256 hashes of the refresh tokens are stored, in a JSON file.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::utils::{self, Res};
+
+/// Number of rotated refresh tokens remembered per session to detect their reuse
+const MAX_ROTATED_TOKENS: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+/// A login session
+struct Session {
+    username: String,
+    /// Hash of the current (unused) refresh token
+    refresh_token_hash: String,
+    /// Hashes of the last rotated (already used) refresh tokens, oldest first
+    rotated_token_hashes: VecDeque<String>,
+    /// Time the session was started at (UNIX timestamp in seconds)
+    created_at: i64,
+    /// Time the session expires at if it is not refreshed (UNIX timestamp in seconds)
+    expiration: i64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+/// The contents of the sessions file
+struct SessionsData {
+    /// Sessions by id
+    sessions: HashMap<String, Session>,
+}
+
+/// The outcome of using a refresh token
+pub enum Refresh {
+    /// The refresh token was valid and has been rotated
+    Rotated {
+        username: String,
+        session_id: String,
+        /// The new refresh token
+        refresh_token: String,
+    },
+    /// The refresh token was already used, the session has been revoked
+    Reused { session_id: String },
+    /// The refresh token is unknown or its session has expired
+    Invalid,
+}
+
+/// Generates a random refresh token
+fn generate_refresh_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// The sessions store
+pub struct SessionStore {
+    path: PathBuf,
+    /// Lifetime of a session after it was last refreshed (in seconds)
+    lifetime: i64,
+    /// Maximum lifetime of a session since it was started (in seconds), refreshing does not extend it past this
+    max_lifetime: i64,
+    data: Mutex<SessionsData>,
+}
+
+impl SessionStore {
+    /// Loads the sessions stored in the given (JSON) file. The file is created on the first login.
+    pub fn load(path: PathBuf, lifetime: i64, max_lifetime: i64) -> Res<Self> {
+        let data = utils::read_json_store(&path)?;
+
+        Ok(Self {
+            path,
+            lifetime,
+            max_lifetime,
+            data: Mutex::new(data),
+        })
+    }
+
+    /// Starts a session for a user. Returns the session id and its first refresh token.
+    pub async fn create(&self, username: &str) -> Res<(String, String)> {
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let refresh_token = generate_refresh_token();
+        let now = chrono::Utc::now().timestamp();
+
+        let mut data = self.data.lock().await;
+        data.sessions.insert(
+            session_id.clone(),
+            Session {
+                username: username.to_string(),
+                refresh_token_hash: utils::hash_secret(&refresh_token),
+                rotated_token_hashes: VecDeque::new(),
+                created_at: now,
+                expiration: now + self.lifetime.min(self.max_lifetime),
+            },
+        );
+        self.save(&mut data).await?;
+
+        Ok((session_id, refresh_token))
+    }
+
+    /// Uses a refresh token: rotates it if it is valid, revokes its session if it was already used
+    pub async fn refresh(&self, refresh_token: &str) -> Res<Refresh> {
+        let hash = utils::hash_secret(refresh_token);
+        let now = chrono::Utc::now().timestamp();
+
+        let mut data = self.data.lock().await;
+
+        if let Some(session_id) = data
+            .sessions
+            .iter()
+            .find(|(_, session)| session.rotated_token_hashes.contains(&hash))
+            .map(|(session_id, _)| session_id.clone())
+        {
+            tracing::warn!("Refresh token reused, revoking session {session_id}.");
+
+            data.sessions.remove(&session_id);
+            self.save(&mut data).await?;
+
+            return Ok(Refresh::Reused { session_id });
+        }
+
+        let Some((session_id, session)) = data
+            .sessions
+            .iter_mut()
+            .find(|(_, session)| session.refresh_token_hash == hash && session.expiration > now)
+        else {
+            return Ok(Refresh::Invalid);
+        };
+
+        let new_refresh_token = generate_refresh_token();
+        session.refresh_token_hash = utils::hash_secret(&new_refresh_token);
+        session.expiration = (now + self.lifetime).min(session.created_at + self.max_lifetime);
+
+        session.rotated_token_hashes.push_back(hash);
+        if session.rotated_token_hashes.len() > MAX_ROTATED_TOKENS {
+            session.rotated_token_hashes.pop_front();
+        }
+
+        let refresh = Refresh::Rotated {
+            username: session.username.clone(),
+            session_id: session_id.clone(),
+            refresh_token: new_refresh_token,
+        };
+        self.save(&mut data).await?;
+
+        Ok(refresh)
+    }
+
+    /// Ends a session
+    pub async fn revoke(&self, session_id: &str) -> Res<()> {
+        let mut data = self.data.lock().await;
+        data.sessions.remove(session_id);
+
+        self.save(&mut data).await
+    }
+
+    /// Ends all the sessions of a user
+    pub async fn revoke_user(&self, username: &str) -> Res<()> {
+        let mut data = self.data.lock().await;
+        data.sessions
+            .retain(|_, session| session.username != username);
+
+        self.save(&mut data).await
+    }
+
+    /// Prunes the expired sessions (and their rotated tokens) and writes the sessions to the file
+    async fn save(&self, data: &mut SessionsData) -> Res<()> {
+        let now = chrono::Utc::now().timestamp();
+        // Reusing a rotated token of an ended session is rejected as an invalid token anyway
+        data.sessions.retain(|_, session| session.expiration > now);
+
+        utils::write_json_atomic(&self.path, data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refresh_tokens_are_rotated_and_reuse_revokes_the_session() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::load(data_dir.path().join("sessions.json"), 60, 3600).unwrap();
+
+        let (session_id, first) = store.create("alice").await.unwrap();
+        let (_, other) = store.create("alice").await.unwrap();
+
+        let Refresh::Rotated {
+            username,
+            session_id: refreshed_session_id,
+            refresh_token: second,
+        } = store.refresh(&first).await.unwrap()
+        else {
+            panic!("The refresh token was not rotated.");
+        };
+        assert_eq!(username, "alice");
+        assert_eq!(refreshed_session_id, session_id);
+
+        assert!(matches!(
+            store.refresh(&first).await.unwrap(),
+            Refresh::Reused { session_id: reused_session_id } if reused_session_id == session_id
+        ));
+        // The whole session is revoked
+        assert!(matches!(
+            store.refresh(&second).await.unwrap(),
+            Refresh::Invalid
+        ));
+
+        // Other sessions are not affected, and the store is persisted
+        let store = SessionStore::load(data_dir.path().join("sessions.json"), 60, 3600).unwrap();
+        assert!(matches!(
+            store.refresh(&other).await.unwrap(),
+            Refresh::Rotated { .. }
+        ));
+        assert!(matches!(
+            store.refresh("unknown").await.unwrap(),
+            Refresh::Invalid
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_not_extended_past_their_maximum_lifetime() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::load(data_dir.path().join("sessions.json"), 60, 3600).unwrap();
+
+        let (session_id, refresh_token) = store.create("alice").await.unwrap();
+        // Started long ago, the next refresh would extend the session past its maximum lifetime
+        store
+            .data
+            .lock()
+            .await
+            .sessions
+            .get_mut(&session_id)
+            .unwrap()
+            .created_at -= 3590;
+
+        assert!(matches!(
+            store.refresh(&refresh_token).await.unwrap(),
+            Refresh::Rotated { .. }
+        ));
+        let expiration = store.data.lock().await.sessions[&session_id].expiration;
+        assert!(expiration <= chrono::Utc::now().timestamp() + 10);
+    }
+
+    #[tokio::test]
+    async fn rotated_tokens_history_is_capped() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::load(data_dir.path().join("sessions.json"), 60, 3600).unwrap();
+
+        let (session_id, mut refresh_token) = store.create("alice").await.unwrap();
+        for _ in 0..MAX_ROTATED_TOKENS + 10 {
+            let Refresh::Rotated {
+                refresh_token: next,
+                ..
+            } = store.refresh(&refresh_token).await.unwrap()
+            else {
+                panic!("The refresh token was not rotated.");
+            };
+            refresh_token = next;
+        }
+
+        let rotated = store.data.lock().await.sessions[&session_id]
+            .rotated_token_hashes
+            .len();
+        assert_eq!(rotated, MAX_ROTATED_TOKENS);
+    }
+}
@@ -0,0 +1,186 @@
+//! Interactive shell sessions in deployment containers over WebSockets
+//!
+//! Browsers cannot set the `Authorization` header of WebSocket connections, so clients may instead send their token as a subprotocol prefixed with [`WEBSOCKET_TOKEN_PROTOCOL_PREFIX`], along with the [`SHELL_PROTOCOL`] subprotocol (eg: `new WebSocket(url, ["maintos.shell", "maintos.bearer.<token>"])`). The server selects the [`SHELL_PROTOCOL`] subprotocol.
+//!
+//! The client sends the shell input either as binary messages (raw bytes) or as [`ShellMessage::Input`] JSON text messages. The terminal can be resized by sending a [`ShellMessage::Resize`] JSON text message. The shell output (stdout and stderr combined, as the session uses a TTY) is sent to the client as binary messages.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
+use bollard::{
+    Docker, exec::StartExecResults, models::ExecConfig, query_parameters::ResizeExecOptionsBuilder,
+};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::utils::Res;
+
+/// The subprotocol selected by the server for shell sessions
+pub const SHELL_PROTOCOL: &str = "maintos.shell";
+/// Prefix of the subprotocol carrying the auth token of a WebSocket connection
+pub const WEBSOCKET_TOKEN_PROTOCOL_PREFIX: &str = "maintos.bearer.";
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// A control message sent by the client as a JSON text message
+pub enum ShellMessage {
+    /// Input to be written to the shell's stdin
+    Input { data: String },
+    /// Resize the TTY of the shell
+    Resize { cols: u16, rows: u16 },
+}
+
+/// Runs an interactive shell session in a container, piping the WebSocket to the shell's stdin and stdout until either side closes or the session times out
+pub async fn run_shell_session(
+    docker: &Docker,
+    container_id: &str,
+    cmd: Vec<String>,
+    timeout: Duration,
+    mut socket: WebSocket,
+) {
+    let result = match create_shell_exec(docker, container_id, cmd).await {
+        Ok(exec_id) => {
+            let result = tokio::time::timeout(
+                timeout,
+                pipe_shell_session(docker, container_id, &exec_id, &mut socket),
+            )
+            .await;
+
+            // The shell keeps running if the session timed out or the client disconnected
+            if let Err(err) = kill_exec(docker, &exec_id).await {
+                tracing::error!(
+                    "Error killing shell session {exec_id} in container {container_id}: {err}"
+                );
+            }
+
+            result
+        }
+        Err(err) => Ok(Err(err)),
+    };
+
+    let (code, reason) = match result {
+        Ok(Ok(())) => (close_code::NORMAL, "Shell session ended."),
+        Ok(Err(err)) => {
+            tracing::error!("Error in shell session in container {container_id}: {err}");
+            (close_code::ERROR, "Shell session error.")
+        }
+        Err(_) => (close_code::NORMAL, "Shell session timed out."),
+    };
+
+    // The client may have already closed the connection
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })))
+        .await;
+}
+
+/// Creates an exec session (with a TTY) in a container, returns its id
+async fn create_shell_exec(docker: &Docker, container_id: &str, cmd: Vec<String>) -> Res<String> {
+    let exec = docker
+        .create_exec(
+            container_id,
+            ExecConfig {
+                attach_stdin: Some(true),
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                tty: Some(true),
+                cmd: Some(cmd),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    Ok(exec.id)
+}
+
+/// Kills the process of an exec session if it is still running
+///
+/// Docker cannot stop exec sessions, so the process is killed by its PID, which requires the backend to share the host's PID namespace.
+async fn kill_exec(docker: &Docker, exec_id: &str) -> Res<()> {
+    let exec = docker.inspect_exec(exec_id).await?;
+    if exec.running != Some(true) {
+        return Ok(());
+    }
+
+    let pid = exec
+        .pid
+        .and_then(|pid| libc::pid_t::try_from(pid).ok())
+        .filter(|pid| *pid > 0)
+        .ok_or(anyhow!("Error: Exec session {exec_id} has no PID."))?;
+
+    // SAFETY: `kill` only sends a signal, it has no memory safety requirements
+    if unsafe { libc::kill(pid, libc::SIGKILL) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Starts an exec session and pipes it to the WebSocket, until the shell exits or the client disconnects
+async fn pipe_shell_session(
+    docker: &Docker,
+    container_id: &str,
+    exec_id: &str,
+    socket: &mut WebSocket,
+) -> Res<()> {
+    let StartExecResults::Attached {
+        mut output,
+        mut input,
+    } = docker.start_exec(exec_id, None).await?
+    else {
+        return Err(anyhow!(
+            "Error: Exec session {exec_id} was started detached."
+        ));
+    };
+
+    loop {
+        tokio::select! {
+            chunk = output.next() => {
+                let Some(chunk) = chunk else {
+                    // The shell exited
+                    return Ok(());
+                };
+
+                socket.send(Message::Binary(chunk?.into_bytes())).await?;
+            }
+            message = socket.recv() => {
+                match message {
+                    Some(Ok(Message::Binary(data))) => input.write_all(&data).await?,
+                    Some(Ok(Message::Text(text))) => {
+                        // A malformed message from the client does not end the session
+                        let message = match serde_json::from_str::<ShellMessage>(&text) {
+                            Ok(message) => message,
+                            Err(err) => {
+                                tracing::warn!("Ignoring invalid message in shell session in container {container_id}: {err}");
+                                continue;
+                            }
+                        };
+
+                        match message {
+                            ShellMessage::Input { data } => input.write_all(data.as_bytes()).await?,
+                            ShellMessage::Resize { cols, rows } => {
+                                docker
+                                    .resize_exec(
+                                        exec_id,
+                                        ResizeExecOptionsBuilder::new()
+                                            .w(cols.into())
+                                            .h(rows.into())
+                                            .build(),
+                                    )
+                                    .await?
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    // Pings are answered automatically
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                    Some(Err(err)) => return Err(err.into()),
+                }
+            }
+        }
+    }
+}
@@ -1,32 +1,153 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path};
 use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::anyhow;
+use futures_util::{StreamExt, TryStreamExt, stream};
 use git2::Repository;
 use reqwest::Url;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 
-use crate::{env::EnvVars, github};
+use crate::{
+    cache::TtlCache,
+    env::EnvVars,
+    github::GithubApi,
+    permissions::{Action, Role},
+};
 
 pub(crate) type Res<T> = Result<T, anyhow::Error>;
 
+/// Hashes a secret token (SHA-256, hex encoded) to store it
+pub fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Reads a value from a JSON file. Returns the default value if the file does not exist, and an error if it is corrupt.
+///
+/// Used for the stores that must not be reset (eg: the token denylist, so that revoked tokens stay revoked).
+pub fn read_json_file<T: DeserializeOwned + Default>(path: &Path) -> Res<T> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|err| anyhow!("Error parsing {}: {err}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads a value from a JSON file. Returns the default value if the file does not exist.
+///
+/// A corrupt file is moved aside (to `<path>.corrupt-<timestamp>`) with a logged error and the default value is returned, so that the server can still start. Only used for the stores that are safe to reset (eg: the jobs or the login sessions), see [`read_json_file`] for the others.
+pub fn read_json_store<T: DeserializeOwned + Default>(path: &Path) -> Res<T> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(err) => return Err(err.into()),
+    };
+
+    match serde_json::from_str(&content) {
+        Ok(value) => Ok(value),
+        Err(err) => {
+            let mut corrupt_path = path.as_os_str().to_owned();
+            corrupt_path.push(format!(".corrupt-{}", chrono::Utc::now().timestamp()));
+            std::fs::rename(path, &corrupt_path)?;
+
+            tracing::error!(
+                "Error parsing {}, moved it to {} and started empty: {err}",
+                path.display(),
+                Path::new(&corrupt_path).display()
+            );
+
+            Ok(T::default())
+        }
+    }
+}
+
+/// Atomically writes a value to a JSON file (through a temporary file, readable only by the owner), creating the parent directory if needed
+pub async fn write_json_atomic<T: Serialize>(path: &Path, value: &T) -> Res<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let temp_path = path.with_extension("json.tmp");
+    let mut temp_file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .await?;
+    temp_file
+        .write_all(serde_json::to_string(value)?.as_bytes())
+        .await?;
+    temp_file.sync_all().await?;
+    // The mode only applies to new files, a leftover temp file keeps its permissions
+    fs::set_permissions(&temp_path, std::fs::Permissions::from_mode(0o600)).await?;
+    fs::rename(&temp_path, path).await?;
+
+    Ok(())
+}
+
 #[derive(Deserialize, Serialize)]
 /// All the information for a repository
 pub struct Deployment {
-    name: String,
+    pub name: String,
     repo_url: String,
     repo_owner: String,
     repo_name: String,
 }
 
-/// Get a list of deployments
-pub async fn get_deployments(env_vars: &EnvVars, username: &str) -> Res<Vec<Deployment>> {
-    let deployments_dir = &env_vars.deployments_dir;
+/// Parses the deployment information from a deployment's repository
+fn parse_deployment(name: String, repo: &Repository) -> Res<Deployment> {
+    // The configured URL, not rewritten by `url.<base>.insteadOf` (eg: to an SSH URL)
+    let repo_url = repo
+        .config()?
+        .get_string("remote.origin.url")
+        .map_err(|_| anyhow!("Error: Origin remote URL not found for repo {name}."))?;
 
-    let mut deployments = Vec::new();
+    let parsed_url = Url::from_str(&repo_url)?;
+    let mut url_paths = parsed_url
+        .path_segments()
+        .ok_or(anyhow!("Error parsing repository remote URL."))?;
+
+    let repo_owner = url_paths
+        .next()
+        .ok_or(anyhow!(
+            "Error parsing repository remote URL: Repo owner not found."
+        ))?
+        .to_string();
+    let repo_name = url_paths
+        .next()
+        .ok_or(anyhow!(
+            "Error parsing repository remote URL: Repo name not found."
+        ))?
+        .to_string();
 
-    // To be reused for collaborator permission checking requests
-    let client = reqwest::Client::new();
+    Ok(Deployment {
+        name,
+        repo_url,
+        repo_owner,
+        repo_name,
+    })
+}
+
+impl Deployment {
+    /// Returns whether the deployment is a clone of the given Github repository
+    pub fn is_repo(&self, owner: &str, name: &str) -> bool {
+        self.repo_owner.eq_ignore_ascii_case(owner)
+            && self
+                .repo_name
+                .trim_end_matches(".git")
+                .eq_ignore_ascii_case(name)
+    }
+}
+
+/// Returns all the deployments (git repositories) in the deployments directory, regardless of users' roles
+pub async fn list_deployments(deployments_dir: &Path) -> Res<Vec<Deployment>> {
+    let mut deployments = Vec::new();
 
     let mut dir_iter = fs::read_dir(deployments_dir).await?;
     while let Some(path) = dir_iter.next_entry().await? {
@@ -38,57 +159,334 @@ pub async fn get_deployments(env_vars: &EnvVars, username: &str) -> Res<Vec<Depl
                 .into_string()
                 .map_err(|err| anyhow!("{}", err.display()))?;
 
-            let repo_url = repo
-                .find_remote("origin")?
-                .url()
-                .ok_or(anyhow!(
-                    "Error: Origin remote URL not found for repo {name}."
-                ))?
-                .to_string();
-
-            let parsed_url = Url::from_str(&repo_url)?;
-            let mut url_paths = parsed_url
-                .path_segments()
-                .ok_or(anyhow!("Error parsing repository remote URL."))?;
-
-            let repo_owner = url_paths
-                .next()
-                .ok_or(anyhow!(
-                    "Error parsing repository remote URL: Repo owner not found."
-                ))?
-                .to_string();
-            let repo_name = url_paths
-                .next()
-                .ok_or(anyhow!(
-                    "Error parsing repository remote URL: Repo name not found."
-                ))?
-                .to_string();
-
-            // Only include repositories owned by the organization
-            if repo_owner == env_vars.gh_org_name {
-                let collab_role = github::get_collaborator_role(
-                    &client,
-                    &env_vars.gh_org_admin_token,
-                    &repo_owner,
-                    &repo_name,
-                    username,
-                )
-                .await?;
+            deployments.push(parse_deployment(name, &repo)?);
+        }
+    }
+
+    Ok(deployments)
+}
+
+/// Maximum number of concurrent Github role lookups when listing deployments
+const ROLE_LOOKUP_CONCURRENCY: usize = 8;
+
+/// Cache of the Github lookups used to compute users' roles on deployments, to avoid hitting the Github API rate limit
+pub struct RoleCache {
+    /// Collaborator role names, keyed by (username, repo owner, repo name). `None` if the user is not a collaborator.
+    collaborator_roles: TtlCache<(String, String, String), Option<String>>,
+    /// Team memberships, keyed by (username, team slug)
+    team_memberships: TtlCache<(String, String), bool>,
+    /// Organization memberships, keyed by username
+    org_memberships: TtlCache<String, bool>,
+}
+
+impl RoleCache {
+    /// Creates an empty cache whose entries expire after `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            collaborator_roles: TtlCache::new(ttl),
+            team_memberships: TtlCache::new(ttl),
+            org_memberships: TtlCache::new(ttl),
+        }
+    }
+}
 
-                // `None` means the user is not a collaborator
-                if let Some(role) = collab_role.as_deref()
-                    && (role == "maintain" || role == "admin")
-                {
-                    deployments.push(Deployment {
-                        name,
-                        repo_url,
-                        repo_owner,
-                        repo_name,
-                    });
-                }
+/// Returns the highest role granted to a user by the organization teams they are a member of (see [`crate::permissions::TeamRole`])
+async fn get_team_role(
+    github: &dyn GithubApi,
+    env_vars: &EnvVars,
+    cache: &RoleCache,
+    username: &str,
+) -> Res<Option<Role>> {
+    let mut role = None;
+
+    for team_role in env_vars.get_team_roles()? {
+        if role.is_some_and(|role| team_role.role <= role) {
+            continue;
+        }
+
+        let key = (username.to_string(), team_role.team.clone());
+        let is_member = match cache.team_memberships.get(&key) {
+            Some(is_member) => is_member,
+            None => {
+                let is_member = github
+                    .check_team_membership(&env_vars.gh_org_name, &team_role.team, username)
+                    .await?;
+                cache.team_memberships.insert(key, is_member);
+                is_member
             }
+        };
+
+        if is_member {
+            role = Some(team_role.role);
         }
     }
 
+    Ok(role)
+}
+
+/// Checks whether a user is (still) a member of the organization
+pub async fn check_org_membership(
+    github: &dyn GithubApi,
+    env_vars: &EnvVars,
+    cache: &RoleCache,
+    username: &str,
+) -> Res<bool> {
+    if let Some(is_member) = cache.org_memberships.get(&username.to_string()) {
+        return Ok(is_member);
+    }
+
+    let is_member = github
+        .check_membership(&env_vars.gh_org_name, username)
+        .await?;
+    cache
+        .org_memberships
+        .insert(username.to_string(), is_member);
+
+    Ok(is_member)
+}
+
+/// Checks whether a user is a maintos admin, i.e., a member of a team with the admin role on all the deployments
+pub async fn is_admin(
+    github: &dyn GithubApi,
+    env_vars: &EnvVars,
+    cache: &RoleCache,
+    username: &str,
+) -> Res<bool> {
+    Ok(get_team_role(github, env_vars, cache, username).await? == Some(Role::Admin))
+}
+
+/// Returns a user's role on a deployment, i.e., the highest of the role mapped from their collaborator role on the repository and their team role (see [`get_team_role`])
+///
+/// Returns `None` if the repository is not owned by the organization or the user has no role on it.
+async fn get_deployment_role(
+    github: &dyn GithubApi,
+    env_vars: &EnvVars,
+    cache: &RoleCache,
+    deployment: &Deployment,
+    team_role: Option<Role>,
+    username: &str,
+) -> Res<Option<Role>> {
+    // Only include repositories owned by the organization
+    if deployment.repo_owner != env_vars.gh_org_name {
+        return Ok(None);
+    }
+
+    let key = (
+        username.to_string(),
+        deployment.repo_owner.clone(),
+        deployment.repo_name.clone(),
+    );
+    let collab_role = match cache.collaborator_roles.get(&key) {
+        Some(collab_role) => collab_role,
+        None => {
+            let collab_role = github
+                .get_collaborator_role(&deployment.repo_owner, &deployment.repo_name, username)
+                .await?;
+            cache.collaborator_roles.insert(key, collab_role.clone());
+            collab_role
+        }
+    };
+
+    // `None` means the user is not a collaborator
+    let role = collab_role
+        .as_deref()
+        .and_then(Role::from_collaborator_role);
+
+    Ok(role.max(team_role))
+}
+
+/// Get a list of deployments
+pub async fn get_deployments(
+    env_vars: &EnvVars,
+    github: &dyn GithubApi,
+    cache: &RoleCache,
+    username: &str,
+) -> Res<Vec<Deployment>> {
+    let candidates = list_deployments(&env_vars.deployments_dir).await?;
+
+    // The team role is the same for all the deployments
+    let team_role = get_team_role(github, env_vars, cache, username).await?;
+
+    let deployments = stream::iter(candidates)
+        .map(|deployment| async move {
+            let role =
+                get_deployment_role(github, env_vars, cache, &deployment, team_role, username)
+                    .await?;
+            Ok::<_, anyhow::Error>((deployment, role))
+        })
+        .buffered(ROLE_LOOKUP_CONCURRENCY)
+        .try_filter_map(|(deployment, role)| async move {
+            Ok(role
+                .is_some_and(|role| Action::View.is_allowed(role))
+                .then_some(deployment))
+        })
+        .try_collect()
+        .await?;
+
     Ok(deployments)
 }
+
+/// Get a single deployment, its opened repository and the user's role on it by the deployment name
+///
+/// Returns `None` if the deployment does not exist or the user has no role on it
+pub async fn get_deployment(
+    env_vars: &EnvVars,
+    github: &dyn GithubApi,
+    cache: &RoleCache,
+    username: &str,
+    name: &str,
+) -> Res<Option<(Deployment, Repository, Role)>> {
+    // The name must be a single directory inside the deployments directory
+    let mut components = Path::new(name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return Ok(None);
+    }
+
+    let path = env_vars.deployments_dir.join(name);
+    if !fs::metadata(&path).await.is_ok_and(|meta| meta.is_dir()) {
+        return Ok(None);
+    }
+
+    let Ok(repo) = Repository::open(&path) else {
+        return Ok(None);
+    };
+
+    let deployment = parse_deployment(name.to_string(), &repo)?;
+
+    let team_role = get_team_role(github, env_vars, cache, username).await?;
+    Ok(
+        get_deployment_role(github, env_vars, cache, &deployment, team_role, username)
+            .await?
+            .map(|role| (deployment, repo, role)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::github::fake::FakeGithub;
+
+    fn env_vars() -> EnvVars {
+        EnvVars::parse_from([
+            "maintos",
+            "client-id",
+            "admin-token",
+            "jwt-secret",
+            "client-secret",
+            "metakgp",
+            "devops:admin, members:viewer",
+        ])
+    }
+
+    fn deployment(owner: &str, repo: &str) -> Deployment {
+        Deployment {
+            name: repo.into(),
+            repo_url: format!("https://github.com/{owner}/{repo}"),
+            repo_owner: owner.into(),
+            repo_name: repo.into(),
+        }
+    }
+
+    async fn role(github: &FakeGithub, cache: &RoleCache, deployment: &Deployment) -> Option<Role> {
+        let env_vars = env_vars();
+        let team_role = get_team_role(github, &env_vars, cache, "alice")
+            .await
+            .unwrap();
+
+        get_deployment_role(github, &env_vars, cache, deployment, team_role, "alice")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn role_is_highest_of_collaborator_and_team_roles() {
+        let cache = RoleCache::new(Duration::from_secs(60));
+        let github = FakeGithub::default()
+            .with_collaborator("metakgp", "gyft", "alice", "maintain")
+            .with_team_member("metakgp", "members", "alice");
+
+        assert_eq!(
+            role(&github, &cache, &deployment("metakgp", "gyft")).await,
+            Some(Role::Operator)
+        );
+        assert_eq!(
+            role(&github, &cache, &deployment("metakgp", "naarad")).await,
+            Some(Role::Viewer)
+        );
+
+        let github = FakeGithub::default()
+            .with_collaborator("metakgp", "gyft", "alice", "write")
+            .with_team_member("metakgp", "devops", "alice");
+
+        assert_eq!(
+            role(&github, &cache, &deployment("metakgp", "gyft")).await,
+            Some(Role::Operator)
+        );
+    }
+
+    #[tokio::test]
+    async fn no_role_outside_org_or_without_access() {
+        let cache = RoleCache::new(Duration::from_secs(60));
+        let github = FakeGithub::default()
+            .with_collaborator("someone", "gyft", "alice", "admin")
+            .with_collaborator("metakgp", "naarad", "alice", "write");
+
+        assert_eq!(
+            role(&github, &cache, &deployment("someone", "gyft")).await,
+            None
+        );
+        assert_eq!(
+            role(&github, &cache, &deployment("metakgp", "naarad")).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn role_lookups_are_cached() {
+        let github = FakeGithub::default().with_collaborator("metakgp", "gyft", "alice", "admin");
+
+        let cache = RoleCache::new(Duration::from_secs(60));
+        role(&github, &cache, &deployment("metakgp", "gyft")).await;
+        let requests = github.requests();
+        role(&github, &cache, &deployment("metakgp", "gyft")).await;
+        assert_eq!(github.requests(), requests);
+
+        let cache = RoleCache::new(Duration::ZERO);
+        role(&github, &cache, &deployment("metakgp", "gyft")).await;
+        let requests = github.requests();
+        role(&github, &cache, &deployment("metakgp", "gyft")).await;
+        assert!(github.requests() > requests);
+    }
+
+    #[tokio::test]
+    async fn json_stores_are_private_and_corrupt_ones_are_moved_aside() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let path = data_dir.path().join("store.json");
+
+        write_json_atomic(&path, &vec!["secret"]).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(read_json_store::<Vec<String>>(&path).unwrap(), ["secret"]);
+
+        std::fs::write(&path, "[\"truncated").unwrap();
+        assert!(read_json_store::<Vec<String>>(&path).unwrap().is_empty());
+        assert!(!path.exists());
+        let moved: Vec<_> = std::fs::read_dir(data_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert!(moved[0].starts_with("store.json.corrupt-"), "{moved:?}");
+
+        // Stores that must not be reset fail instead
+        std::fs::write(&path, "[\"truncated").unwrap();
+        assert!(read_json_file::<Vec<String>>(&path).is_err());
+        assert!(path.exists());
+    }
+}
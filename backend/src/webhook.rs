@@ -0,0 +1,125 @@
+//! Github push webhooks, to redeploy deployments automatically
+//!
+//! Deployments opt in with the `maintos.autoDeploy` git config of their repository (see [`git::is_auto_deploy_enabled`]). A push to the branch checked out in such a deployment pulls and redeploys it.
+
+use std::path::Path;
+
+use git2::Repository;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{
+    git,
+    utils::{self, Res},
+};
+
+/// Header containing the HMAC-SHA256 signature of the payload (`sha256=<hex digest>`)
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+/// Header containing the name of the event (eg: `push`)
+pub const EVENT_HEADER: &str = "X-GitHub-Event";
+
+/// Verifies the signature of a webhook payload, signed with the webhook secret
+pub fn verify_signature(secret: &str, payload: &[u8], signature: &str) -> bool {
+    let Some(signature) = signature
+        .strip_prefix("sha256=")
+        .and_then(|digest| hex::decode(digest).ok())
+    else {
+        return false;
+    };
+
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(payload);
+
+    // Constant time comparison
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[derive(Deserialize)]
+/// The repository of a push event
+pub struct PushRepository {
+    /// Full name of the repository (`owner/name`)
+    pub full_name: String,
+}
+
+#[derive(Deserialize)]
+/// The user who triggered a push event
+pub struct PushSender {
+    pub login: String,
+}
+
+#[derive(Deserialize)]
+/// The (relevant part of the) payload of a push event
+pub struct PushEvent {
+    /// The pushed ref (eg: `refs/heads/main`)
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    /// SHA of the latest commit after the push
+    pub after: String,
+    /// Whether the push deleted the ref
+    #[serde(default)]
+    pub deleted: bool,
+    pub repository: PushRepository,
+    pub sender: PushSender,
+}
+
+impl PushEvent {
+    /// Returns the name of the pushed branch (`None` if a tag was pushed)
+    pub fn branch(&self) -> Option<&str> {
+        self.git_ref.strip_prefix("refs/heads/")
+    }
+}
+
+/// Returns the names of the deployments to be redeployed after a push: the deployments of the pushed repository that opted in to auto-deploy and have the pushed branch checked out
+pub async fn get_auto_deploy_targets(
+    deployments_dir: &Path,
+    event: &PushEvent,
+) -> Res<Vec<String>> {
+    let (Some(branch), false) = (event.branch(), event.deleted) else {
+        return Ok(Vec::new());
+    };
+    let Some((owner, name)) = event.repository.full_name.split_once('/') else {
+        return Ok(Vec::new());
+    };
+
+    let mut targets = Vec::new();
+    for deployment in utils::list_deployments(deployments_dir).await? {
+        if !deployment.is_repo(owner, name) {
+            continue;
+        }
+
+        let repo = Repository::open(deployments_dir.join(&deployment.name))?;
+        if git::is_auto_deploy_enabled(&repo)?
+            && git::get_branch(&repo)?.is_some_and(|checked_out| checked_out == branch)
+        {
+            targets.push(deployment.name);
+        }
+    }
+
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_are_verified() {
+        // Example from the Github webhooks documentation
+        let secret = "It's a Secret to Everybody";
+        let payload = b"Hello, World!";
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+        assert!(verify_signature(secret, payload, signature));
+        assert!(!verify_signature("wrong secret", payload, signature));
+        assert!(!verify_signature(secret, b"Hello, World?", signature));
+        assert!(!verify_signature(
+            secret,
+            payload,
+            signature.trim_start_matches("sha256=")
+        ));
+        assert!(!verify_signature(secret, payload, "sha256=not-hex"));
+    }
+}
@@ -0,0 +1,404 @@
+//! Test harness running the maintos router against a mock Github API, a fake docker daemon and a temporary deployments directory
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use axum::{Json, extract::Request};
+use bollard::Docker;
+use clap::Parser;
+use git2::{Repository, Signature};
+use http::StatusCode;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+use tokio::net::{TcpListener, UnixListener};
+use wiremock::{
+    Mock, MockServer, ResponseTemplate,
+    matchers::{header, method, path, query_param},
+};
+
+use maintos_backend::{env::EnvVars, github::GithubClient, routing};
+
+pub const ORG: &str = "metakgp";
+pub const JWT_SECRET: &str = "test-jwt-secret";
+pub const WEBHOOK_SECRET: &str = "test-webhook-secret";
+/// Team whose members are admins of all the deployments
+pub const ADMIN_TEAM: &str = "devops";
+/// Team whose members are viewers of all the deployments
+pub const VIEWER_TEAM: &str = "members";
+/// The verifier kept by the browser during the OAuth flow, see [`TestApp::oauth_state`]
+pub const OAUTH_VERIFIER: &str = "test-oauth-verifier";
+
+/// A running maintos server
+pub struct TestApp {
+    pub base_url: String,
+    pub client: reqwest::Client,
+    pub github: MockServer,
+    pub deployments_dir: PathBuf,
+    /// The requests received by the fake docker daemon (`METHOD /path?query` without the API version, and the JSON body)
+    pub docker_requests: Arc<Mutex<Vec<(String, Value)>>>,
+    _dir: TempDir,
+}
+
+/// Initializes a deployment repository with an `origin` remote and an initial commit
+fn init_deployment(deployments_dir: &Path, name: &str, remote_url: &str) {
+    let repo = Repository::init(deployments_dir.join(name)).unwrap();
+    repo.remote("origin", remote_url).unwrap();
+
+    let signature = Signature::now("maintos", "maintos@example.com").unwrap();
+    let tree_id = repo.index().unwrap().write_tree().unwrap();
+    let tree = repo.find_tree(tree_id).unwrap();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        "Initial commit",
+        &tree,
+        &[],
+    )
+    .unwrap();
+}
+
+/// Handles the docker API requests: every deployment has a single `web` container of a compose project named after it, every image exists and the networks and volumes do not
+async fn fake_docker(
+    deployments_dir: PathBuf,
+    requests: Arc<Mutex<Vec<(String, Value)>>>,
+    request: Request,
+) -> (StatusCode, Json<Value>) {
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+    // Without the API version prefix
+    let path = parts.uri.path().trim_start_matches('/');
+    let path = match path.split_once('/') {
+        Some((version, path)) if version.starts_with("v1.") => path,
+        _ => path,
+    };
+    let query = parts
+        .uri
+        .query()
+        .map(|query| format!("?{query}"))
+        .unwrap_or_default();
+    requests.lock().unwrap().push((
+        format!("{} /{path}{query}", parts.method),
+        serde_json::from_slice(&body).unwrap_or(Value::Null),
+    ));
+    let path = format!("/{path}");
+
+    if path == "/containers/json" {
+        let containers = ["gyft", "naarad", "external"]
+            .iter()
+            .map(|name| {
+                json!({
+                    "Id": format!("{name}-id"),
+                    "Names": [format!("/{name}-web-1")],
+                    "Image": format!("{name}:latest"),
+                    "State": "running",
+                    "Status": "Up 2 hours",
+                    "Labels": {
+                        "com.docker.compose.project": name,
+                        "com.docker.compose.project.working_dir": deployments_dir.join(name),
+                        "com.docker.compose.service": "web",
+                    },
+                })
+            })
+            .collect();
+
+        (StatusCode::OK, Json(Value::Array(containers)))
+    } else if let Some(id) = path
+        .strip_suffix("/json")
+        .and_then(|path| path.strip_prefix("/containers/"))
+    {
+        (StatusCode::OK, Json(json!({ "Id": id, "RestartCount": 0 })))
+    } else if let Some(image) = path
+        .strip_suffix("/json")
+        .and_then(|path| path.strip_prefix("/images/"))
+    {
+        (
+            StatusCode::OK,
+            Json(json!({ "Id": format!("sha256:{image}") })),
+        )
+    } else if parts.method == http::Method::POST {
+        match path.as_str() {
+            "/containers/create" => (
+                StatusCode::CREATED,
+                Json(json!({ "Id": "new-container-id", "Warnings": [] })),
+            ),
+            "/networks/create" => (
+                StatusCode::CREATED,
+                Json(json!({ "Id": "network-id", "Warning": "" })),
+            ),
+            "/volumes/create" => (
+                StatusCode::CREATED,
+                Json(json!({
+                    "Name": "volume",
+                    "Driver": "local",
+                    "Mountpoint": "/var/lib/docker/volumes/volume",
+                    "Labels": {},
+                    "Scope": "local",
+                    "Options": {},
+                })),
+            ),
+            "/images/create" => (
+                StatusCode::OK,
+                Json(json!({ "status": "Downloaded newer image" })),
+            ),
+            "/build" => (StatusCode::OK, Json(json!({ "stream": "Built\n" }))),
+            // Container actions and network connections
+            _ => (StatusCode::NO_CONTENT, Json(Value::Null)),
+        }
+    } else if parts.method == http::Method::DELETE {
+        (StatusCode::NO_CONTENT, Json(Value::Null))
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "message": "not found" })),
+        )
+    }
+}
+
+impl TestApp {
+    /// Starts maintos with the deployments `gyft` and `naarad` (owned by the organization), `external` (owned by another user) and a directory that is not a repository
+    pub async fn spawn() -> Self {
+        let dir = TempDir::new().unwrap();
+        let deployments_dir = dir.path().join("deployments");
+        let data_dir = dir.path().join("data");
+        std::fs::create_dir_all(&deployments_dir).unwrap();
+        std::fs::create_dir_all(deployments_dir.join("not-a-repo")).unwrap();
+
+        init_deployment(&deployments_dir, "gyft", "https://github.com/metakgp/gyft");
+        init_deployment(
+            &deployments_dir,
+            "naarad",
+            "https://github.com/metakgp/naarad",
+        );
+        init_deployment(
+            &deployments_dir,
+            "external",
+            "https://github.com/someone-else/external",
+        );
+
+        let github = MockServer::start().await;
+
+        // Fake docker daemon on a unix socket
+        let socket_path = dir.path().join("docker.sock");
+        let docker_listener = UnixListener::bind(&socket_path).unwrap();
+        let docker_deployments_dir = deployments_dir.clone();
+        let docker_requests = Arc::new(Mutex::new(Vec::new()));
+        let fake_docker_requests = docker_requests.clone();
+        tokio::spawn(async move {
+            axum::serve(
+                docker_listener,
+                axum::Router::new().fallback(move |request: Request| {
+                    fake_docker(
+                        docker_deployments_dir.clone(),
+                        fake_docker_requests.clone(),
+                        request,
+                    )
+                }),
+            )
+            .await
+            .unwrap();
+        });
+        let docker = Docker::connect_with_unix(
+            socket_path.to_str().unwrap(),
+            10,
+            bollard::API_DEFAULT_VERSION,
+        )
+        .unwrap();
+
+        let env_vars = EnvVars::parse_from([
+            "maintos".into(),
+            "client-id".into(),
+            "admin-token".into(),
+            JWT_SECRET.into(),
+            "client-secret".into(),
+            ORG.into(),
+            format!("{ADMIN_TEAM}:admin,{VIEWER_TEAM}:viewer"),
+            github.uri(),
+            github.uri(),
+            WEBHOOK_SECRET.into(),
+            deployments_dir.display().to_string(),
+            data_dir.display().to_string(),
+        ]);
+
+        let router = routing::get_router(
+            &env_vars,
+            Arc::new(docker),
+            Arc::new(GithubClient::new(&env_vars)),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        Self {
+            base_url,
+            client: reqwest::Client::new(),
+            github,
+            deployments_dir,
+            docker_requests,
+            _dir: dir,
+        }
+    }
+
+    /// Mocks a Github user who can log in with the OAuth code `{username}-code`
+    pub async fn mock_user(&self, username: &str, org_member: bool) {
+        Mock::given(method("GET"))
+            .and(path("/login/oauth/access_token"))
+            .and(query_param("client_id", "client-id"))
+            .and(query_param("client_secret", "client-secret"))
+            .and(query_param("code", format!("{username}-code")))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "access_token": format!("{username}-token") })),
+            )
+            .mount(&self.github)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .and(header("Authorization", format!("Bearer {username}-token")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "login": username })))
+            .mount(&self.github)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path(format!("/orgs/{ORG}/members/{username}")))
+            .and(header("Authorization", "Bearer admin-token"))
+            .respond_with(ResponseTemplate::new(if org_member { 204 } else { 404 }))
+            .mount(&self.github)
+            .await;
+    }
+
+    /// Mocks a user's collaborator role on a repository
+    pub async fn mock_collaborator(&self, owner: &str, repo: &str, username: &str, role: &str) {
+        Mock::given(method("GET"))
+            .and(path(format!(
+                "/repos/{owner}/{repo}/collaborators/{username}/permission"
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "role_name": role })))
+            .mount(&self.github)
+            .await;
+    }
+
+    /// Mocks a user's active membership of an organization team
+    pub async fn mock_team_member(&self, team: &str, username: &str) {
+        Mock::given(method("GET"))
+            .and(path(format!(
+                "/orgs/{ORG}/teams/{team}/memberships/{username}"
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "state": "active" })))
+            .mount(&self.github)
+            .await;
+    }
+
+    /// Starts the OAuth flow (bound to [`OAUTH_VERIFIER`]), returns the `state` parameter
+    pub async fn oauth_state(&self) -> String {
+        let binding = format!("{:x}", Sha256::digest(OAUTH_VERIFIER));
+        let body: Value = self
+            .client
+            .get(format!("{}/oauth/start", self.base_url))
+            .query(&[("binding", binding)])
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        body["data"]["state"].as_str().unwrap().to_string()
+    }
+
+    /// Logs in through the OAuth endpoint with a new state, returns the response status and body
+    pub async fn oauth(&self, code: &str) -> (StatusCode, Value) {
+        self.oauth_with_state(code, &self.oauth_state().await).await
+    }
+
+    /// Logs in through the OAuth endpoint with [`OAUTH_VERIFIER`], returns the response status and body
+    pub async fn oauth_with_state(&self, code: &str, state: &str) -> (StatusCode, Value) {
+        self.oauth_with_verifier(code, state, OAUTH_VERIFIER).await
+    }
+
+    /// Logs in through the OAuth endpoint, returns the response status and body
+    pub async fn oauth_with_verifier(
+        &self,
+        code: &str,
+        state: &str,
+        verifier: &str,
+    ) -> (StatusCode, Value) {
+        let response = self
+            .client
+            .post(format!("{}/oauth", self.base_url))
+            .json(&json!({ "code": code, "state": state, "verifier": verifier }))
+            .send()
+            .await
+            .unwrap();
+
+        (response.status(), response.json().await.unwrap())
+    }
+
+    /// Mocks an organization member and logs them in, returns their JWT
+    pub async fn login(&self, username: &str) -> String {
+        self.mock_user(username, true).await;
+
+        let (status, body) = self.oauth(&format!("{username}-code")).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        body["data"]["token"].as_str().unwrap().to_string()
+    }
+
+    /// Sends a request with a JWT, returns the response status and body
+    pub async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        token: &str,
+    ) -> (StatusCode, Value) {
+        let response = self
+            .client
+            .request(method, format!("{}{path}", self.base_url))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap();
+
+        (response.status(), response.json().await.unwrap())
+    }
+
+    /// Sends a GET request with a JWT, returns the response status and body
+    pub async fn get(&self, path: &str, token: &str) -> (StatusCode, Value) {
+        self.request(reqwest::Method::GET, path, token).await
+    }
+
+    /// Sends a POST request with a JWT and a JSON body, returns the response status and body
+    pub async fn post(&self, path: &str, token: &str, body: Value) -> (StatusCode, Value) {
+        let response = self
+            .client
+            .post(format!("{}{path}", self.base_url))
+            .bearer_auth(token)
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+
+        (response.status(), response.json().await.unwrap())
+    }
+
+    /// Waits for a job to finish (by streaming its events), returns the job
+    pub async fn wait_for_job(&self, id: &str, token: &str) -> Value {
+        self.client
+            .get(format!("{}/jobs/{id}/events", self.base_url))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+
+        let (status, body) = self.get(&format!("/jobs/{id}"), token).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        body["data"].clone()
+    }
+}
@@ -2,7 +2,6 @@ import { useEffect, useState } from "react";
 import "./deployments_grid.scss";
 import { useAuthContext } from "../../utils/auth";
 import type { IEndpointTypes } from "../../types/backend";
-import { makeRequest } from "../../utils/backend";
 
 function DeploymentsGrid() {
 	const auth = useAuthContext();
@@ -12,7 +11,7 @@ function DeploymentsGrid() {
 	const [message, setMessage] = useState<string>("");
 
 	const fetchDeployments = async () => {
-		const resp = await makeRequest("deployments", "get", null, auth.jwt);
+		const resp = await auth.request("deployments", "get");
 
 		if (resp.status == "success") {
 			setDeployments(resp.data);
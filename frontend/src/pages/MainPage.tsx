@@ -1,14 +1,15 @@
-import { useEffect } from "react";
+import { useEffect, useState } from "react";
 import { Header } from "../components/Common/Common";
-import { OAUTH_LOGIN_URL, useAuthContext } from "../utils/auth";
+import { startOAuthLogin, useAuthContext } from "../utils/auth";
 import DeploymentsGrid from "../components/DeploymentsGrid/DeploymentsGrid";
 
 function MainPage() {
 	const auth = useAuthContext();
+	const [loginError, setLoginError] = useState<string | null>(null);
 
 	useEffect(() => {
 		if (!auth.isAuthenticated) {
-			window.location.assign(OAUTH_LOGIN_URL);
+			startOAuthLogin().then(setLoginError);
 		}
 	}, []);
 
@@ -19,7 +20,7 @@ function MainPage() {
 				subtitle={
 					auth.isAuthenticated
 						? `Welcome ${auth.username}!`
-						: `Not authenticated.`
+						: loginError ?? `Not authenticated.`
 				}
 			/>
 			{auth.isAuthenticated && <DeploymentsGrid />}
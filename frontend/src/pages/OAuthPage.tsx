@@ -1,5 +1,5 @@
 import { useNavigate } from "react-router-dom";
-import { useAuthContext } from "../utils/auth";
+import { clearOAuthLogin, getOAuthVerifier, useAuthContext } from "../utils/auth";
 import { makeRequest } from "../utils/backend";
 import { useEffect, useState } from "react";
 import { Header } from "../components/Common/Common";
@@ -10,12 +10,13 @@ export default function OAuthPage() {
 	const [message, setMessage] = useState<string>("Authenticating, please wait...");
 	const [awaitingResponse, setAwaitingRepsonse] = useState<boolean>(false);
 
-	const loginHandler = async (code: string) => {
-		const response = await makeRequest('oauth', 'post', { code });
+	const loginHandler = async (code: string, state: string, verifier: string) => {
+		const response = await makeRequest('oauth', 'post', { code, state, verifier });
+		clearOAuthLogin();
 
 		if (response.status === 'success') {
-			if ("token" in response.data) {
-				auth.login(response.data["token"]);
+			if ("token" in response.data && "refresh_token" in response.data) {
+				auth.login(response.data["token"], response.data["refresh_token"]);
 				setMessage("Successfully authenticated.");
 				navigate('/');
 			}
@@ -35,12 +36,18 @@ export default function OAuthPage() {
 			navigate('/');
 		} else {
 			const urlParams = new URLSearchParams(location.search);
+			const code = urlParams.get("code");
+			const state = urlParams.get("state");
 
-			if (urlParams.get("code") === null) {
+			const verifier = state === null ? null : getOAuthVerifier(state);
+
+			if (code === null || state === null) {
 				setMessage("No OAuth code found.");
+			} else if (verifier === null) {
+				setMessage("This login was not started from this browser. Please try again.");
 			} else if (!awaitingResponse) {
 				setAwaitingRepsonse(true);
-				loginHandler(urlParams.get("code") as string);
+				loginHandler(code, state, verifier);
 			}
 		}
 	}, []);
@@ -16,14 +16,39 @@ export interface IErrorResponse {
 export type BackendResponse<T> = IOkResponse<T> | IErrorResponse;
 
 export interface IEndpointTypes {
+	"oauth/start": {
+		request: {
+			binding: string;
+		};
+		response: {
+			state: string;
+			redirect_url: string;
+		};
+	};
 	oauth: {
 		request: {
 			code: string;
+			state: string;
+			verifier: string;
 		};
 		response: {
 			token: string;
+			refresh_token: string;
 		};
 	};
+	"token/refresh": {
+		request: {
+			refresh_token: string;
+		};
+		response: {
+			token: string;
+			refresh_token: string;
+		};
+	};
+	logout: {
+		request: null;
+		response: null;
+	};
 	profile: {
 		request: null;
 		response: {
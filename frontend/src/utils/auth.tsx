@@ -7,13 +7,22 @@ import React, {
 } from "react";
 import { useNavigate } from "react-router-dom";
 import { makeRequest } from "./backend";
+import type { AllowedBackendMethods, BackendResponse, IEndpointTypes } from "../types/backend";
+
+type AuthRequest = <E extends keyof IEndpointTypes>(
+	endpoint: E,
+	method: AllowedBackendMethods,
+	params?: IEndpointTypes[E]["request"] | null,
+) => Promise<BackendResponse<IEndpointTypes[E]["response"]>>;
 
 interface IAuthContext {
 	isAuthenticated: boolean;
 	jwt: string | null;
 	username: string | null;
-	login: (jwt: string) => void;
+	login: (jwt: string, refreshToken: string) => void;
 	logout: () => void;
+	/** Makes an authenticated request, refreshing the JWT and retrying once if it is rejected */
+	request: AuthRequest;
 }
 
 const DEFAULT_AUTH_CONTEXT: IAuthContext = {
@@ -21,13 +30,68 @@ const DEFAULT_AUTH_CONTEXT: IAuthContext = {
 	jwt: null,
 	username: null,
 	login: () => { },
-	logout: () => { }
+	logout: () => { },
+	request: (endpoint, method, params = null) => makeRequest(endpoint, method, params)
 };
 
+/** Time before the expiration of the JWT at which it is refreshed (in milliseconds) */
+const REFRESH_MARGIN_MS = 60 * 1000;
+
 const getLsAuthJwt = () => {
 	return localStorage.getItem("jwt");
 };
 
+const storeSession = (jwt: string, refreshToken: string) => {
+	localStorage.setItem("jwt", jwt);
+	localStorage.setItem("refresh_token", refreshToken);
+};
+
+const clearStoredSession = () => {
+	localStorage.removeItem("jwt");
+	localStorage.removeItem("refresh_token");
+};
+
+/** Returns the expiration time of a JWT (in milliseconds), read from its `exp` claim */
+function getJwtExpiration(jwt: string): number | null {
+	try {
+		const payload = JSON.parse(atob(jwt.split(".")[1].replace(/-/g, "+").replace(/_/g, "/")));
+		return typeof payload.exp === "number" ? payload.exp * 1000 : null;
+	} catch (e) {
+		return null;
+	}
+}
+
+let pendingRefresh: Promise<string | null> | null = null;
+
+/**
+ * Exchanges the stored refresh token for a new JWT (and refresh token) and returns the JWT, or null if the session has ended.
+ * A refresh token can only be used once (reusing one revokes the session), so refreshes are serialized, also across tabs, and a JWT refreshed in the meantime is reused.
+ */
+function refreshSession(staleJwt: string | null): Promise<string | null> {
+	if (pendingRefresh === null) {
+		pendingRefresh = navigator.locks.request("maintos-refresh", async () => {
+			const jwt = getLsAuthJwt();
+			if (jwt !== null && jwt !== staleJwt) return jwt;
+
+			const refreshToken = localStorage.getItem("refresh_token");
+			if (refreshToken === null) return null;
+
+			const response = await makeRequest('token/refresh', 'post', { refresh_token: refreshToken });
+			if (response.status !== 'success') {
+				if (response.status_code === 401) clearStoredSession();
+				return null;
+			}
+
+			storeSession(response.data.token, response.data.refresh_token);
+			return response.data.token;
+		}).finally(() => {
+			pendingRefresh = null;
+		});
+	}
+
+	return pendingRefresh;
+}
+
 const AuthContext = createContext<IAuthContext>(DEFAULT_AUTH_CONTEXT);
 
 export const useAuthContext = () => useContext(AuthContext);
@@ -35,30 +99,59 @@ export const useAuthContext = () => useContext(AuthContext);
 export function AuthProvider({ children }: { children: React.ReactNode }) {
 	const navigate = useNavigate();
 
-	const lsAuthJwt = getLsAuthJwt();
+	const [jwt, setJwt] = useState(getLsAuthJwt());
 	const [isAuthenticated, setIsAuthenticated] = useState(
-		lsAuthJwt !== null && lsAuthJwt !== "",
+		jwt !== null && jwt !== "",
 	);
 	const [username, setUsername] = useState<string | null>(null);
 
-	const login = async (jwt: string) => {
-		localStorage.setItem("jwt", jwt);
-		await checkAuth(jwt);
-	};
-
-	const logout = () => {
-		localStorage.removeItem("jwt");
+	const endSession = () => {
+		clearStoredSession();
+		setJwt(null);
 		setIsAuthenticated(false);
 		setUsername(null);
+	};
+
+	const refresh = async (staleJwt: string | null) => {
+		const refreshed = await refreshSession(staleJwt);
+
+		if (refreshed !== null) {
+			setJwt(refreshed);
+		} else if (getLsAuthJwt() === null) {
+			endSession();
+		}
+
+		return refreshed;
+	};
+
+	const request: AuthRequest = async (endpoint, method, params = null) => {
+		const currentJwt = getLsAuthJwt();
+		const response = await makeRequest(endpoint, method, params, currentJwt);
+		if (response.status_code !== 401 || currentJwt === null) return response;
+
+		const refreshed = await refresh(currentJwt);
+		return refreshed === null ? response : await makeRequest(endpoint, method, params, refreshed);
+	};
+
+	const login = async (jwt: string, refreshToken: string) => {
+		storeSession(jwt, refreshToken);
+		setJwt(jwt);
+		await checkAuth();
+	};
+
+	const logout = async () => {
+		// Ends the session on the backend, which revokes its JWT and refresh token
+		if (getLsAuthJwt() !== null) await request('logout', 'post');
+
+		endSession();
 		navigate('/');
 	};
 
-	const checkAuth = async (jwt: string) => {
-		const response = await makeRequest('profile', 'get', null, jwt);
+	const checkAuth = async () => {
+		const response = await request('profile', 'get');
 
 		if (response.status !== 'success') {
-			localStorage.removeItem("jwt");
-			setIsAuthenticated(false);
+			endSession();
 		} else {
 			setUsername(response.data.username);
 			setIsAuthenticated(true);
@@ -66,22 +159,82 @@ export function AuthProvider({ children }: { children: React.ReactNode }) {
 	}
 	useEffect(() => {
 		if (isAuthenticated) {
-			checkAuth(lsAuthJwt as string);
+			checkAuth();
 		}
 	}, [])
 
+	// Refreshes the JWT shortly before it expires
+	useEffect(() => {
+		const expiration = jwt === null ? null : getJwtExpiration(jwt);
+		if (expiration === null) return;
+
+		const timeout = setTimeout(
+			() => refresh(jwt),
+			Math.max(expiration - Date.now() - REFRESH_MARGIN_MS, 0),
+		);
+		return () => clearTimeout(timeout);
+	}, [jwt]);
+
 	const value = useMemo(
 		() => ({
 			isAuthenticated,
-			jwt: lsAuthJwt,
+			jwt,
 			username: username,
 			login,
-			logout
+			logout,
+			request
 		}),
-		[isAuthenticated, username, login, logout],
+		[isAuthenticated, jwt, username, login, logout, request],
 	);
 
 	return <AuthContext.Provider value={value}>{children}</AuthContext.Provider>;
 }
 
-export const OAUTH_LOGIN_URL = `https://github.com/login/oauth/authorize?client_id=${import.meta.env.VITE_GH_OAUTH_CLIENT_ID}`;
+/** Key of the OAuth verifier and state in the session storage, they bind the login to this browser (tab) */
+const OAUTH_SESSION_KEY = "oauth";
+
+async function sha256Hex(text: string): Promise<string> {
+	const digest = await crypto.subtle.digest("SHA-256", new TextEncoder().encode(text));
+
+	return Array.from(new Uint8Array(digest))
+		.map((byte) => byte.toString(16).padStart(2, "0"))
+		.join("");
+}
+
+/**
+ * Starts the OAuth flow on the backend and redirects to the Github authorization URL (which includes the OAuth `state`).
+ * The state is bound to this browser by the hash of a random verifier, kept in the session storage with the state.
+ * Returns an error message if the flow could not be started.
+ */
+export async function startOAuthLogin(): Promise<string | null> {
+	const verifier = `${crypto.randomUUID()}${crypto.randomUUID()}`;
+	const response = await makeRequest('oauth/start', 'get', { binding: await sha256Hex(verifier) });
+
+	if (response.status === 'success') {
+		sessionStorage.setItem(OAUTH_SESSION_KEY, JSON.stringify({ verifier, state: response.data.state }));
+		window.location.assign(response.data.redirect_url);
+		return null;
+	}
+
+	return `Could not start the login: ${response.message}`;
+}
+
+/**
+ * Returns the verifier of the OAuth login started in this browser, if its state matches the returned state.
+ */
+export function getOAuthVerifier(state: string): string | null {
+	const stored = sessionStorage.getItem(OAUTH_SESSION_KEY);
+	if (stored === null) return null;
+
+	try {
+		const login: { verifier: string; state: string } = JSON.parse(stored);
+		return login.state === state ? login.verifier : null;
+	} catch (e) {
+		return null;
+	}
+}
+
+/** Forgets the OAuth login started in this browser, once it has been used */
+export function clearOAuthLogin() {
+	sessionStorage.removeItem(OAUTH_SESSION_KEY);
+}